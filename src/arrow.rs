@@ -0,0 +1,150 @@
+#[cfg(not(feature = "u64-ids"))]
+use arrow_array::builder::UInt32Builder as TokenBuilder;
+#[cfg(feature = "u64-ids")]
+use arrow_array::builder::UInt64Builder as TokenBuilder;
+use arrow_array::builder::{
+    BinaryBuilder, Float32Builder, Int32Builder, Int64Builder, ListBuilder, UInt16Builder,
+    UInt8Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::ArrowError;
+use std::sync::Arc;
+
+use crate::{Column, Columns};
+
+// Token outputs become list<uint32> (list<uint64> with u64-ids) columns, float outputs
+// list<float32>, fixed width integer outputs list<int32>, list<int64>, list<uint8> or
+// list<uint16> and bitsets binary columns, with one row per packed bin.
+// Columns are sorted by name so the schema is the same for every call
+pub fn to_record_batch(result: &Columns) -> Result<RecordBatch, ArrowError> {
+    let mut keys = result.keys().collect::<Vec<_>>();
+    keys.sort();
+    let columns = keys.into_iter().map(|key| {
+        let array: ArrayRef = match &result[key] {
+            Column::Tokens(rows) => {
+                let mut builder = ListBuilder::new(TokenBuilder::new());
+                for row in rows {
+                    builder.values().append_slice(row);
+                    builder.append(true);
+                }
+                Arc::new(builder.finish())
+            }
+            Column::Floats(rows) => {
+                let mut builder = ListBuilder::new(Float32Builder::new());
+                for row in rows {
+                    builder.values().append_slice(row);
+                    builder.append(true);
+                }
+                Arc::new(builder.finish())
+            }
+            Column::Int32(rows) => {
+                let mut builder = ListBuilder::new(Int32Builder::new());
+                for row in rows {
+                    builder.values().append_slice(row);
+                    builder.append(true);
+                }
+                Arc::new(builder.finish())
+            }
+            Column::Int64(rows) => {
+                let mut builder = ListBuilder::new(Int64Builder::new());
+                for row in rows {
+                    builder.values().append_slice(row);
+                    builder.append(true);
+                }
+                Arc::new(builder.finish())
+            }
+            Column::UInt8(rows) => {
+                let mut builder = ListBuilder::new(UInt8Builder::new());
+                for row in rows {
+                    builder.values().append_slice(row);
+                    builder.append(true);
+                }
+                Arc::new(builder.finish())
+            }
+            Column::UInt16(rows) => {
+                let mut builder = ListBuilder::new(UInt16Builder::new());
+                for row in rows {
+                    builder.values().append_slice(row);
+                    builder.append(true);
+                }
+                Arc::new(builder.finish())
+            }
+            Column::Bytes(rows) => {
+                let mut builder = BinaryBuilder::new();
+                for row in rows {
+                    builder.append_value(row);
+                }
+                Arc::new(builder.finish())
+            }
+        };
+        (key.as_str(), array)
+    });
+    RecordBatch::try_from_iter(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::ArrowPrimitiveType;
+    use arrow_schema::DataType;
+
+    #[cfg(not(feature = "u64-ids"))]
+    use arrow_array::types::UInt32Type as TokenType;
+    #[cfg(feature = "u64-ids")]
+    use arrow_array::types::UInt64Type as TokenType;
+
+    #[test]
+    fn test_record_batch() {
+        let result = Columns::from([
+            (
+                "tokens".to_string(),
+                Column::Tokens(vec![vec![1, 2, 3], vec![4, 0, 0]]),
+            ),
+            (
+                "positions_ids".to_string(),
+                Column::Tokens(vec![vec![0, 1, 2], vec![0, 0, 0]]),
+            ),
+        ]);
+        let batch = to_record_batch(&result).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let schema = batch.schema();
+        let names = schema
+            .fields()
+            .iter()
+            .map(|f| f.name().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["positions_ids", "tokens"]);
+        for field in schema.fields() {
+            assert!(
+                matches!(field.data_type(), DataType::List(item) if item.data_type() == &TokenType::DATA_TYPE)
+            );
+        }
+        let tokens = batch.column(1).as_list::<i32>();
+        assert_eq!(
+            tokens.value(1).as_primitive::<TokenType>().values(),
+            &[4, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_fixed_width_columns() {
+        use arrow_array::types::{Int32Type, Int64Type};
+
+        let result = Columns::from([
+            ("int32".to_string(), Column::Int32(vec![vec![0, 1, 0]])),
+            ("int64".to_string(), Column::Int64(vec![vec![0, 1, 2]])),
+        ]);
+        let batch = to_record_batch(&result).unwrap();
+        let schema = batch.schema();
+        let item_types = schema
+            .fields()
+            .iter()
+            .map(|field| match field.data_type() {
+                DataType::List(item) => item.data_type().clone(),
+                other => panic!("Expected a list column, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(item_types, vec![Int32Type::DATA_TYPE, Int64Type::DATA_TYPE]);
+    }
+}
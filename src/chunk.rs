@@ -0,0 +1,142 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::common::Feature;
+
+// The examples after cutting the long sequences, in the same column layout as the input
+// with the columns ordered by name
+#[derive(Debug, Default, PartialEq, IntoPyObject)]
+pub struct ChunkedExamples {
+    pub examples: BTreeMap<String, Vec<Feature>>,
+    // index of the input example each chunk came from
+    pub original_index: Vec<usize>,
+}
+
+// Start and end of every chunk of a sequence of `len` tokens. Chunks start `stride` tokens
+// apart and the last one ends at the end of the sequence, a sequence that fits is kept whole
+fn chunk_bounds(len: usize, pack_size: usize, stride: usize) -> Vec<(usize, usize)> {
+    let mut bounds = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + pack_size).min(len);
+        bounds.push((start, end));
+        if end == len {
+            return bounds;
+        }
+        start += stride;
+    }
+}
+
+// Cut a feature of the example to a chunk. Lists as long as the input_ids (e.g. labels,
+// position_ids) are cut the same way, anything else is copied to every chunk
+fn slice_feature(feature: &Feature, len: usize, (start, end): (usize, usize)) -> Feature {
+    match feature {
+        Feature::Tokens(values) if values.len() == len => {
+            Feature::Tokens(values[start..end].to_vec())
+        }
+        Feature::Floats(values) if values.len() == len => {
+            Feature::Floats(values[start..end].to_vec())
+        }
+        other => other.clone(),
+    }
+}
+
+pub fn chunk_examples(
+    examples: &HashMap<String, Vec<Feature>>,
+    pack_size: usize,
+    stride: usize,
+) -> ChunkedExamples {
+    let input_ids = examples
+        .get("input_ids")
+        .expect("Expected key 'input_ids' in the dataset");
+    let mut result = ChunkedExamples {
+        examples: examples
+            .keys()
+            .map(|key| (key.clone(), Vec::new()))
+            .collect(),
+        original_index: Vec::new(),
+    };
+    for (index, tokens) in input_ids.iter().enumerate() {
+        let len = tokens
+            .as_tokens()
+            .expect("Expected key 'input_ids' to be a list of token ids")
+            .len();
+        for bounds in chunk_bounds(len, pack_size, stride) {
+            for (key, values) in examples {
+                let chunk = slice_feature(&values[index], len, bounds);
+                result.examples.get_mut(key).unwrap().push(chunk);
+            }
+            result.original_index.push(index);
+        }
+    }
+    result
+}
+
+/// Cuts the sequences longer than `pack_size` into chunks that fit, so they can be packed.
+///
+/// Chunks start `stride` tokens apart (`pack_size` by default), a smaller stride makes
+/// them overlap. Columns as long as `input_ids` are cut the same way, the others are
+/// repeated for every chunk. `original_index` maps every chunk to its input example.
+#[pyfunction]
+#[pyo3(signature = (examples, pack_size, stride=None))]
+pub fn chunk_long_sequences(
+    py: Python<'_>,
+    examples: HashMap<String, Vec<Feature>>,
+    pack_size: usize,
+    stride: Option<usize>,
+) -> PyResult<ChunkedExamples> {
+    let stride = stride.unwrap_or(pack_size);
+    if pack_size == 0 {
+        return Err(PyValueError::new_err("pack_size must be greater than 0"));
+    }
+    // a larger stride would skip the tokens between the chunks
+    if stride == 0 || stride > pack_size {
+        return Err(PyValueError::new_err(
+            "stride must be between 1 and pack_size",
+        ));
+    }
+    if !examples.contains_key("input_ids") {
+        return Err(PyValueError::new_err(
+            "Expected key 'input_ids' in the dataset",
+        ));
+    }
+    Ok(py.allow_threads(|| chunk_examples(&examples, pack_size, stride)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_long_sequence() {
+        let examples = HashMap::from([
+            (
+                "input_ids".to_string(),
+                vec![
+                    Feature::Tokens((0..12).collect()),
+                    Feature::Tokens(vec![7, 8]),
+                ],
+            ),
+            ("source".to_string(), vec![Feature::Int(1), Feature::Int(2)]),
+        ]);
+        let result = chunk_examples(&examples, 4, 4);
+        assert_eq!(
+            result.examples["input_ids"],
+            vec![
+                Feature::Tokens(vec![0, 1, 2, 3]),
+                Feature::Tokens(vec![4, 5, 6, 7]),
+                Feature::Tokens(vec![8, 9, 10, 11]),
+                Feature::Tokens(vec![7, 8]),
+            ]
+        );
+        assert_eq!(result.original_index, vec![0, 0, 0, 1]);
+        assert_eq!(result.examples["source"][..3], vec![Feature::Int(1); 3]);
+
+        // overlapping chunks, the last one ends at the end of the sequence
+        assert_eq!(
+            chunk_bounds(12, 4, 3),
+            vec![(0, 4), (3, 7), (6, 10), (9, 12)]
+        );
+    }
+}
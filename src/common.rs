@@ -1,13 +1,199 @@
 // Types and common enums should be defined here
-use std::collections::HashMap;
+use pyo3::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+// Token ids are u32 by default, the u64-ids feature allows vocabularies above u32::MAX
+#[cfg(not(feature = "u64-ids"))]
+pub type TokenId = u32;
+#[cfg(feature = "u64-ids")]
+pub type TokenId = u64;
 
 // Sequence usually refers to things like input_ids, position_ids, etc.
-pub type Sequence = Vec<u32>;
+pub type Sequence = Vec<TokenId>;
+
+// A single value of a column for one example. Token columns are lists of ids, while
+// metadata columns (e.g. difficulty) are scalars carried along with the example.
+// The order of the variants matters, pyo3 tries them from top to bottom.
+#[derive(Clone, Debug, PartialEq, FromPyObject, IntoPyObject)]
+pub enum Feature {
+    Tokens(Sequence),
+    Floats(Vec<f32>),
+    Int(i64),
+    Float(f64),
+    // only produced for outputs with a fixed width (e.g. position_id_dtype), integer
+    // inputs are always extracted as Tokens or Floats
+    Int32s(Vec<i32>),
+    Int64s(Vec<i64>),
+    // a packed bitset, e.g. the compact_mask loss mask, returned to python as bytes
+    Bytes(Vec<u8>),
+    // token ids narrowed with token_dtype, uint8 rows are returned to python as bytes
+    UInt8s(Vec<u8>),
+    UInt16s(Vec<u16>),
+}
+
+impl Feature {
+    pub fn as_tokens(&self) -> Option<&Sequence> {
+        match self {
+            Feature::Tokens(tokens) => Some(tokens),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Feature::Int(v) => Some(*v as f64),
+            Feature::Float(v) => Some(*v),
+            Feature::Tokens(_)
+            | Feature::Floats(_)
+            | Feature::Int32s(_)
+            | Feature::Int64s(_)
+            | Feature::Bytes(_)
+            | Feature::UInt8s(_)
+            | Feature::UInt16s(_) => None,
+        }
+    }
+}
+
+// A single output of the packed result, with one entry per packed row
+#[derive(Clone, Debug, PartialEq, IntoPyObject)]
+pub enum Column {
+    Tokens(Vec<Sequence>),
+    Floats(Vec<Vec<f32>>),
+    Int32(Vec<Vec<i32>>),
+    Int64(Vec<Vec<i64>>),
+    Bytes(Vec<Vec<u8>>),
+    UInt8(Vec<Vec<u8>>),
+    UInt16(Vec<Vec<u16>>),
+}
+
+impl Column {
+    pub fn len(&self) -> usize {
+        match self {
+            Column::Tokens(rows) => rows.len(),
+            Column::Floats(rows) => rows.len(),
+            Column::Int32(rows) => rows.len(),
+            Column::Int64(rows) => rows.len(),
+            Column::Bytes(rows) => rows.len(),
+            Column::UInt8(rows) => rows.len(),
+            Column::UInt16(rows) => rows.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Split the rows into consecutive groups of `size` rows, the last group can be smaller
+    pub fn chunks(self, size: usize) -> Vec<Column> {
+        fn split<T>(rows: Vec<T>, size: usize) -> Vec<Vec<T>> {
+            let mut rows = rows.into_iter().peekable();
+            let mut chunks = Vec::new();
+            while rows.peek().is_some() {
+                chunks.push(rows.by_ref().take(size).collect());
+            }
+            chunks
+        }
+        match self {
+            Column::Tokens(rows) => split(rows, size).into_iter().map(Column::Tokens).collect(),
+            Column::Floats(rows) => split(rows, size).into_iter().map(Column::Floats).collect(),
+            Column::Int32(rows) => split(rows, size).into_iter().map(Column::Int32).collect(),
+            Column::Int64(rows) => split(rows, size).into_iter().map(Column::Int64).collect(),
+            Column::Bytes(rows) => split(rows, size).into_iter().map(Column::Bytes).collect(),
+            Column::UInt8(rows) => split(rows, size).into_iter().map(Column::UInt8).collect(),
+            Column::UInt16(rows) => split(rows, size).into_iter().map(Column::UInt16).collect(),
+        }
+    }
+
+    // Concatenate the rows into a single value, e.g. the values of the csr format
+    pub fn flatten(self) -> Feature {
+        match self {
+            Column::Tokens(rows) => Feature::Tokens(rows.concat()),
+            Column::Floats(rows) => Feature::Floats(rows.concat()),
+            Column::Int32(rows) => Feature::Int32s(rows.concat()),
+            Column::Int64(rows) => Feature::Int64s(rows.concat()),
+            Column::Bytes(rows) => Feature::Bytes(rows.concat()),
+            Column::UInt8(rows) => Feature::UInt8s(rows.concat()),
+            Column::UInt16(rows) => Feature::UInt16s(rows.concat()),
+        }
+    }
+
+    // Number of values in each row
+    pub fn row_lens(&self) -> Vec<usize> {
+        match self {
+            Column::Tokens(rows) => rows.iter().map(Vec::len).collect(),
+            Column::Floats(rows) => rows.iter().map(Vec::len).collect(),
+            Column::Int32(rows) => rows.iter().map(Vec::len).collect(),
+            Column::Int64(rows) => rows.iter().map(Vec::len).collect(),
+            Column::Bytes(rows) => rows.iter().map(Vec::len).collect(),
+            Column::UInt8(rows) => rows.iter().map(Vec::len).collect(),
+            Column::UInt16(rows) => rows.iter().map(Vec::len).collect(),
+        }
+    }
+
+    // Split the column into the value of each row
+    pub fn into_rows(self) -> Vec<Feature> {
+        match self {
+            Column::Tokens(rows) => rows.into_iter().map(Feature::Tokens).collect(),
+            Column::Floats(rows) => rows.into_iter().map(Feature::Floats).collect(),
+            Column::Int32(rows) => rows.into_iter().map(Feature::Int32s).collect(),
+            Column::Int64(rows) => rows.into_iter().map(Feature::Int64s).collect(),
+            Column::Bytes(rows) => rows.into_iter().map(Feature::Bytes).collect(),
+            Column::UInt8(rows) => rows.into_iter().map(Feature::UInt8s).collect(),
+            Column::UInt16(rows) => rows.into_iter().map(Feature::UInt16s).collect(),
+        }
+    }
+}
+
+impl From<Vec<Sequence>> for Column {
+    fn from(rows: Vec<Sequence>) -> Self {
+        Column::Tokens(rows)
+    }
+}
+
+impl From<Vec<Vec<f32>>> for Column {
+    fn from(rows: Vec<Vec<f32>>) -> Self {
+        Column::Floats(rows)
+    }
+}
+
+// The outputs of a packing run, keyed by output name. Ordered by name, so the outputs
+// come back in the same order on every run
+pub type Columns = BTreeMap<String, Column>;
+
+// Group the rows into batches of `batch_size` rows, every batch has all the outputs
+pub fn into_batches(columns: Columns, batch_size: usize) -> Vec<Columns> {
+    let mut batches: Vec<Columns> = Vec::new();
+    for (key, column) in columns {
+        for (i, chunk) in column.chunks(batch_size).into_iter().enumerate() {
+            if i == batches.len() {
+                batches.push(Columns::new());
+            }
+            batches[i].insert(key.clone(), chunk);
+        }
+    }
+    batches
+}
+
+// An example is a single row of the dataset, keyed by column name
+pub type Example = HashMap<String, Feature>;
 
 // Histogram is a mapping of sequence lengths to their corresponding sequences
-// The key is the length of the sequence, and the value is a vector of dictionaries
-// where each dictionary contains the sequence data.
-pub type Histogram = HashMap<usize, Vec<HashMap<String, Sequence>>>;
+// The key is the length of the sequence, and the value is a vector of examples
+// where each example contains all the columns of that row.
+pub type Histogram = HashMap<usize, Vec<Example>>;
+
+// ifile handles hold the examples of each length in the order they are popped,
+// with an extra position_ids column
+pub type IFileHandles = HashMap<usize, Vec<Example>>;
+
+// A bin holds the examples that are packed together into a single row
+pub type Bin = Vec<Example>;
 
-// ifile handles can be adjusted here, but it contains the input_ids and position_ids
-pub type IFileHandles = HashMap<usize, (Vec<Sequence>, Vec<Sequence>)>;
+// Protected examples (a non-zero `protected` column) are never truncated, they are
+// always packed whole into a bin of their own
+pub fn is_protected(entry: &Example) -> bool {
+    entry
+        .get("protected")
+        .and_then(Feature::as_f64)
+        .is_some_and(|value| value != 0.0)
+}
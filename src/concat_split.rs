@@ -0,0 +1,127 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+
+use crate::common::{Sequence, TokenId};
+
+// Result of concatenating every sequence and cutting the stream into rows
+#[derive(Debug, Default, PartialEq, IntoPyObject)]
+pub struct ConcatSplit {
+    pub tokens: Vec<Sequence>,
+    // offsets into the concatenated stream where a row was cut, one less than the rows
+    pub split_points: Vec<usize>,
+}
+
+// Find where the row starting at `start` ends. Without an eos window this is always
+// `start + pack_size`, otherwise the cut moves back to just after the last eos token
+// within `eos_window` tokens of the hard cut
+fn find_cut(
+    stream: &[TokenId],
+    start: usize,
+    pack_size: usize,
+    eos: Option<(TokenId, usize)>,
+) -> usize {
+    let hard_cut = (start + pack_size).min(stream.len());
+    let Some((eos_id, eos_window)) = eos else {
+        return hard_cut;
+    };
+    if hard_cut == stream.len() {
+        return hard_cut;
+    }
+    // the row must keep at least one token
+    let window_start = hard_cut.saturating_sub(eos_window).max(start + 1);
+    (window_start..=hard_cut)
+        .rev()
+        .find(|&cut| stream[cut - 1] == eos_id)
+        .unwrap_or(hard_cut)
+}
+
+pub fn split_concatenated(
+    sequences: &[Sequence],
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    eos: Option<(TokenId, usize)>,
+) -> ConcatSplit {
+    let stream: Sequence = sequences.concat();
+    let mut result = ConcatSplit::default();
+    let mut start = 0;
+    while start < stream.len() {
+        let cut = find_cut(&stream, start, pack_size, eos);
+        let mut row = stream[start..cut].to_vec();
+        if let Some(pad_id) = pad_id {
+            row.resize(pack_size, pad_id);
+        }
+        result.tokens.push(row);
+        if cut < stream.len() {
+            result.split_points.push(cut);
+        }
+        start = cut;
+    }
+    result
+}
+
+/// Concatenates every sequence in `input_ids` and cuts the stream into rows of
+/// `target_pack_size` tokens, documents can be split across rows.
+///
+/// `split_points` are the offsets in the concatenated stream where a row was cut. With
+/// `prefer_eos_split`, a cut moves back to just after an `eos_id` token when there is one
+/// within `eos_window` tokens, so fewer documents are split.
+#[pyfunction]
+#[pyo3(signature = (input_ids, target_pack_size, pad_id=None, eos_id=None, prefer_eos_split=false, eos_window=0))]
+pub fn concat_split(
+    py: Python<'_>,
+    input_ids: Vec<Sequence>,
+    target_pack_size: usize,
+    pad_id: Option<TokenId>,
+    eos_id: Option<TokenId>,
+    prefer_eos_split: bool,
+    eos_window: usize,
+) -> PyResult<ConcatSplit> {
+    if target_pack_size == 0 {
+        return Err(PyValueError::new_err(
+            "target_pack_size must be greater than 0",
+        ));
+    }
+    let eos = match (prefer_eos_split, eos_id) {
+        (false, _) => None,
+        (true, Some(eos_id)) => Some((eos_id, eos_window)),
+        (true, None) => {
+            return Err(PyValueError::new_err("prefer_eos_split requires eos_id"));
+        }
+    };
+    Ok(py.allow_threads(|| split_concatenated(&input_ids, target_pack_size, pad_id, eos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_split() {
+        let sequences = vec![vec![1, 1, 1, 9], vec![2, 2, 9], vec![3, 3, 3, 3, 9]];
+        let result = split_concatenated(&sequences, 5, Some(0), None);
+        assert_eq!(result.split_points, vec![5, 10]);
+        assert_eq!(
+            result.tokens,
+            vec![
+                vec![1, 1, 1, 9, 2],
+                vec![2, 9, 3, 3, 3],
+                vec![3, 9, 0, 0, 0],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefer_eos_split() {
+        let sequences = vec![vec![1, 1, 1, 9], vec![2, 2, 9], vec![3, 3, 3, 3, 9]];
+        // the eos at offset 3 is one token before the hard cut at 5
+        let result = split_concatenated(&sequences, 5, None, Some((9, 2)));
+        assert_eq!(result.split_points, vec![4, 7]);
+        assert_eq!(
+            result.tokens,
+            vec![vec![1, 1, 1, 9], vec![2, 2, 9], vec![3, 3, 3, 3, 9]]
+        );
+        // no eos within the window, cut at the pack size
+        let result = split_concatenated(&sequences, 5, None, Some((9, 0)));
+        assert_eq!(result.split_points, vec![5, 10]);
+    }
+}
@@ -0,0 +1,158 @@
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::collections::HashMap;
+
+use crate::common::{Feature, TokenId};
+use crate::{fast_pack, parse_config, PackOutput};
+
+/// The settings of `fast_pack`, checked once when the config is made and reused for every
+/// `pack(examples)`.
+///
+/// The common settings are typed arguments, any other `fast_pack` option is passed as a
+/// keyword argument.
+#[pyclass(module = "binpack_rs")]
+pub struct PackConfig {
+    #[pyo3(get)]
+    pack_size: usize,
+    #[pyo3(get)]
+    algorithm: String,
+    #[pyo3(get)]
+    return_format: String,
+    #[pyo3(get)]
+    pad_id: Option<TokenId>,
+    #[pyo3(get)]
+    seed: Option<u64>,
+    #[pyo3(get)]
+    truncation_side: String,
+    // the kwargs of fast_pack, including seed and truncation_side
+    options: Py<PyDict>,
+}
+
+#[pymethods]
+impl PackConfig {
+    #[new]
+    #[pyo3(signature = (
+        pack_size,
+        algorithm="first_fit".to_string(),
+        return_format="composer".to_string(),
+        pad_id=None,
+        seed=None,
+        truncation_side="right".to_string(),
+        **kwargs
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        py: Python<'_>,
+        pack_size: usize,
+        algorithm: String,
+        return_format: String,
+        pad_id: Option<TokenId>,
+        seed: Option<u64>,
+        truncation_side: String,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Self> {
+        let options = match kwargs {
+            Some(kwargs) => kwargs.copy()?,
+            None => PyDict::new(py),
+        };
+        options.set_item("seed", seed)?;
+        options.set_item("truncation_side", &truncation_side)?;
+        parse_config(
+            pack_size,
+            &algorithm,
+            &return_format,
+            pad_id,
+            None,
+            Some(&options),
+        )?;
+        Ok(PackConfig {
+            pack_size,
+            algorithm,
+            return_format,
+            pad_id,
+            seed,
+            truncation_side,
+            options: options.unbind(),
+        })
+    }
+
+    // Packs the examples like fast_pack with these settings
+    fn pack(
+        &self,
+        py: Python<'_>,
+        examples: HashMap<String, Vec<Feature>>,
+    ) -> PyResult<PackOutput> {
+        fast_pack(
+            py,
+            examples,
+            self.pack_size,
+            self.algorithm.clone(),
+            self.return_format.clone(),
+            self.pad_id,
+            Some(self.options.bind(py)),
+        )
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PackConfig(pack_size={}, algorithm='{}', return_format='{}')",
+            self.pack_size, self.algorithm, self.return_format
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::ffi::c_str;
+
+    #[test]
+    fn test_pack_config() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let locals = PyDict::new(py);
+            locals
+                .set_item("PackConfig", py.get_type::<PackConfig>())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+config = PackConfig(8, "stable", pad_id=0, seed=3, return_doc_lengths=True)
+examples = {"input_ids": [[1, 1, 1], [2, 2, 2, 2, 2], [3, 3]]}
+first = config.pack(examples)
+second = config.pack(examples)
+"#
+                ),
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+            let get = |key: &str| locals.get_item(key).unwrap().unwrap();
+            let first: HashMap<String, Vec<Vec<TokenId>>> = get("first").extract().unwrap();
+            let tokens = vec![vec![3, 3, 1, 1, 1, 0, 0, 0], vec![2, 2, 2, 2, 2, 0, 0, 0]];
+            assert_eq!(first["tokens"], tokens);
+            assert_eq!(first["doc_lengths"], vec![vec![2, 3], vec![5]]);
+            assert!(get("first").eq(get("second")).unwrap());
+            assert_eq!(
+                get("config")
+                    .getattr("seed")
+                    .unwrap()
+                    .extract::<u64>()
+                    .unwrap(),
+                3
+            );
+
+            // the settings are checked when the config is made
+            let invalid = [
+                c_str!("PackConfig(8, 'no_such_algorithm')"),
+                c_str!("PackConfig(8, return_format='no_such_format')"),
+                c_str!("PackConfig(0)"),
+                c_str!("PackConfig(8, truncation_side='middle')"),
+                c_str!("PackConfig(8, pad_to_multiple_of=0)"),
+            ];
+            for code in invalid {
+                assert!(py.eval(code, None, Some(&locals)).is_err(), "{code:?}");
+            }
+        });
+    }
+}
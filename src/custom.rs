@@ -0,0 +1,95 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::sync::Mutex;
+
+use crate::packing::validate_packing;
+
+// The callable used by packing_algorithm="custom", set with register_packing_algorithm
+static CUSTOM_ALGORITHM: Mutex<Option<Py<PyAny>>> = Mutex::new(None);
+
+/// Registers the callable used by `packing_algorithm="custom"`, replacing any earlier one.
+///
+/// It gets the length histogram (`histogram[i]` sequences of length `i`) and the pack size,
+/// and returns the bins as lists of sequence lengths. The bins are checked to hold every
+/// sequence once and fit the pack size.
+#[pyfunction]
+pub fn register_packing_algorithm(callback: Bound<'_, PyAny>) -> PyResult<()> {
+    if !callback.is_callable() {
+        return Err(PyValueError::new_err(
+            "The packing algorithm must be callable",
+        ));
+    }
+    *CUSTOM_ALGORITHM.lock().unwrap() = Some(callback.unbind());
+    Ok(())
+}
+
+// Packs the histogram with the registered callable, the GIL is taken for the call
+pub fn pack(histogram: &[usize], pack_size: usize) -> PyResult<Vec<Vec<usize>>> {
+    let assignments = Python::with_gil(|py| {
+        let callback = CUSTOM_ALGORITHM
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|callback| callback.clone_ref(py))
+            .ok_or_else(|| {
+                PyValueError::new_err(
+                    "No custom packing algorithm, register one with register_packing_algorithm",
+                )
+            })?;
+        callback
+            .call1(py, (histogram.to_vec(), pack_size))?
+            .extract::<Vec<Vec<usize>>>(py)
+    })?;
+    let seq_lens = histogram
+        .iter()
+        .enumerate()
+        .flat_map(|(seq_len, &count)| std::iter::repeat_n(seq_len, count))
+        .collect::<Vec<usize>>();
+    validate_packing(&[seq_lens], &assignments, pack_size).map_err(|error| {
+        PyValueError::new_err(format!(
+            "Invalid packing from the custom algorithm: {error}"
+        ))
+    })?;
+    Ok(assignments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::ffi::c_str;
+
+    #[test]
+    fn test_custom_algorithm() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            // every sequence in a bin of its own
+            let callback = py
+                .eval(
+                    c_str!(
+                        "lambda histogram, pack_size: \
+                         [[l] for l, count in enumerate(histogram) for _ in range(count)]"
+                    ),
+                    None,
+                    None,
+                )
+                .unwrap();
+            register_packing_algorithm(callback).unwrap();
+        });
+        assert_eq!(
+            pack(&[0, 1, 0, 2], 4).unwrap(),
+            vec![vec![1], vec![3], vec![3]]
+        );
+
+        Python::with_gil(|py| {
+            // two sequences of length 3 do not fit in a bin of 4
+            let callback = c_str!("lambda histogram, pack_size: [[1], [3, 3]]");
+            register_packing_algorithm(py.eval(callback, None, None).unwrap()).unwrap();
+            let error = pack(&[0, 1, 0, 2], 4).unwrap_err();
+            assert!(error
+                .value(py)
+                .to_string()
+                .contains("holds more than 4 tokens"));
+            assert!(register_packing_algorithm(py.eval(c_str!("1"), None, None).unwrap()).is_err());
+        });
+    }
+}
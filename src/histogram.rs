@@ -0,0 +1,78 @@
+use pyo3::prelude::*;
+
+// Counts per length of a dataset that grows, index `i` holds the number of sequences of
+// length `i`. The same layout as compute_histogram
+#[pyclass(module = "binpack_rs")]
+#[derive(Debug, Default, PartialEq)]
+pub struct OnlineHistogram {
+    counts: Vec<usize>,
+}
+
+#[pymethods]
+impl OnlineHistogram {
+    #[new]
+    fn new() -> Self {
+        OnlineHistogram::default()
+    }
+
+    // Count a batch of sequence lengths, the histogram grows to fit the longest
+    pub fn update(&mut self, lengths: Vec<usize>) {
+        if let Some(&longest) = lengths.iter().max() {
+            if longest >= self.counts.len() {
+                self.counts.resize(longest + 1, 0);
+            }
+        }
+        for length in lengths {
+            self.counts[length] += 1;
+        }
+    }
+
+    // The counts so far, up to the longest length seen
+    pub fn counts(&self) -> Vec<usize> {
+        self.counts.clone()
+    }
+
+    // Number of tokens counted so far
+    pub fn num_tokens(&self) -> usize {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(length, count)| length * count)
+            .sum()
+    }
+
+    fn __len__(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "OnlineHistogram(sequences={}, max_len={})",
+            self.__len__(),
+            self.counts.len().saturating_sub(1)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_online_histogram() {
+        let mut histogram = OnlineHistogram::new();
+        assert_eq!(histogram.counts(), Vec::<usize>::new());
+        histogram.update(vec![2, 3, 2]);
+        assert_eq!(histogram.counts(), vec![0, 0, 2, 1]);
+        // a later batch with longer sequences grows the histogram
+        histogram.update(vec![5, 1, 3]);
+        histogram.update(vec![]);
+        assert_eq!(histogram.counts(), vec![0, 1, 2, 2, 0, 1]);
+        assert_eq!(histogram.__len__(), 6);
+        assert_eq!(histogram.num_tokens(), 16);
+        assert_eq!(
+            histogram.__repr__(),
+            "OnlineHistogram(sequences=6, max_len=5)"
+        );
+    }
+}
@@ -1,159 +1,3044 @@
 use pyo3::{exceptions::PyValueError, prelude::*, types::PyDict};
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod chunk;
 pub mod common;
+pub mod concat_split;
+pub mod config;
+pub mod custom;
+pub mod histogram;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod options;
 pub mod packing;
+pub mod pairs;
+#[cfg(feature = "spill")]
+pub mod spill;
+pub mod stats;
 pub mod strategy;
-use common::{Histogram, IFileHandles, Sequence};
+use common::{
+    into_batches, is_protected, Bin, Column, Columns, Example, Feature, Histogram, IFileHandles,
+    Sequence, TokenId,
+};
+use options::{PackOptions, ZeroLengthPolicy};
+use stats::{PackMeta, PackStats, PackingPlan};
 
-use strategy::common::fill_packing_strategy;
+use strategy::common::{fill_packing_strategy, populate_order, stream_packing_strategy};
+use strategy::iterator::{into_rows, PyReturnIter};
 use strategy::nemo::NemoOptions;
 
-#[derive(IntoPyObject, IntoPyObjectRef)]
+#[derive(Debug, PartialEq)]
 pub enum ReturnFormat {
-    Composer(HashMap<String, Vec<Vec<u32>>>),
+    Composer(Columns),
     // Nemo has the same format, but the keys are different
     // Different entries
-    Nemo(HashMap<String, Vec<Vec<u32>>>),
+    Nemo(Columns),
+    // Same outputs as composer, but returned as an iterator of per-bin dicts
+    Iterator(Columns),
+    // Composer rows with a loss_mask, returned flat with a single offsets array
+    Csr(Columns),
+}
+
+impl ReturnFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReturnFormat::Composer(_) => "composer",
+            ReturnFormat::Nemo(_) => "nemo",
+            ReturnFormat::Iterator(_) => "iterator",
+            ReturnFormat::Csr(_) => "csr",
+        }
+    }
+
+    pub fn data(&self) -> &Columns {
+        match self {
+            ReturnFormat::Composer(result)
+            | ReturnFormat::Nemo(result)
+            | ReturnFormat::Iterator(result)
+            | ReturnFormat::Csr(result) => result,
+        }
+    }
+
+    pub fn into_data(self) -> Columns {
+        match self {
+            ReturnFormat::Composer(result)
+            | ReturnFormat::Nemo(result)
+            | ReturnFormat::Iterator(result)
+            | ReturnFormat::Csr(result) => result,
+        }
+    }
+
+    pub fn data_mut(&mut self) -> &mut Columns {
+        match self {
+            ReturnFormat::Composer(result)
+            | ReturnFormat::Nemo(result)
+            | ReturnFormat::Iterator(result)
+            | ReturnFormat::Csr(result) => result,
+        }
+    }
+
+    // Get a token output, panics if the output does not exist or holds floats
+    pub fn sequences(&self, key: &str) -> &Vec<Sequence> {
+        match self.data().get(key) {
+            Some(Column::Tokens(rows)) => rows,
+            other => panic!("Expected '{key}' to be a token output, got {other:?}"),
+        }
+    }
+
+    // Add an extra output, the key is the same for every format
+    pub fn insert(&mut self, key: &str, value: impl Into<Column>) {
+        match self {
+            ReturnFormat::Composer(result)
+            | ReturnFormat::Nemo(result)
+            | ReturnFormat::Iterator(result)
+            | ReturnFormat::Csr(result) => {
+                result.insert(key.to_string(), value.into());
+            }
+        }
+    }
+}
+
+impl<'py> IntoPyObject<'py> for ReturnFormat {
+    type Target = PyAny;
+    type Output = Bound<'py, PyAny>;
+    type Error = PyErr;
+
+    fn into_pyobject(self, py: Python<'py>) -> Result<Self::Output, Self::Error> {
+        match self {
+            ReturnFormat::Composer(result) | ReturnFormat::Nemo(result) => {
+                Ok(result.into_pyobject(py)?.into_any())
+            }
+            ReturnFormat::Iterator(result) => {
+                Ok(Bound::new(py, PyReturnIter::new(result))?.into_any())
+            }
+            ReturnFormat::Csr(result) => Ok(csr_dict(py, result)?.into_any()),
+        }
+    }
+}
+
+// Row `i` of every token aligned output is `values[offsets[i]..offsets[i + 1]]`. Outputs
+// with another length per row (e.g. doc_lengths) are kept as a list per row
+fn csr_dict(py: Python<'_>, result: Columns) -> PyResult<Bound<'_, PyDict>> {
+    let row_lens = result
+        .get("tokens")
+        .map(Column::row_lens)
+        .unwrap_or_default();
+    let mut offsets = vec![0];
+    offsets.extend(row_lens.iter().scan(0, |end, len| {
+        *end += len;
+        Some(*end)
+    }));
+    let dict = PyDict::new(py);
+    dict.set_item("offsets", offsets)?;
+    for (key, column) in result {
+        if column.row_lens() == row_lens {
+            dict.set_item(key, column.flatten())?;
+        } else {
+            dict.set_item(key, column)?;
+        }
+    }
+    Ok(dict)
 }
 
 impl std::str::FromStr for ReturnFormat {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().as_str() {
-            "nemo" => Ok(ReturnFormat::Nemo(HashMap::new())),
-            "composer" => Ok(ReturnFormat::Composer(HashMap::new())),
+        match s.trim().to_lowercase().as_str() {
+            "nemo" => Ok(ReturnFormat::Nemo(Columns::new())),
+            // the composer dict of lists loads straight into a huggingface Dataset.from_dict
+            "composer" | "hf" | "huggingface" => Ok(ReturnFormat::Composer(Columns::new())),
+            "iterator" | "iter" => Ok(ReturnFormat::Iterator(Columns::new())),
+            "csr" => Ok(ReturnFormat::Csr(Columns::new())),
             _ => Err("Invalid return format"),
         }
     }
 }
 
+// fast_pack only returns the stats when they are asked for, once per call so the size
+// of the stats does not matter
+#[derive(IntoPyObject)]
+#[allow(clippy::large_enum_variant)]
+pub enum PackOutput {
+    Result(PyObject),
+    WithStats(PyObject, PackStats),
+}
+
 #[allow(dead_code)]
 enum InputFormat {
     DictOfList(HashMap<String, Vec<Sequence>>),
     ListOfDicts(Vec<HashMap<String, Sequence>>),
 }
 
-/// Formats the sum of two numbers as string.
+/// Packs the examples into bins of at most `target_pack_size` tokens.
+///
+/// The GIL is released while packing, so other Python threads (e.g. a dataloader)
+/// keep running until the result is converted back to Python objects.
 #[pyfunction]
 #[pyo3(signature = (examples, target_pack_size, packing_algorithm, return_format, pad_id, **kwargs))]
 fn fast_pack(
-    examples: HashMap<String, Vec<Sequence>>,
+    py: Python<'_>,
+    examples: HashMap<String, Vec<Feature>>,
+    target_pack_size: usize,
+    packing_algorithm: String,
+    return_format: String,
+    pad_id: Option<TokenId>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PackOutput> {
+    run_pack(
+        py,
+        examples,
+        None,
+        target_pack_size,
+        packing_algorithm,
+        return_format,
+        pad_id,
+        kwargs,
+    )
+}
+
+/// Same as `fast_pack`, but with the counts per length from `compute_histogram`, so the
+/// lengths are not counted again.
+///
+/// The histogram must match the `input_ids` of the examples.
+#[pyfunction]
+#[pyo3(signature = (examples, histogram, target_pack_size, packing_algorithm, return_format, pad_id, **kwargs))]
+#[allow(clippy::too_many_arguments)]
+fn pack_from_histogram(
+    py: Python<'_>,
+    examples: HashMap<String, Vec<Feature>>,
+    histogram: Vec<usize>,
     target_pack_size: usize,
     packing_algorithm: String,
     return_format: String,
-    pad_id: Option<u32>,
+    pad_id: Option<TokenId>,
     kwargs: Option<&Bound<'_, PyDict>>,
-) -> PyResult<ReturnFormat> {
-    let (sequences, seq_lens) = create_hist(examples.clone(), target_pack_size);
+) -> PyResult<PackOutput> {
+    run_pack(
+        py,
+        examples,
+        Some(histogram),
+        target_pack_size,
+        packing_algorithm,
+        return_format,
+        pad_id,
+        kwargs,
+    )
+}
+
+/// Counts the sequences of each length, index `i` holds the number of sequences of length
+/// `i`. Pass it to `pack_from_histogram` to pack without counting again.
+#[pyfunction]
+#[pyo3(signature = (seq_lens, target_pack_size))]
+fn compute_histogram(seq_lens: Vec<usize>, target_pack_size: usize) -> PyResult<Vec<usize>> {
+    count_lengths(&seq_lens, target_pack_size)
+}
+
+fn count_lengths(seq_lens: &[usize], target_pack_size: usize) -> PyResult<Vec<usize>> {
+    let mut histogram = vec![0usize; target_pack_size + 1];
+    for &seq_len in seq_lens {
+        if seq_len > target_pack_size {
+            return Err(PyValueError::new_err(
+                "Sequence length exceeds the maximum allowed length.",
+            ));
+        }
+        histogram[seq_len] += 1;
+    }
+    Ok(histogram)
+}
+
+// The packing algorithm, options and return format of a pack, checked before any example
+// is looked at. PackConfig checks its settings with it once
+fn parse_config(
+    target_pack_size: usize,
+    packing_algorithm: &str,
+    return_format: &str,
+    pad_id: Option<TokenId>,
+    histogram: Option<Vec<usize>>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<(
+    packing::PackingAlgo,
+    PackOptions,
+    ReturnFormat,
+    Option<NemoOptions>,
+)> {
+    if target_pack_size == 0 {
+        return Err(PyValueError::new_err(
+            "target_pack_size must be greater than 0",
+        ));
+    }
     let packing_algorithm = match packing_algorithm
         .parse::<packing::PackingAlgo>() {
         Ok(packing_algorithm) => packing_algorithm,
         Err(_) => {
             return Err(pyo3::exceptions::PyValueError::new_err(
-                "Invalid packing algorithm. Use 'first_fit', 'first_fit_shuffle', or 'first_fit_decreasing'.",
+                "Invalid packing algorithm. Use 'first_fit', 'first_fit_shuffle', 'first_fit_decreasing', 'first_fit_decreasing_attention', 'stable', 'stable_decreasing', 'min_shapes', 'strict_first_fit', 'next_fit_decreasing', 'best_fit_decreasing', 'none', 'balanced:<num_bins>', 'seqs_per_bin:<num_seqs>', 'first_fit_weighted:<bias>', or 'custom'.",
             ))
         }
     };
+    let mut pack_options = PackOptions::builder()
+        .histogram(histogram)
+        .from_py_dict(kwargs)?
+        .build()?;
+    // the seed is only known once the options are built (e.g. with deterministic)
+    pack_options.shuffle = packing_algorithm.shuffles_buckets(pack_options.seed);
+    if pack_options.shared_prefix.len() + pack_options.reserve_slots >= target_pack_size {
+        return Err(PyValueError::new_err(
+            "shared_prefix and reserve_slots must leave room in target_pack_size",
+        ));
+    }
 
-    let assignments = create_packing_strategy(seq_lens, target_pack_size, packing_algorithm);
-    let result = match return_format.as_str() {
-        "composer" => {
-            // Composer does not need answer_start_id, etc.
-            fill_packing_strategy(
-                assignments,
-                sequences,
+    let return_format = return_format
+        .parse::<ReturnFormat>()
+        .map_err(|_| PyValueError::new_err("Unknown format"))?;
+    let options = match return_format {
+        // Composer does not need answer_start_id, etc.
+        ReturnFormat::Composer(_) | ReturnFormat::Iterator(_) | ReturnFormat::Csr(_) => None,
+        ReturnFormat::Nemo(_) if pack_options.separator_id.is_some() => {
+            return Err(PyValueError::new_err(
+                "separator_id is only supported by the composer and iterator formats",
+            ))
+        }
+        ReturnFormat::Nemo(_) => {
+            // Extract Nemo-specific kwargs from kwargs dict
+            Some(
+                NemoOptions::builder()
+                    .pad_id(pad_id)
+                    .from_py_dict(kwargs)?
+                    .build()?,
+            )
+        }
+    };
+    Ok((packing_algorithm, pack_options, return_format, options))
+}
+
+// The shared body of fast_pack and pack_from_histogram
+#[allow(clippy::too_many_arguments)]
+fn run_pack(
+    py: Python<'_>,
+    examples: HashMap<String, Vec<Feature>>,
+    histogram: Option<Vec<usize>>,
+    target_pack_size: usize,
+    packing_algorithm: String,
+    return_format: String,
+    pad_id: Option<TokenId>,
+    kwargs: Option<&Bound<'_, PyDict>>,
+) -> PyResult<PackOutput> {
+    let algorithm_name = packing_algorithm.to_lowercase();
+    let (packing_algorithm, pack_options, return_format, options) = parse_config(
+        target_pack_size,
+        &packing_algorithm,
+        &return_format,
+        pad_id,
+        histogram,
+        kwargs,
+    )?;
+
+    let meta = pack_options.return_meta.then(|| PackMeta {
+        algorithm: algorithm_name,
+        pack_size: target_pack_size,
+        seed: pack_options.seed,
+        pad_id,
+        truncation_side: pack_options.truncation_side.name().to_string(),
+        return_format: return_format.name().to_string(),
+    });
+
+    let bin_callback = bin_callback(kwargs)?;
+    if matches!(return_format, ReturnFormat::Csr(_))
+        && (bin_callback.is_some() || pack_options.as_arrow || pack_options.batch_size.is_some())
+    {
+        return Err(PyValueError::new_err(
+            "The csr format cannot be combined with as_arrow, batch_size or bin_callback",
+        ));
+    }
+    if pack_options.output_path.is_some()
+        && (bin_callback.is_some()
+            || pack_options.as_arrow
+            || pack_options.batch_size.is_some()
+            || matches!(return_format, ReturnFormat::Iterator(_)))
+    {
+        return Err(PyValueError::new_err(
+            "output_path cannot be combined with the iterator format, as_arrow, batch_size or \
+             bin_callback",
+        ));
+    }
+    // the rows of csr are flattened, the other outputs are not a dict of lists
+    if pack_options.row_major
+        && (bin_callback.is_some()
+            || pack_options.as_arrow
+            || pack_options.batch_size.is_some()
+            || pack_options.output_path.is_some()
+            || matches!(return_format, ReturnFormat::Csr(_)))
+    {
+        return Err(PyValueError::new_err(
+            "row_major cannot be combined with the csr format, as_arrow, batch_size, \
+             output_path or bin_callback",
+        ));
+    }
+    // the schema describes the rows of the result, csr has flat columns instead
+    if pack_options.return_schema
+        && (bin_callback.is_some() || matches!(return_format, ReturnFormat::Csr(_)))
+    {
+        return Err(PyValueError::new_err(
+            "return_schema cannot be combined with the csr format or bin_callback",
+        ));
+    }
+    // only the iterator rows keep the other columns, the other formats would drop the
+    // multiplicity of the kept copies
+    if pack_options.dedup && !matches!(return_format, ReturnFormat::Iterator(_)) {
+        return Err(PyValueError::new_err(
+            "dedup is only supported by the iterator format, which returns the multiplicity \
+             of every example",
+        ));
+    }
+    // the rows go to the callback before the longest row is known
+    if pack_options.pad_to_longest && bin_callback.is_some() {
+        return Err(PyValueError::new_err(
+            "pad_to_longest cannot be combined with bin_callback",
+        ));
+    }
+    if pack_options.batch_size.is_some()
+        && (bin_callback.is_some()
+            || pack_options.as_arrow
+            || matches!(return_format, ReturnFormat::Iterator(_)))
+    {
+        return Err(PyValueError::new_err(
+            "batch_size is only supported by the composer and nemo formats, without as_arrow \
+             or bin_callback",
+        ));
+    }
+
+    if let Some(bin_callback) = bin_callback {
+        // Rows go to the callback as they are filled, the GIL is only taken for each call
+        let (result, mut stats) = py.allow_threads(|| {
+            pack_with(
+                examples,
                 target_pack_size,
-                pad_id,
-                ReturnFormat::Composer(HashMap::new()),
-                None,
+                packing_algorithm,
+                &pack_options,
+                |assignments, sequences, stats| {
+                    stream_packing_strategy(
+                        assignments,
+                        sequences,
+                        target_pack_size,
+                        pad_id,
+                        return_format,
+                        &pack_options,
+                        options,
+                        stats,
+                        |row| Python::with_gil(|py| bin_callback.call1(py, (row,)).map(drop)),
+                    )
+                },
             )
+        });
+        result?;
+        stats.meta = meta.map(|meta| used_meta(meta, &stats));
+        return if pack_options.returns_stats() {
+            Ok(PackOutput::WithStats(py.None(), stats))
+        } else {
+            Ok(PackOutput::Result(py.None()))
+        };
+    }
+
+    // Everything from here on is pure rust, the GIL is only needed again
+    // to convert the result
+    let (result, mut stats) = py.allow_threads(|| {
+        pack(
+            examples,
+            target_pack_size,
+            packing_algorithm,
+            pad_id,
+            return_format,
+            &pack_options,
+            options,
+        )
+    });
+
+    let result = result?;
+    stats.meta = meta.map(|meta| used_meta(meta, &stats));
+    if pack_options.return_schema {
+        stats.schema = Some(stats::schema(result.data()));
+    }
+    let result = if let Some(path) = &pack_options.output_path {
+        to_mmap(py, &result, path)?
+    } else if pack_options.as_arrow {
+        to_pyarrow(py, &result)?
+    } else if let Some(batch_size) = pack_options.batch_size {
+        // a list of batches, each batch is a dict with batch_size rows of every output
+        into_batches(result.into_data(), batch_size)
+            .into_pyobject(py)?
+            .unbind()
+    } else if pack_options.row_major {
+        // a list with a dict per row, the columns transposed
+        into_rows(result.into_data()).into_pyobject(py)?.unbind()
+    } else {
+        result.into_pyobject(py)?.unbind()
+    };
+    if pack_options.returns_stats() {
+        Ok(PackOutput::WithStats(result, stats))
+    } else {
+        Ok(PackOutput::Result(result))
+    }
+}
+
+// With target_efficiency, the meta has the algorithm that was used
+fn used_meta(meta: PackMeta, stats: &PackStats) -> PackMeta {
+    match &stats.algorithm {
+        Some(algorithm) => PackMeta {
+            algorithm: algorithm.clone(),
+            ..meta
+        },
+        None => meta,
+    }
+}
+
+#[cfg(feature = "mmap")]
+fn to_mmap(py: Python<'_>, result: &ReturnFormat, path: &str) -> PyResult<PyObject> {
+    let output = py.allow_threads(|| mmap::write_mmap(result.data(), path))?;
+    Ok(output.into_pyobject(py)?.into_any().unbind())
+}
+
+#[cfg(not(feature = "mmap"))]
+fn to_mmap(_py: Python<'_>, _result: &ReturnFormat, _path: &str) -> PyResult<PyObject> {
+    Err(PyValueError::new_err(
+        "output_path requires binpack_rs to be built with the 'mmap' feature",
+    ))
+}
+
+// The `bin_callback` kwarg, a callable that gets each packed row as a dict
+fn bin_callback(kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Option<PyObject>> {
+    let callback = match kwargs {
+        Some(kwargs) => kwargs.get_item("bin_callback")?,
+        None => None,
+    };
+    match callback {
+        Some(callback) if callback.is_none() => Ok(None),
+        Some(callback) if !callback.is_callable() => {
+            Err(PyValueError::new_err("bin_callback must be callable"))
         }
-        "nemo" => {
-            // Extract Nemo-specific kwargs from kwargs dict
-            let options = NemoOptions::builder().from_py_dict(kwargs)?.build()?;
+        callback => Ok(callback.map(Bound::unbind)),
+    }
+}
+
+#[cfg(feature = "arrow")]
+fn to_pyarrow(py: Python<'_>, result: &ReturnFormat) -> PyResult<PyObject> {
+    use arrow_pyarrow::ToPyArrow;
 
+    let batch =
+        arrow::to_record_batch(result.data()).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    batch.to_pyarrow(py)
+}
+
+#[cfg(not(feature = "arrow"))]
+fn to_pyarrow(_py: Python<'_>, _result: &ReturnFormat) -> PyResult<PyObject> {
+    Err(PyValueError::new_err(
+        "as_arrow requires binpack_rs to be built with the 'arrow' feature",
+    ))
+}
+
+// The GIL free part of fast_pack
+fn pack(
+    examples: HashMap<String, Vec<Feature>>,
+    target_pack_size: usize,
+    packing_algorithm: packing::PackingAlgo,
+    pad_id: Option<TokenId>,
+    return_format: ReturnFormat,
+    pack_options: &PackOptions,
+    options: Option<NemoOptions>,
+) -> (PyResult<ReturnFormat>, PackStats) {
+    pack_with(
+        examples,
+        target_pack_size,
+        packing_algorithm,
+        pack_options,
+        |assignments, sequences, stats| {
             fill_packing_strategy(
                 assignments,
                 sequences,
                 target_pack_size,
                 pad_id,
-                ReturnFormat::Nemo(HashMap::new()),
-                Some(options),
+                return_format,
+                pack_options,
+                options,
+                stats,
             )
+        },
+    )
+}
+
+// Build the histogram and the bins, then hand them to `fill`
+fn pack_with<T, E: From<PyErr>>(
+    examples: HashMap<String, Vec<Feature>>,
+    target_pack_size: usize,
+    packing_algorithm: packing::PackingAlgo,
+    pack_options: &PackOptions,
+    fill: impl FnOnce(Vec<Vec<usize>>, &Histogram, &mut PackStats) -> Result<T, E>,
+) -> (Result<T, E>, PackStats) {
+    let mut stats = PackStats::default();
+    // the shared prefix is added to every row and the reserved slots stay free, so the
+    // sequences only get the rest
+    let target_pack_size = target_pack_size
+        .saturating_sub(pack_options.shared_prefix.len() + pack_options.reserve_slots);
+    let start = Instant::now();
+    // a prompt and its response are packed as a single sequence
+    let mut examples = match pairs::group_pairs(examples) {
+        Ok(examples) => examples,
+        Err(err) => return (Err(err.into()), stats),
+    };
+    if pack_options.dedup {
+        (examples, stats.num_duplicates) = match dedup_examples(examples) {
+            Ok(deduped) => deduped,
+            Err(err) => return (Err(err.into()), stats),
+        };
+    }
+    let (sequences, mut seq_lens) = match create_hist(examples, target_pack_size, pack_options) {
+        Ok(hist) => hist,
+        Err(err) => return (Err(err.into()), stats),
+    };
+    // the longest length with any sequences, 0 for an empty dataset
+    stats.max_seq_len = seq_lens.iter().rposition(|&count| count > 0).unwrap_or(0);
+    let histogram_time = start.elapsed();
+
+    let start = Instant::now();
+    // protected sequences are left out of the packing and get a bin of their own, so
+    // they are never truncated or merged
+    let mut protected = sequences
+        .iter()
+        .flat_map(|(&seq_len, bucket)| {
+            bucket
+                .iter()
+                .filter(|entry| is_protected(entry))
+                .map(move |_| seq_len)
+        })
+        .collect::<Vec<usize>>();
+    protected.sort_unstable();
+    for &seq_len in &protected {
+        seq_lens[seq_len] = seq_lens[seq_len].saturating_sub(1);
+    }
+    // A separator goes between the sequences of a bin. Packing every sequence one token
+    // longer into a bin one token larger leaves exactly enough room for them
+    let separator_len = pack_options.separator_id.map_or(0, |_| 1);
+    let mut histogram = vec![0; separator_len];
+    histogram.extend(seq_lens);
+    let mut assignments = match pack_options.target_efficiency {
+        Some(target) => {
+            let (assignments, algorithm) = escalate_packing(
+                histogram,
+                target_pack_size + separator_len,
+                target,
+                pack_options.seed,
+            );
+            stats.algorithm = Some(algorithm.to_string());
+            assignments
         }
-        _ => return Err(PyValueError::new_err("Unknown format")),
+        None => match pack_options.time_budget_ms {
+            Some(budget) if packing_algorithm != packing::PackingAlgo::Custom => {
+                let deadline = start + Duration::from_millis(budget);
+                let (assignments, unplaced) = packing_algorithm.pack_until(
+                    expand_histogram(&histogram),
+                    target_pack_size + separator_len,
+                    pack_options.seed,
+                    deadline,
+                );
+                stats.unplaced = unplaced.into_iter().map(|s| s - separator_len).collect();
+                let pack_size = target_pack_size + separator_len;
+                if let Err(err) = packing_algorithm.check_fit(&assignments, pack_size) {
+                    return (Err(PyValueError::new_err(err).into()), stats);
+                }
+                assignments
+            }
+            _ => match create_packing_strategy(
+                histogram,
+                target_pack_size + separator_len,
+                packing_algorithm,
+                pack_options.seed,
+                pack_options.solo_threshold,
+            ) {
+                Ok(assignments) => assignments,
+                Err(err) => return (Err(err.into()), stats),
+            },
+        },
     };
+    if let Some(min_seqs) = pack_options.min_seqs_per_bin {
+        assignments =
+            packing::merge_small_bins(assignments, target_pack_size + separator_len, min_seqs);
+    }
+    for seq_len in assignments.iter_mut().flatten() {
+        *seq_len -= separator_len;
+    }
+    if let Some(min_tokens) = pack_options.min_tokens {
+        (assignments, stats.residual_bins) = packing::split_residual_bins(assignments, min_tokens);
+    }
+    assignments.extend(protected.into_iter().map(|seq_len| vec![seq_len]));
+    packing::sort_bins(&mut assignments, pack_options.sort_bins_by);
+    packing::drop_empty_bins(&mut assignments);
+    if let Some(batch_size) = pack_options.batch_size {
+        packing::fit_to_batches(&mut assignments, batch_size, pack_options.last_batch);
+    }
+    // after every step that drops or reorders bins, so the indices are those of the rows
+    if let Some(min_seqs) = pack_options.min_seqs_per_bin {
+        stats.unmergeable_bins = packing::small_bins(&assignments, min_seqs);
+    }
+    stats.bin_seq_counts = assignments.iter().map(Vec::len).collect();
+    stats.attention_cost = packing::attention_cost(&assignments);
+    stats.num_shapes = packing::count_shapes(&assignments);
+    let packing_time = start.elapsed();
+
+    let start = Instant::now();
+    let result = fill(assignments, &sequences, &mut stats);
+    let fill_time = start.elapsed();
 
-    Ok(result)
+    if pack_options.return_timing {
+        let ms = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+        stats.timing = Some(BTreeMap::from([
+            ("histogram".to_string(), ms(histogram_time)),
+            ("packing".to_string(), ms(packing_time)),
+            ("fill".to_string(), ms(fill_time)),
+        ]));
+    }
+    (result, stats)
+}
+
+/// Plans the packing using only the sequence lengths, no token arrays are needed.
+///
+/// Returns the length composition of every bin along with per-bin fill, sequence counts
+/// and padding, which is useful for capacity planning.
+#[pyfunction]
+#[pyo3(signature = (seq_lens, target_pack_size, packing_algorithm, seed=None))]
+fn plan_packing(
+    py: Python<'_>,
+    seq_lens: Vec<usize>,
+    target_pack_size: usize,
+    packing_algorithm: String,
+    seed: Option<u64>,
+) -> PyResult<PackingPlan> {
+    let packing_algorithm = packing_algorithm
+        .parse::<packing::PackingAlgo>()
+        .map_err(PyValueError::new_err)?;
+    py.allow_threads(|| plan(&seq_lens, target_pack_size, packing_algorithm, seed))
+}
+
+fn plan(
+    seq_lens: &[usize],
+    target_pack_size: usize,
+    packing_algorithm: packing::PackingAlgo,
+    seed: Option<u64>,
+) -> PyResult<PackingPlan> {
+    let histogram = count_lengths(seq_lens, target_pack_size)?;
+    let assignments =
+        create_packing_strategy(histogram, target_pack_size, packing_algorithm, seed, None)?;
+    Ok(PackingPlan::new(assignments, target_pack_size))
+}
+
+/// Estimates the bytes the packed output of `fast_pack` takes, before packing.
+///
+/// Every format has two token sized values per packed token (tokens and position ids, or
+/// input ids and the loss mask), and every row is counted at the full pack size. Nemo rows
+/// are not padded, so for nemo this is an upper bound.
+#[pyfunction]
+#[pyo3(signature = (seq_lens, pack_size, return_format, packing_algorithm="first_fit".to_string()))]
+fn estimate_memory(
+    py: Python<'_>,
+    seq_lens: Vec<usize>,
+    pack_size: usize,
+    return_format: String,
+    packing_algorithm: String,
+) -> PyResult<usize> {
+    let return_format = return_format
+        .parse::<ReturnFormat>()
+        .map_err(PyValueError::new_err)?;
+    let packing_algorithm = packing_algorithm
+        .parse::<packing::PackingAlgo>()
+        .map_err(PyValueError::new_err)?;
+    py.allow_threads(|| memory_estimate(&seq_lens, pack_size, &return_format, packing_algorithm))
+}
+
+fn memory_estimate(
+    seq_lens: &[usize],
+    pack_size: usize,
+    return_format: &ReturnFormat,
+    packing_algorithm: packing::PackingAlgo,
+) -> PyResult<usize> {
+    let num_bins = plan(seq_lens, pack_size, packing_algorithm, None)?
+        .assignments
+        .len();
+    let fields_per_token = match return_format {
+        // tokens and positions_ids
+        ReturnFormat::Composer(_) | ReturnFormat::Iterator(_) => 2,
+        // input_ids and loss_mask, seq_start_id has a single value per row
+        ReturnFormat::Nemo(_) => 2,
+        // tokens, positions_ids and loss_mask, the offsets have a single value per row
+        ReturnFormat::Csr(_) => 3,
+    };
+    Ok(num_bins * pack_size * fields_per_token * std::mem::size_of::<TokenId>())
+}
+
+/// Combines the bins of two packings, e.g. of shards packed separately.
+///
+/// Full bins are kept, with `repack_tails` the sequences of the partially filled bins are
+/// packed again so the merged packing has fewer half empty bins.
+#[pyfunction]
+#[pyo3(signature = (a, b, pack_size, repack_tails=true))]
+fn merge_packings(
+    a: Vec<Vec<usize>>,
+    b: Vec<Vec<usize>>,
+    pack_size: usize,
+    repack_tails: bool,
+) -> PyResult<Vec<Vec<usize>>> {
+    if a.iter()
+        .chain(&b)
+        .any(|bin| bin.iter().sum::<usize>() > pack_size)
+    {
+        return Err(PyValueError::new_err(
+            "A bin holds more tokens than the pack size.",
+        ));
+    }
+    Ok(packing::merge_packings(a, b, pack_size, repack_tails))
+}
+
+/// Packs the sequences of the bins filled below `threshold` (a fraction of `pack_size`)
+/// again with first fit decreasing, e.g. the sparse last bins of `plan_packing`.
+///
+/// The other bins are kept, the repacked bins go last.
+#[pyfunction]
+#[pyo3(signature = (assignments, pack_size, threshold=0.5))]
+fn repack_tail(
+    assignments: Vec<Vec<usize>>,
+    pack_size: usize,
+    threshold: f64,
+) -> PyResult<Vec<Vec<usize>>> {
+    if !(threshold > 0.0 && threshold <= 1.0) {
+        return Err(PyValueError::new_err("threshold must be in (0, 1]"));
+    }
+    if assignments
+        .iter()
+        .any(|bin| bin.iter().sum::<usize>() > pack_size)
+    {
+        return Err(PyValueError::new_err(
+            "A bin holds more tokens than the pack size.",
+        ));
+    }
+    let repacked = packing::repack_tail(assignments.clone(), pack_size, threshold);
+    packing::validate_packing(&assignments, &repacked, pack_size)
+        .map_err(pyo3::exceptions::PyRuntimeError::new_err)?;
+    Ok(repacked)
+}
+
+/// The indices of the bins of `assignments` for each of `num_shards` shards, e.g. one per
+/// data parallel rank. Whole bins are spread so every shard gets about the same number of
+/// tokens, within the tokens of the largest bin.
+#[pyfunction]
+fn shard_packing(assignments: Vec<Vec<usize>>, num_shards: usize) -> PyResult<Vec<Vec<usize>>> {
+    if num_shards == 0 {
+        return Err(PyValueError::new_err("num_shards must be greater than 0"));
+    }
+    Ok(packing::shard_bins(&assignments, num_shards))
+}
+
+/// The indices of `seq_lens` for each of `num_chunks` chunks, e.g. to plan or pack the chunks
+/// separately and merge them with `merge_packings`. Every chunk gets the same mix of lengths
+/// and about the same number of tokens, so the chunks pack about as well as the whole.
+#[pyfunction]
+fn stratified_chunks(seq_lens: Vec<usize>, num_chunks: usize) -> PyResult<Vec<Vec<usize>>> {
+    if num_chunks == 0 {
+        return Err(PyValueError::new_err("num_chunks must be greater than 0"));
+    }
+    Ok(packing::stratified_chunks(&seq_lens, num_chunks))
+}
+
+/// The pools of sequences the bins are filled from, for checking the bucketing and shuffling.
+///
+/// Maps every length to the rows of `examples` with that many `input_ids`, in the order the
+/// bins take them, so the number of rows is the count of the length. Empty rows are dropped
+/// like in `fast_pack`, and the same `seed` gives the same order as `fast_pack` with it.
+#[pyfunction]
+#[pyo3(signature = (examples, pack_size, seed=None))]
+fn debug_populate(
+    py: Python<'_>,
+    examples: HashMap<String, Vec<Feature>>,
+    pack_size: usize,
+    seed: Option<u64>,
+) -> PyResult<BTreeMap<usize, Vec<usize>>> {
+    if pack_size == 0 {
+        return Err(PyValueError::new_err("pack_size must be greater than 0"));
+    }
+    let pack_options = PackOptions::builder().seed(seed).build()?;
+    py.allow_threads(|| {
+        // the rows of every length in input order, the order of its bucket
+        let mut rows: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        if let Some(input_ids) = examples.get("input_ids") {
+            for (row, ids) in input_ids.iter().enumerate() {
+                match ids.as_tokens() {
+                    Some(ids) if !ids.is_empty() => rows.entry(ids.len()).or_default().push(row),
+                    _ => {}
+                }
+            }
+        }
+        let (sequences, _) = create_hist(examples, pack_size, &pack_options)?;
+        let order = populate_order(&sequences, pack_size, &pack_options);
+        Ok(order
+            .into_iter()
+            .map(|(seq_len, order)| {
+                let rows = &rows[&seq_len];
+                (seq_len, order.into_iter().map(|i| rows[i]).collect())
+            })
+            .collect())
+    })
+}
+
+/// The (length, count) segments of every bin of `assignments`, e.g. for a stacked bar
+/// chart of the bin composition. Consecutive sequences of the same length are one segment.
+#[pyfunction]
+fn bin_segments(assignments: Vec<Vec<usize>>) -> Vec<Vec<(usize, usize)>> {
+    packing::bin_segments(&assignments)
+}
+
+// Keeps only the first example of each distinct input_ids, with a `multiplicity` column
+// counting its copies. Returns the kept examples and the number of examples left out
+fn dedup_examples(
+    mut dataset: HashMap<String, Vec<Feature>>,
+) -> PyResult<(HashMap<String, Vec<Feature>>, usize)> {
+    if dataset.contains_key("multiplicity") {
+        return Err(PyValueError::new_err(
+            "dedup adds a 'multiplicity' column, which the dataset already has",
+        ));
+    }
+    let input_ids = dataset
+        .get("input_ids")
+        .expect("Expected key 'input_ids' in the dataset");
+    // index of the first example with the same input_ids, for every example
+    let mut first_of: HashMap<&Sequence, usize> = HashMap::new();
+    let first = input_ids
+        .iter()
+        .enumerate()
+        .map(|(row, seq)| {
+            let seq = seq
+                .as_tokens()
+                .expect("Expected key 'input_ids' to be a list of token ids");
+            *first_of.entry(seq).or_insert(row)
+        })
+        .collect::<Vec<usize>>();
+    let num_duplicates = input_ids.len() - first_of.len();
+    let mut multiplicity = vec![0; input_ids.len()];
+    for &row in &first {
+        multiplicity[row] += 1;
+    }
+    let kept = |row: &usize| first[*row] == *row;
+    for values in dataset.values_mut() {
+        let mut rows = 0..;
+        values.retain(|_| rows.next().is_some_and(|row| kept(&row)));
+    }
+    let multiplicity = (0..first.len())
+        .filter(kept)
+        .map(|row| Feature::Int(multiplicity[row]))
+        .collect();
+    dataset.insert("multiplicity".to_string(), multiplicity);
+    Ok((dataset, num_duplicates))
+}
+
+// Move a run of examples with the same length into the histogram at once
+fn flush_run(sequences: &mut Histogram, seq_len: usize, run: &mut Vec<Example>) {
+    match sequences.entry(seq_len) {
+        Entry::Vacant(bucket) => {
+            bucket.insert(std::mem::take(run));
+        }
+        Entry::Occupied(mut bucket) => bucket.get_mut().append(run),
+    }
+}
+
+// Checks the columns that are only read while filling, which runs in parallel without the
+// GIL, so a bad value is a ValueError here instead of a panic there
+fn check_columns(
+    dataset: &HashMap<String, Vec<Feature>>,
+    pack_options: &PackOptions,
+) -> PyResult<()> {
+    let input_ids = &dataset["input_ids"];
+    if let Some(key) = &pack_options.sort_key {
+        let Some(values) = dataset.get(key) else {
+            return Err(PyValueError::new_err(format!(
+                "Expected the sort_key '{key}' in the dataset"
+            )));
+        };
+        if let Some(other) = values.iter().find(|value| value.as_f64().is_none()) {
+            return Err(PyValueError::new_err(format!(
+                "Expected the sort_key '{key}' to be a number, got {other:?}"
+            )));
+        }
+    }
+    if let Some(offsets) = dataset.get("position_offset") {
+        for (offset, input_ids) in offsets.iter().zip(input_ids) {
+            let len = input_ids.as_tokens().map_or(0, Vec::len);
+            let last = match offset {
+                Feature::Int(offset) => TokenId::try_from(*offset)
+                    .ok()
+                    .map(|offset| (offset, len.saturating_sub(1))),
+                _ => None,
+            };
+            let Some((offset, last)) = last else {
+                return Err(PyValueError::new_err(format!(
+                    "Expected key 'position_offset' to be a non-negative integer that fits a \
+                     token id, got {offset:?}"
+                )));
+            };
+            let fits = TokenId::try_from(last).is_ok_and(|last| offset.checked_add(last).is_some());
+            if !fits {
+                return Err(PyValueError::new_err(format!(
+                    "position_offset {offset} with {len} tokens goes past the largest token id \
+                     {}",
+                    TokenId::MAX
+                )));
+            }
+        }
+    }
+    if let Some(position_ids) = dataset.get("position_ids") {
+        for (given, input_ids) in position_ids.iter().zip(input_ids) {
+            let len = input_ids.as_tokens().map_or(0, Vec::len);
+            if given.as_tokens().is_none_or(|given| given.len() != len) {
+                return Err(PyValueError::new_err(format!(
+                    "Expected key 'position_ids' to be a list of {len} ids, got {given:?}"
+                )));
+            }
+        }
+    }
+    if let Some(weights) = dataset.get("loss_weight") {
+        for (weights, input_ids) in weights.iter().zip(input_ids) {
+            let len = input_ids.as_tokens().map_or(0, Vec::len);
+            let weights_len = match weights {
+                Feature::Floats(weights) => Some(weights.len()),
+                // whole numbers (and empty lists) are extracted as tokens
+                Feature::Tokens(weights) => Some(weights.len()),
+                _ => None,
+            };
+            if weights_len != Some(len) {
+                return Err(PyValueError::new_err(format!(
+                    "Expected key 'loss_weight' to be a list of {len} weights, got {weights:?}"
+                )));
+            }
+        }
+    }
+    if let Some(sources) = dataset.get("source") {
+        if let Some(other) = sources
+            .iter()
+            .find(|value| !matches!(value, Feature::Int(_)))
+        {
+            return Err(PyValueError::new_err(format!(
+                "Expected key 'source' to be an integer, got {other:?}"
+            )));
+        }
+    }
+    Ok(())
 }
 
 fn create_hist(
-    dataset: HashMap<String, Vec<Sequence>>,
+    dataset: HashMap<String, Vec<Feature>>,
     truncate_seq_len: usize,
-) -> (Histogram, Vec<usize>) {
-    let mut sequences: HashMap<usize, Vec<HashMap<String, Sequence>>> = HashMap::new();
-    let mut counts = vec![0u32; truncate_seq_len + 1];
-    let mut seq_lens: Vec<usize> = Vec::new();
+    pack_options: &PackOptions,
+) -> PyResult<(Histogram, Vec<usize>)> {
+    // Count the lengths straight from the input_ids column first, so every bucket is
+    // allocated once with its final size
+    let input_ids = dataset
+        .get("input_ids")
+        .expect("Expected key 'input_ids' in the dataset");
+    let num_rows = input_ids.len();
+    check_columns(&dataset, pack_options)?;
+    // with length_key, the packing uses the lengths of that column and the full input_ids
+    // are still concatenated
+    let length_column = match &pack_options.length_key {
+        Some(key) => match dataset.get(key) {
+            Some(column) if column.len() == num_rows => Some((key, column)),
+            Some(_) => {
+                return Err(PyValueError::new_err(format!(
+                    "Column '{key}' does not have the same length as 'input_ids'."
+                )))
+            }
+            None => {
+                return Err(PyValueError::new_err(format!(
+                    "Expected key '{key}' in the dataset"
+                )))
+            }
+        },
+        None => None,
+    };
+    // with length_field, the lengths of that token column instead of input_ids
+    let length_field = pack_options.length_field.as_deref().unwrap_or("input_ids");
+    let token_column = match dataset.get(length_field) {
+        Some(column) if column.len() == num_rows => column,
+        Some(_) => {
+            return Err(PyValueError::new_err(format!(
+                "Column '{length_field}' does not have the same length as 'input_ids'."
+            )))
+        }
+        None => {
+            return Err(PyValueError::new_err(format!(
+                "Expected key '{length_field}' in the dataset"
+            )))
+        }
+    };
+    let lengths = (0..num_rows)
+        .map(|row| {
+            let seq_len = match length_column {
+                Some((key, column)) => match column[row] {
+                    Feature::Int(seq_len) if seq_len >= 0 => seq_len as usize,
+                    ref other => {
+                        return Err(PyValueError::new_err(format!(
+                            "Expected key '{key}' to be a length, got {other:?}"
+                        )))
+                    }
+                },
+                None => match token_column[row].as_tokens() {
+                    Some(tokens) => tokens.len(),
+                    None => {
+                        return Err(PyValueError::new_err(format!(
+                            "Expected key '{length_field}' to be a list of token ids"
+                        )))
+                    }
+                },
+            };
+            // Should we check if the inputs were truncated?
+            if seq_len > truncate_seq_len {
+                let prefix_len = pack_options.shared_prefix.len();
+                let reserve_slots = pack_options.reserve_slots;
+                return Err(PyValueError::new_err(format!(
+                    "Sequence length {seq_len} exceeds the {truncate_seq_len} tokens left of \
+                     target_pack_size after the shared_prefix ({prefix_len} tokens) and \
+                     reserve_slots ({reserve_slots})"
+                )));
+            }
+            if seq_len == 0 && pack_options.zero_length == ZeroLengthPolicy::Error {
+                return Err(PyValueError::new_err(
+                    "Found a sequence without any tokens in input_ids.",
+                ));
+            }
+            Ok(seq_len)
+        })
+        .collect::<PyResult<Vec<usize>>>()?;
+    let drop_empty = pack_options.zero_length == ZeroLengthPolicy::Drop;
+    let mut seq_lens = match &pack_options.histogram {
+        Some(histogram) => {
+            let mut seq_lens = histogram.clone();
+            seq_lens.resize(truncate_seq_len + 1, 0);
+            seq_lens
+        }
+        None => {
+            let mut seq_lens = vec![0usize; truncate_seq_len + 1];
+            for &seq_len in &lengths {
+                seq_lens[seq_len] += 1;
+            }
+            seq_lens
+        }
+    };
+    if drop_empty {
+        seq_lens[0] = 0;
+    }
+    let mut sequences: Histogram = seq_lens
+        .iter()
+        .enumerate()
+        .filter(|(_, &count)| count > 0)
+        .map(|(seq_len, &count)| (seq_len, Vec::with_capacity(count)))
+        .collect();
 
-    // format the input data into a list of dicts
-    let dataset = dataset
+    // format the input data into a list of examples, every column is kept
+    // so that metadata (e.g. a sort key) is available when filling
+    let mut columns = dataset
         .into_iter()
-        .flat_map(|(key, value)| {
-            value.into_iter().map(move |v| {
-                let mut entry = HashMap::new();
-                entry.insert(key.clone(), v);
-                entry
-            })
+        .map(|(key, values)| {
+            if values.len() != num_rows {
+                panic!("Column '{key}' does not have the same length as 'input_ids'.");
+            }
+            (key, values.into_iter())
         })
         .collect::<Vec<_>>();
+    let num_columns = columns.len();
+    let dataset = lengths
+        .into_iter()
+        .map(|seq_len| {
+            let mut entry = Example::with_capacity(num_columns);
+            for (key, values) in columns.iter_mut() {
+                entry.insert(key.clone(), values.next().unwrap());
+            }
+            (seq_len, entry)
+        })
+        .filter(|(seq_len, _)| !(drop_empty && *seq_len == 0));
 
-    dataset.into_iter().for_each(|entry| {
-        // Only need input_ids key
-        let seq = entry
-            .get("input_ids")
-            .expect("Expected key 'input_ids' in the dataset entry");
-        let seq_len = seq.len();
-        // Should we check if the inputs were truncated?
-        if seq_len > truncate_seq_len {
-            panic!("Sequence length exceeds the maximum allowed length.");
-        }
-        sequences.entry(seq_len).or_default().push(entry);
-        counts[seq_len] += 1;
-    });
-
-    for seq_len in 0..(truncate_seq_len + 1) {
-        let seq_len = sequences.get(&seq_len).map_or(0, |v| v.len());
-        seq_lens.push(seq_len);
+    // With assume_sorted, the examples are expected to be grouped by length. Each run of
+    // the same length is moved into the histogram at once instead of looking up the bucket
+    // for every example. Unsorted input gives the same histogram, only slower
+    let mismatch =
+        || PyValueError::new_err("The histogram does not match the lengths of input_ids");
+    let mut run: Vec<Example> = Vec::new();
+    let mut run_len = 0;
+    for (seq_len, entry) in dataset {
+        if !pack_options.assume_sorted {
+            sequences
+                .get_mut(&seq_len)
+                .ok_or_else(mismatch)?
+                .push(entry);
+            continue;
+        }
+        if seq_len != run_len && !run.is_empty() {
+            flush_run(&mut sequences, run_len, &mut run);
+        }
+        run_len = seq_len;
+        run.push(entry);
+    }
+    if !run.is_empty() {
+        flush_run(&mut sequences, run_len, &mut run);
+    }
+    if pack_options.histogram.is_some()
+        && sequences
+            .iter()
+            .any(|(seq_len, bucket)| bucket.len() != seq_lens[*seq_len])
+    {
+        return Err(mismatch());
     }
 
-    (sequences, seq_lens)
+    Ok((sequences, seq_lens))
 }
 
-fn create_packing_strategy(
+// Tries the algorithms from cheapest to tightest and keeps the first one that reaches
+// the target efficiency, or the most efficient one if none do
+fn escalate_packing(
     histogram: Vec<usize>,
     pack_size: usize,
+    target_efficiency: f64,
+    seed: Option<u64>,
+) -> (Vec<Vec<usize>>, &'static str) {
+    let algorithms = [
+        ("first_fit", packing::PackingAlgo::FirstFit),
+        (
+            "first_fit_decreasing",
+            packing::PackingAlgo::FirstFitDecreasing,
+        ),
+        (
+            "best_fit_decreasing",
+            packing::PackingAlgo::BestFitDecreasing,
+        ),
+    ];
+    let mut best: Option<(Vec<Vec<usize>>, &'static str, f64)> = None;
+    for (name, algorithm) in algorithms {
+        let assignments = algorithm.pack(expand_histogram(&histogram), pack_size, seed);
+        let efficiency = packing::efficiency(&assignments, pack_size);
+        if efficiency >= target_efficiency {
+            return (assignments, name);
+        }
+        if best.as_ref().is_none_or(|(_, _, best)| efficiency > *best) {
+            best = Some((assignments, name, efficiency));
+        }
+    }
+    let (assignments, name, _) = best.unwrap();
+    (assignments, name)
+}
+
+// With solo_threshold, the sequences of at least that fraction of the pack size barely
+// pack with anything, so each goes last in a bin of its own and only the rest are packed
+fn create_packing_strategy(
+    mut histogram: Vec<usize>,
+    pack_size: usize,
     packing_algorithm: packing::PackingAlgo,
-) -> Vec<Vec<usize>> {
+    seed: Option<u64>,
+    solo_threshold: Option<f64>,
+) -> PyResult<Vec<Vec<usize>>> {
+    let mut solo = Vec::new();
+    if let Some(threshold) = solo_threshold {
+        let min_len = (threshold * pack_size as f64).ceil() as usize;
+        for (seq_len, count) in histogram.iter_mut().enumerate().skip(min_len) {
+            solo.extend(std::iter::repeat_n(vec![seq_len], *count));
+            *count = 0;
+        }
+    }
+    let mut assignments = if packing_algorithm == packing::PackingAlgo::Custom {
+        custom::pack(&histogram, pack_size)?
+    } else {
+        let all_seq_lens = expand_histogram(&histogram);
+        packing_algorithm.pack(all_seq_lens, pack_size, seed)
+    };
+    packing_algorithm
+        .check_fit(&assignments, pack_size)
+        .map_err(PyValueError::new_err)?;
+    assignments.extend(solo);
+
+    Ok(assignments)
+}
+
+fn expand_histogram(histogram: &[usize]) -> Vec<usize> {
     // this replicates the behavior of the original code
     // all_seq_lens = []
     // for i, count in enumerate(histogram):
     // all_seq_lens.extend([i] * count)
-    let all_seq_lens: Vec<usize> = histogram
+    histogram
         .iter()
         .enumerate()
-        .flat_map(|(i, &count)| std::iter::repeat(i).take(count))
-        .collect();
-
-    let assignments: Vec<Vec<usize>> = packing_algorithm.pack(all_seq_lens, pack_size);
-
-    assignments
+        .flat_map(|(i, &count)| std::iter::repeat_n(i, count))
+        .collect()
 }
 
 /// A Python module implemented in Rust.
 #[pymodule]
 fn binpack_rs(m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(fast_pack, m)?)?;
+    m.add_function(wrap_pyfunction!(plan_packing, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_histogram, m)?)?;
+    m.add_function(wrap_pyfunction!(pack_from_histogram, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_packings, m)?)?;
+    m.add_function(wrap_pyfunction!(repack_tail, m)?)?;
+    m.add_function(wrap_pyfunction!(bin_segments, m)?)?;
+    m.add_function(wrap_pyfunction!(shard_packing, m)?)?;
+    m.add_function(wrap_pyfunction!(stratified_chunks, m)?)?;
+    m.add_function(wrap_pyfunction!(debug_populate, m)?)?;
+    m.add_function(wrap_pyfunction!(estimate_memory, m)?)?;
+    m.add_function(wrap_pyfunction!(concat_split::concat_split, m)?)?;
+    m.add_function(wrap_pyfunction!(chunk::chunk_long_sequences, m)?)?;
+    m.add_function(wrap_pyfunction!(custom::register_packing_algorithm, m)?)?;
+    #[cfg(feature = "mmap")]
+    m.add_function(wrap_pyfunction!(mmap::verify_output, m)?)?;
+    m.add_class::<PyReturnIter>()?;
+    m.add_class::<histogram::OnlineHistogram>()?;
+    m.add_class::<config::PackConfig>()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::ffi::c_str;
+
+    fn examples_from_lengths(lengths: &[usize]) -> HashMap<String, Vec<Feature>> {
+        let input_ids = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| Feature::Tokens(vec![i as TokenId + 1; len]))
+            .collect();
+        HashMap::from([("input_ids".to_string(), input_ids)])
+    }
+
+    fn stable_pack(examples: HashMap<String, Vec<Feature>>, return_format: &str) -> ReturnFormat {
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        stable_pack_with(examples, return_format, pack_options)
+    }
+
+    fn stable_pack_with(
+        examples: HashMap<String, Vec<Feature>>,
+        return_format: &str,
+        pack_options: PackOptions,
+    ) -> ReturnFormat {
+        stable_pack_with_stats(examples, return_format, pack_options).0
+    }
+
+    fn stable_pack_with_stats(
+        examples: HashMap<String, Vec<Feature>>,
+        return_format: &str,
+        pack_options: PackOptions,
+    ) -> (ReturnFormat, PackStats) {
+        let algorithm = packing::PackingAlgo::Stable;
+        let (return_format, options) = match return_format {
+            "nemo" => (
+                ReturnFormat::Nemo(Columns::new()),
+                Some(NemoOptions::builder().build().unwrap()),
+            ),
+            _ => (ReturnFormat::Composer(Columns::new()), None),
+        };
+        let (result, stats) = pack(
+            examples,
+            8,
+            algorithm,
+            Some(0),
+            return_format,
+            &pack_options,
+            options,
+        );
+        (result.unwrap(), stats)
+    }
+
+    #[test]
+    fn test_stable_is_deterministic() {
+        let lengths = [3, 5, 3, 2, 3, 8, 1, 5, 3, 2];
+        for return_format in ["composer", "nemo"] {
+            let first = stable_pack(examples_from_lengths(&lengths), return_format);
+            for _ in 0..5 {
+                assert_eq!(
+                    stable_pack(examples_from_lengths(&lengths), return_format),
+                    first
+                );
+            }
+        }
+        // sequences of the same length are used in input order
+        let result = stable_pack(examples_from_lengths(&lengths), "composer");
+        assert_eq!(
+            result.sequences("tokens")[0],
+            vec![7, 4, 4, 10, 10, 1, 1, 1]
+        );
+    }
+
+    #[test]
+    fn test_first_fit_decreasing_ties() {
+        pyo3::prepare_freethreaded_python();
+        // many sequences of the same length, told apart by their token ids
+        let lengths = (0..200).map(|i| i % 4 + 2).collect::<Vec<usize>>();
+        let pack = |algorithm: &str| {
+            Python::with_gil(|py| {
+                let examples = examples_from_lengths(&lengths);
+                let format = "composer".to_string();
+                let result = fast_pack(py, examples, 8, algorithm.into(), format, Some(0), None);
+                let Ok(PackOutput::Result(result)) = result else {
+                    panic!("Expected the result without stats");
+                };
+                let result: HashMap<String, Vec<Vec<TokenId>>> = result.extract(py).unwrap();
+                result["tokens"].clone()
+            })
+        };
+        // without a seed, equal lengths are used in input order on every run
+        let first = pack("first_fit_decreasing");
+        for _ in 0..5 {
+            assert_eq!(pack("first_fit_decreasing"), first);
+        }
+        assert_eq!(first, pack("stable_decreasing"));
+        assert!(packing::PackingAlgo::FirstFitDecreasing.shuffles_buckets(Some(3)));
+    }
+
+    #[test]
+    fn test_output_order() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+examples = {
+    "input_ids": [[i] * (i % 7 + 1) for i in range(40)],
+    "labels": [[i] * (i % 7 + 1) for i in range(40)],
+    "source": [i % 3 for i in range(40)],
+}
+for return_format in ["composer", "nemo"]:
+    options = dict(return_doc_lengths=True, return_timing=True)
+    runs = [fast_pack(examples, 8, "stable", return_format, 0, **options) for _ in range(5)]
+    keys = list(runs[0][0])
+    # the outputs are ordered by name, the same on every run
+    assert keys == sorted(keys), keys
+    for result, stats in runs:
+        assert list(result) == keys and result == runs[0][0], return_format
+        assert list(stats["timing"]) == sorted(stats["timing"])
+    rows, _ = fast_pack(examples, 8, "stable", return_format, 0, row_major=True, **options)
+    assert all(list(row) == keys for row in rows)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_concurrent_fast_pack() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            // fast_pack releases the GIL, so the calls can overlap with each other
+            py.run(
+                c_str!(
+                    r#"
+from concurrent.futures import ThreadPoolExecutor
+
+examples = {"input_ids": [[1] * (i % 30 + 1) for i in range(2000)]}
+with ThreadPoolExecutor(4) as pool:
+    results = list(pool.map(lambda _: fast_pack(examples, 64, "first_fit", "composer", 0), range(8)))
+num_rows = sorted({len(result["tokens"]) for result in results})
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let num_rows: Vec<usize> = globals
+                .get_item("num_rows")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(num_rows.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_sort_bins_by() {
+        let lengths = [6, 2, 7, 3, 1, 4, 5];
+        for (order, expected) in [
+            (packing::BinOrder::FillAsc, vec![4, 5, 6, 6, 7]),
+            (packing::BinOrder::FillDesc, vec![7, 6, 6, 5, 4]),
+        ] {
+            let pack_options = PackOptions::builder()
+                .shuffle(false)
+                .sort_bins_by(order)
+                .build()
+                .unwrap();
+            let result = stable_pack_with(examples_from_lengths(&lengths), "nemo", pack_options);
+            let fills = result
+                .sequences("input_ids")
+                .iter()
+                .map(|row| row.len())
+                .collect::<Vec<_>>();
+            assert_eq!(fills, expected);
+        }
+    }
+
+    #[test]
+    fn test_unmergeable_bins_sorted() {
+        let lengths = [6, 2, 7, 3, 1, 4, 7];
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .min_seqs_per_bin(Some(2))
+            .sort_bins_by(packing::BinOrder::FillDesc)
+            .build()
+            .unwrap();
+        let (result, stats) =
+            stable_pack_with_stats(examples_from_lengths(&lengths), "composer", pack_options);
+        // the indices are those of the sorted rows, the 3 sequence row is the only one
+        // that could be filled up
+        assert_eq!(stats.bin_seq_counts, vec![1, 1, 3, 1, 1]);
+        assert_eq!(stats.unmergeable_bins, vec![0, 1, 3, 4]);
+        let padding = |i: usize| {
+            result.sequences("tokens")[i]
+                .iter()
+                .filter(|&&t| t == 0)
+                .count()
+        };
+        let padding = stats
+            .unmergeable_bins
+            .iter()
+            .map(|&i| padding(i))
+            .collect::<Vec<_>>();
+        assert_eq!(padding, vec![1, 1, 2, 4]);
+    }
+
+    #[test]
+    fn test_plan_packing() {
+        let seq_lens = [5, 1, 4, 3, 2];
+        let result = plan(&seq_lens, 5, packing::PackingAlgo::FirstFitDecreasing, None).unwrap();
+        // only lengths come back, the same as packing the lengths directly
+        assert_eq!(result.assignments, vec![vec![5], vec![4, 1], vec![3, 2]]);
+        assert_eq!(result.bin_fill, vec![5, 5, 5]);
+        assert_eq!(result.bin_seq_counts, vec![1, 2, 2]);
+        assert_eq!(result.bin_padding, vec![0, 0, 0]);
+        assert_eq!(result.efficiency, 1.0);
+
+        assert!(plan(&[6], 5, packing::PackingAlgo::FirstFit, None).is_err());
+    }
+
+    #[cfg(feature = "u64-ids")]
+    #[test]
+    fn test_u64_token_ids() {
+        let big = u32::MAX as TokenId + 10;
+        let examples = HashMap::from([(
+            "input_ids".to_string(),
+            vec![
+                Feature::Tokens(vec![big, big + 1]),
+                Feature::Tokens(vec![big + 2]),
+            ],
+        )]);
+        for return_format in ["composer", "nemo"] {
+            let result = stable_pack(examples.clone(), return_format);
+            let mut tokens = match return_format {
+                "composer" => result.sequences("tokens").concat(),
+                _ => result.sequences("input_ids").concat(),
+            };
+            tokens.retain(|&id| id != 0);
+            assert_eq!(tokens, vec![big + 2, big, big + 1]);
+        }
+    }
+
+    #[test]
+    fn test_bin_seq_counts() {
+        let lengths = [6, 2, 7, 3, 1, 4, 5];
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let (result, stats) =
+            stable_pack_with_stats(examples_from_lengths(&lengths), "composer", pack_options);
+        // bins are [1, 2, 3], [4], [5], [6], [7]
+        assert_eq!(stats.bin_seq_counts, vec![3, 1, 1, 1, 1]);
+        assert_eq!(stats.bin_seq_counts.len(), result.sequences("tokens").len());
+    }
+
+    #[test]
+    fn test_return_timing() {
+        let lengths = [6, 2, 7, 3];
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let (_, stats) =
+            stable_pack_with_stats(examples_from_lengths(&lengths), "composer", pack_options);
+        assert!(stats.timing.is_none());
+
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .return_timing(true)
+            .build()
+            .unwrap();
+        let (_, stats) =
+            stable_pack_with_stats(examples_from_lengths(&lengths), "composer", pack_options);
+        let timing = stats.timing.unwrap();
+        let mut keys = timing.keys().map(String::as_str).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["fill", "histogram", "packing"]);
+        assert!(timing.values().all(|ms| *ms >= 0.0));
+    }
+
+    #[test]
+    fn test_assume_sorted_histogram() {
+        let lengths = [1, 1, 2, 3, 3, 3, 5, 8];
+        let general = create_hist(
+            examples_from_lengths(&lengths),
+            8,
+            &PackOptions::builder().build().unwrap(),
+        )
+        .unwrap();
+        let sorted = create_hist(
+            examples_from_lengths(&lengths),
+            8,
+            &PackOptions::builder().assume_sorted(true).build().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sorted, general);
+        assert_eq!(sorted.1, vec![0, 2, 1, 3, 0, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_fill_reuses_histogram() {
+        let lengths = [6, 2, 7, 3, 1, 4, 5];
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let (sequences, seq_lens) =
+            create_hist(examples_from_lengths(&lengths), 8, &pack_options).unwrap();
+        let mut tokens = Vec::new();
+        for algorithm in [
+            packing::PackingAlgo::Stable,
+            packing::PackingAlgo::FirstFitDecreasing,
+        ] {
+            let assignments =
+                create_packing_strategy(seq_lens.clone(), 8, algorithm, None, None).unwrap();
+            let result = fill_packing_strategy(
+                assignments,
+                &sequences,
+                8,
+                None,
+                ReturnFormat::Composer(Columns::new()),
+                &pack_options,
+                None,
+                &mut PackStats::default(),
+            )
+            .unwrap();
+            let mut packed = result.sequences("tokens").concat();
+            packed.sort();
+            tokens.push(packed);
+        }
+        // both packings see every example of the histogram
+        assert_eq!(tokens[0], tokens[1]);
+        assert_eq!(tokens[0].len(), lengths.iter().sum::<usize>());
+    }
+
+    #[test]
+    fn test_row_major() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+examples = {"input_ids": [[i] * (i % 7 + 1) for i in range(30)]}
+for return_format in ["composer", "nemo", "iterator"]:
+    options = dict(seed=3, return_doc_lengths=True)
+    columns = fast_pack(examples, 8, "first_fit", return_format, 0, **options)
+    rows = fast_pack(examples, 8, "first_fit", return_format, 0, row_major=True, **options)
+    if return_format == "iterator":
+        # already a dict per row, only not a list
+        assert rows == list(columns)
+        continue
+    transposed = [dict(zip(columns, row)) for row in zip(*columns.values())]
+    assert isinstance(rows, list) and rows == transposed, return_format
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let csr = c_str!("fast_pack({'input_ids': [[1]]}, 8, 'stable', 'csr', row_major=True)");
+            assert!(py.eval(csr, Some(&globals), None).is_err());
+        });
+    }
+
+    #[test]
+    fn test_debug_populate() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item(
+                    "debug_populate",
+                    wrap_pyfunction!(debug_populate, py).unwrap(),
+                )
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+from collections import Counter
+examples = {"input_ids": [[i] * (i % 5) for i in range(40)]}
+pools = debug_populate(examples, 8, seed=1)
+# the empty rows are dropped, every other row is in the pool of its length
+counts = Counter(len(ids) for ids in examples["input_ids"] if ids)
+assert {seq_len: len(rows) for seq_len, rows in pools.items()} == counts
+for seq_len, rows in pools.items():
+    assert all(len(examples["input_ids"][row]) == seq_len for row in rows)
+    assert len(set(rows)) == len(rows)
+assert debug_populate(examples, 8, seed=1) == pools
+assert any(rows != sorted(rows) for rows in pools.values())
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_bin_callback() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+examples = {"input_ids": [[1] * (i % 7 + 1) for i in range(50)]}
+rows = []
+result, stats = fast_pack(
+    examples, 8, "first_fit", "nemo", 0, bin_callback=rows.append, return_stats=True
+)
+num_bins = len(stats["bin_seq_counts"])
+keys = sorted(rows[0])
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let get = |key: &str| globals.get_item(key).unwrap().unwrap();
+            // every bin went to the callback, nothing is collected
+            assert!(get("result").is_none());
+            let num_rows = get("rows").len().unwrap();
+            assert_eq!(num_rows, get("num_bins").extract::<usize>().unwrap());
+            let keys: Vec<String> = get("keys").extract().unwrap();
+            assert_eq!(keys, vec!["input_ids", "loss_mask", "seq_start_id"]);
+        });
+    }
+
+    #[test]
+    fn test_max_seq_len() {
+        let lengths = [3, 6, 2, 5];
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let (_, stats) =
+            stable_pack_with_stats(examples_from_lengths(&lengths), "composer", pack_options);
+        assert_eq!(stats.max_seq_len, 6);
+    }
+
+    #[test]
+    fn test_histogram_counts() {
+        let lengths = [4, 0, 2, 4, 8, 2, 4];
+        let examples = examples_from_lengths(&lengths);
+        let (sequences, seq_lens) =
+            create_hist(examples, 8, &PackOptions::builder().build().unwrap()).unwrap();
+        // the empty sequence is dropped by default
+        assert_eq!(seq_lens, vec![0, 0, 2, 0, 3, 0, 0, 0, 1]);
+        for (seq_len, bucket) in &sequences {
+            assert_eq!(bucket.len(), seq_lens[*seq_len]);
+            assert!(bucket
+                .iter()
+                .all(|entry| entry["input_ids"].as_tokens().unwrap().len() == *seq_len));
+        }
+        // input order is kept within a bucket
+        let fours = sequences[&4]
+            .iter()
+            .map(|entry| entry["input_ids"].as_tokens().unwrap()[0])
+            .collect::<Vec<_>>();
+        assert_eq!(fours, vec![1, 4, 7]);
+    }
+
+    // cargo test --release -- --ignored bench_create_hist --nocapture
+    #[test]
+    #[ignore]
+    fn bench_create_hist() {
+        let lengths = (0..200_000).map(|i| i % 512 + 1).collect::<Vec<_>>();
+        let pack_options = PackOptions::builder().build().unwrap();
+        let start = Instant::now();
+        let (_, seq_lens) =
+            create_hist(examples_from_lengths(&lengths), 512, &pack_options).unwrap();
+        println!(
+            "create_hist: {:?} for {} rows",
+            start.elapsed(),
+            lengths.len()
+        );
+        assert_eq!(seq_lens.iter().sum::<usize>(), lengths.len());
+    }
+
+    #[test]
+    fn test_shared_prefix() {
+        let lengths = [6, 2];
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .shared_prefix(vec![99, 98])
+            .build()
+            .unwrap();
+        let result = stable_pack_with(
+            examples_from_lengths(&lengths),
+            "composer",
+            pack_options.clone(),
+        );
+        // only 6 of the 8 tokens are left for the sequences, so 6 and 2 no longer share a row
+        for row in result.sequences("tokens") {
+            assert_eq!(row[..2], [99, 98]);
+            assert_eq!(row.iter().filter(|&&id| id >= 98).count(), 2);
+            assert_eq!(row.len(), 8);
+        }
+        let fills = result
+            .sequences("tokens")
+            .iter()
+            .map(|row| row.iter().filter(|&&id| id != 0).count() - 2)
+            .collect::<Vec<_>>();
+        assert_eq!(fills, vec![2, 6]);
+        assert_eq!(result.sequences("positions_ids")[0][..3], [0, 1, 0]);
+
+        let result = stable_pack_with(examples_from_lengths(&lengths), "nemo", pack_options);
+        assert_eq!(result.sequences("loss_mask")[0][..3], [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_separator_capacity() {
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .separator_id(Some(9))
+            .build()
+            .unwrap();
+        // 3 + 1 + 4 fits in 8, 4 + 1 + 4 does not
+        let result = stable_pack_with(
+            examples_from_lengths(&[4, 3]),
+            "composer",
+            pack_options.clone(),
+        );
+        assert_eq!(
+            result.sequences("tokens"),
+            &vec![vec![2, 2, 2, 9, 1, 1, 1, 1]]
+        );
+        let result = stable_pack_with(examples_from_lengths(&[4, 4]), "composer", pack_options);
+        assert_eq!(result.sequences("tokens").len(), 2);
+    }
+
+    #[test]
+    fn test_pack_from_histogram() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            for function in [
+                wrap_pyfunction!(fast_pack, py).unwrap(),
+                wrap_pyfunction!(pack_from_histogram, py).unwrap(),
+                wrap_pyfunction!(compute_histogram, py).unwrap(),
+            ] {
+                globals
+                    .set_item(function.getattr("__name__").unwrap(), function)
+                    .unwrap();
+            }
+            py.run(
+                c_str!(
+                    r#"
+examples = {"input_ids": [[i] * (i % 7 + 1) for i in range(40)]}
+histogram = compute_histogram([len(ids) for ids in examples["input_ids"]], 8)
+expected = fast_pack(examples, 8, "stable", "composer", 0)
+result = pack_from_histogram(examples, histogram, 8, "stable", "composer", 0)
+same = result == expected
+histogram[3] += 1
+try:
+    pack_from_histogram(examples, histogram, 8, "stable", "composer", 0)
+    mismatch_raises = False
+except ValueError:
+    mismatch_raises = True
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let get = |key: &str| globals.get_item(key).unwrap().unwrap();
+            let histogram: Vec<usize> = get("histogram").extract().unwrap();
+            // the histogram was changed by one after packing
+            assert_eq!(histogram, vec![0, 6, 6, 7, 6, 6, 5, 5, 0]);
+            assert!(get("same").extract::<bool>().unwrap());
+            assert!(get("mismatch_raises").extract::<bool>().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_zero_length_policy() {
+        let lengths = [3, 0, 2, 0];
+        let hist = |policy| {
+            let pack_options = PackOptions::builder().zero_length(policy).build().unwrap();
+            create_hist(examples_from_lengths(&lengths), 8, &pack_options)
+        };
+        let hist = |policy| hist(policy).unwrap();
+        let (sequences, seq_lens) = hist(ZeroLengthPolicy::Drop);
+        assert_eq!(seq_lens[..4], [0, 0, 1, 1]);
+        assert!(!sequences.contains_key(&0));
+
+        let (sequences, seq_lens) = hist(ZeroLengthPolicy::Keep);
+        assert_eq!(seq_lens[..4], [2, 0, 1, 1]);
+        assert_eq!(sequences[&0].len(), 2);
+        // the kept sequences still fill, nemo masks them without a first token
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .zero_length(ZeroLengthPolicy::Keep)
+            .build()
+            .unwrap();
+        let result = stable_pack_with(examples_from_lengths(&lengths), "nemo", pack_options);
+        assert_eq!(result.sequences("input_ids").concat().len(), 5);
+
+        // a ValueError, not a panic that `except Exception` would miss
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+try:
+    fast_pack({"input_ids": [[1], []]}, 8, "first_fit", "composer", 0, zero_length="error")
+    raise AssertionError("Expected the empty sequence to be rejected")
+except ValueError as err:
+    assert "without any tokens" in str(err)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_reserve_slots() {
+        let lengths = [5, 3, 4, 2, 6, 1, 2];
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .reserve_slots(2)
+            .build()
+            .unwrap();
+        let result = stable_pack_with(examples_from_lengths(&lengths), "composer", pack_options);
+        for row in result.sequences("tokens") {
+            // padded to the full pack size, but at most 6 real tokens
+            assert_eq!(row.len(), 8);
+            assert!(row.iter().filter(|&&id| id != 0).count() <= 6);
+        }
+        let total = result
+            .sequences("tokens")
+            .concat()
+            .iter()
+            .filter(|&&id| id != 0)
+            .count();
+        assert_eq!(total, lengths.iter().sum::<usize>());
+
+        // 7 tokens fit the pack size, but not the room the reserved slots leave
+        pyo3::prepare_freethreaded_python();
+        let pack_options = PackOptions::builder().reserve_slots(2).build().unwrap();
+        let (result, _) = pack(
+            examples_from_lengths(&[7]),
+            8,
+            packing::PackingAlgo::Stable,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &pack_options,
+            None,
+        );
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("reserve_slots (2)"), "{err}");
+    }
+
+    #[test]
+    fn test_protected() {
+        let lengths = [5, 3, 2, 1];
+        let mut examples = examples_from_lengths(&lengths);
+        let protected = [1, 0, 0, 0].into_iter().map(Feature::Int).collect();
+        examples.insert("protected".to_string(), protected);
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let result = stable_pack_with(examples, "composer", pack_options);
+        let tokens = result.sequences("tokens");
+        // the 3, 2 and 1 tokens are packed together, the protected sequence is alone
+        assert_eq!(tokens.len(), 2);
+        let protected_row = tokens.iter().find(|row| row.contains(&1)).unwrap();
+        assert_eq!(protected_row, &vec![1, 1, 1, 1, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_full_length_sequence() {
+        // the sequences with ids 1 and 3 fill a bin alone, with no padding or truncation
+        let lengths = [8, 3, 8];
+        let algorithms = [
+            "first_fit",
+            "first_fit_shuffle",
+            "first_fit_decreasing",
+            "stable",
+            "min_shapes",
+            "strict_first_fit",
+            "next_fit_decreasing",
+            "balanced:3",
+        ];
+        for algorithm in algorithms {
+            for return_format in ["composer", "iterator", "nemo"] {
+                let pack_options = PackOptions::builder().build().unwrap();
+                let options =
+                    (return_format == "nemo").then(|| NemoOptions::builder().build().unwrap());
+                let (result, _) = pack(
+                    examples_from_lengths(&lengths),
+                    8,
+                    algorithm.parse().unwrap(),
+                    Some(0),
+                    return_format.parse().unwrap(),
+                    &pack_options,
+                    options,
+                );
+                let key = if return_format == "nemo" {
+                    "input_ids"
+                } else {
+                    "tokens"
+                };
+                let rows = result.unwrap().sequences(key).clone();
+                assert_eq!(rows.len(), 3, "{algorithm} {return_format}");
+                for id in [1, 3] {
+                    let row = rows.iter().find(|row| row.contains(&id)).unwrap();
+                    assert_eq!(row, &vec![id; 8], "{algorithm} {return_format}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pairs_stay_adjacent() {
+        let lengths = [3, 2, 4, 1, 5, 6, 2];
+        let mut examples = examples_from_lengths(&lengths);
+        let pair_ids = [0, 1, 0, 2, 1, 3, 3]
+            .into_iter()
+            .map(Feature::Int)
+            .collect();
+        examples.insert("pair_ids".to_string(), pair_ids);
+        let pack_options = PackOptions::builder().build().unwrap();
+        let (result, _) = pack(
+            examples,
+            8,
+            packing::PackingAlgo::FirstFitShuffle,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &pack_options,
+            None,
+        );
+        let result = result.unwrap();
+        let rows = result.sequences("tokens");
+        // the ids of the rows are 1 + their index in the dataset
+        for (first, second) in [(1, 3), (2, 5), (6, 7)] {
+            let index = rows.iter().position(|row| row.contains(&first)).unwrap();
+            let row = &rows[index];
+            let start = row.iter().position(|&id| id == first).unwrap();
+            let end = start + lengths[first as usize - 1];
+            assert!(row[end..].starts_with(&vec![second; lengths[second as usize - 1]]));
+            // the response starts at position 0
+            assert_eq!(result.sequences("positions_ids")[index][end], 0);
+        }
+    }
+
+    #[test]
+    fn test_dedup() {
+        let examples = || {
+            let input_ids = [
+                vec![1, 2],
+                vec![3],
+                vec![1, 2],
+                vec![4, 5, 6],
+                vec![1, 2],
+                vec![3],
+            ];
+            let input_ids = input_ids.into_iter().map(Feature::Tokens).collect();
+            let source = (0..6).map(Feature::Int).collect();
+            HashMap::from([
+                ("input_ids".to_string(), input_ids),
+                ("source_row".to_string(), source),
+            ])
+        };
+        let pack_examples = |examples, dedup: bool| {
+            let pack_options = PackOptions::builder()
+                .shuffle(false)
+                .dedup(dedup)
+                .build()
+                .unwrap();
+            pack(
+                examples,
+                8,
+                packing::PackingAlgo::Stable,
+                None,
+                ReturnFormat::Iterator(Columns::new()),
+                &pack_options,
+                None,
+            )
+        };
+        let pack = |dedup: bool| {
+            let (result, stats) = pack_examples(examples(), dedup);
+            (result.unwrap().into_data(), stats)
+        };
+        let (result, stats) = pack(false);
+        assert_eq!(stats.num_duplicates, 0);
+        assert_eq!(result["tokens"].row_lens().iter().sum::<usize>(), 11);
+
+        let (result, stats) = pack(true);
+        // one copy of each distinct input_ids, the first one, with the number of copies
+        assert_eq!(stats.num_duplicates, 3);
+        assert_eq!(result["tokens"].row_lens().iter().sum::<usize>(), 6);
+        let Column::Int64(rows) = &result["source_row"] else {
+            panic!("Expected the source_row of every sequence");
+        };
+        let Column::Int64(multiplicity) = &result["multiplicity"] else {
+            panic!("Expected the multiplicity of every sequence");
+        };
+        let mut copies = rows
+            .concat()
+            .into_iter()
+            .zip(multiplicity.concat())
+            .collect::<Vec<_>>();
+        copies.sort();
+        assert_eq!(copies, vec![(0, 3), (1, 2), (3, 1)]);
+
+        let histogram = PackOptions::builder()
+            .dedup(true)
+            .histogram(Some(vec![0, 2]))
+            .build();
+        assert!(histogram.is_err());
+
+        // a multiplicity column of the dataset is not overwritten
+        let mut existing = examples();
+        existing.insert("multiplicity".to_string(), vec![Feature::Int(1); 6]);
+        assert!(pack_examples(existing, true).0.is_err());
+
+        // the other formats would lose the multiplicity
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+examples = {"input_ids": [[1, 2], [1, 2]]}
+assert len(list(fast_pack(examples, 8, "first_fit", "iterator", 0, dedup=True))) == 1
+for return_format in ["composer", "nemo", "csr"]:
+    try:
+        fast_pack(examples, 8, "first_fit", return_format, 0, dedup=True)
+        raise AssertionError(f"Expected dedup to be rejected for {return_format}")
+    except ValueError as err:
+        assert "dedup" in str(err)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_return_meta() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let kwargs = PyDict::new(py);
+            kwargs.set_item("return_meta", true).unwrap();
+            kwargs.set_item("seed", 3).unwrap();
+            kwargs.set_item("truncation_side", "left").unwrap();
+            let examples = examples_from_lengths(&[3, 5, 2]);
+            let result = fast_pack(
+                py,
+                examples,
+                8,
+                "First_Fit_Decreasing".into(),
+                "hf".into(),
+                Some(0),
+                Some(&kwargs),
+            );
+            let Ok(PackOutput::WithStats(_, stats)) = result else {
+                panic!("Expected the stats with return_meta");
+            };
+            let meta = PackMeta {
+                algorithm: "first_fit_decreasing".to_string(),
+                pack_size: 8,
+                seed: Some(3),
+                pad_id: Some(0),
+                truncation_side: "left".to_string(),
+                return_format: "composer".to_string(),
+            };
+            assert_eq!(stats.meta, Some(meta));
+
+            // with target_efficiency, the algorithm that was used
+            kwargs.set_item("target_efficiency", 0.5).unwrap();
+            let examples = examples_from_lengths(&[3, 5, 2]);
+            let result = fast_pack(
+                py,
+                examples,
+                8,
+                "none".into(),
+                "nemo".into(),
+                None,
+                Some(&kwargs),
+            );
+            let Ok(PackOutput::WithStats(_, stats)) = result else {
+                panic!("Expected the stats with return_meta");
+            };
+            assert_eq!(stats.meta.unwrap().algorithm, stats.algorithm.unwrap());
+        });
+    }
+
+    #[test]
+    fn test_return_schema() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            for (format, options) in [
+                (
+                    "composer",
+                    c_str!("dict(return_doc_lengths=True, position_id_dtype='int32')"),
+                ),
+                ("nemo", c_str!("dict(compact_mask=True)")),
+            ] {
+                let kwargs = py.eval(options, None, None).unwrap();
+                let kwargs = kwargs.downcast::<PyDict>().unwrap();
+                kwargs.set_item("return_schema", true).unwrap();
+                let examples = examples_from_lengths(&[3, 5, 2]);
+                let algorithm = "first_fit".to_string();
+                let result = fast_pack(
+                    py,
+                    examples,
+                    8,
+                    algorithm,
+                    format.into(),
+                    Some(0),
+                    Some(kwargs),
+                );
+                let Ok(PackOutput::WithStats(result, stats)) = result else {
+                    panic!("Expected the stats with return_schema");
+                };
+                let schema = stats.schema.unwrap();
+                let result = result.bind(py).downcast::<PyDict>().unwrap();
+                let mut keys = result.keys().extract::<Vec<String>>().unwrap();
+                keys.sort();
+                let mut schema_keys = schema.keys().cloned().collect::<Vec<_>>();
+                schema_keys.sort();
+                assert_eq!(keys, schema_keys);
+                // the rows of binary features are returned as bytes, every other as a list
+                for (key, feature) in &schema {
+                    let row = result.get_item(key).unwrap().unwrap().get_item(0).unwrap();
+                    let binary = *feature == stats::FeatureType::value("binary");
+                    assert_eq!(
+                        row.is_instance_of::<pyo3::types::PyBytes>(),
+                        binary,
+                        "{key}"
+                    );
+                }
+                if format == "composer" {
+                    let int32 = stats::FeatureType::sequence("int32");
+                    assert_eq!(schema["positions_ids"], int32);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_length_key() {
+        let examples = || {
+            let mut examples = examples_from_lengths(&[6, 6, 2]);
+            let lengths = [2, 2, 6].into_iter().map(Feature::Int).collect();
+            examples.insert("packed_len".to_string(), lengths);
+            examples
+        };
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .length_key(Some("packed_len".to_string()))
+            .build()
+            .unwrap();
+        let (result, stats) = stable_pack_with_stats(examples(), "nemo", pack_options);
+        // packed by packed_len, but every token of input_ids is in the rows
+        assert_eq!(stats.bin_seq_counts, vec![2, 1]);
+        let row_lens = result
+            .sequences("input_ids")
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>();
+        assert_eq!(row_lens, vec![12, 2]);
+
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let (result, _) = stable_pack_with_stats(examples(), "nemo", pack_options);
+        let row_lens = result
+            .sequences("input_ids")
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>();
+        assert_eq!(row_lens, vec![8, 6]);
+
+        // a bad or missing length is a ValueError, not a panic
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+for lengths in [[2, 2.5], [2, [2]], [2, -1], [2], None]:
+    examples = {"input_ids": [[1, 1], [2, 2]]}
+    if lengths is not None:
+        examples["packed_len"] = lengths
+    try:
+        fast_pack(examples, 8, "first_fit", "composer", 0, length_key="packed_len")
+        raise AssertionError(f"Expected {lengths} to be rejected")
+    except ValueError as err:
+        assert "packed_len" in str(err)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_invalid_columns() {
+        pyo3::prepare_freethreaded_python();
+        // the position ids of the 2 tokens would go past the largest token id. A u64 id
+        // always has room after an i64 offset
+        #[cfg(not(feature = "u64-ids"))]
+        {
+            let mut examples = examples_from_lengths(&[2]);
+            let offset = Feature::Int(TokenId::MAX as i64);
+            examples.insert("position_offset".to_string(), vec![offset]);
+            let pack_options = PackOptions::builder().build().unwrap();
+            let err = check_columns(&examples, &pack_options)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("goes past the largest token id"), "{err}");
+        }
+        // columns that are only read while filling are checked up front, a bad value is a
+        // ValueError naming the column instead of a panic in the fill
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+cases = [
+    ("source", [0, 1.5], {}),
+    ("position_offset", [0, -1], {}),
+    ("position_offset", [0, 2.0], {}),
+    ("position_ids", [[0, 1], [0]], {}),
+    ("position_ids", [[0, 1], [0.5, 1.5]], {}),
+    ("position_ids", [[0, 1], 3], {}),
+    ("difficulty", [1.5, [2]], {"sort_key": "difficulty"}),
+    ("loss_weight", [[1.0, 0.5], [1.0]], {}),
+    ("loss_weight", [[1.0, 0.5], 1.0], {}),
+]
+for key, values, kwargs in cases:
+    examples = {"input_ids": [[1, 1], [2, 2]], key: values}
+    try:
+        fast_pack(examples, 8, "first_fit", "composer", 0, **kwargs)
+        raise AssertionError(f"Expected {key}={values} to be rejected")
+    except ValueError as err:
+        assert key in str(err), str(err)
+# a missing sort_key column
+try:
+    fast_pack({"input_ids": [[1]]}, 8, "first_fit", "composer", 0, sort_key="difficulty")
+    raise AssertionError("Expected a missing sort_key to be rejected")
+except ValueError as err:
+    assert "difficulty" in str(err), str(err)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_length_field() {
+        // the labels are shorter than input_ids for the first two examples
+        let mut examples = examples_from_lengths(&[6, 6, 2]);
+        let labels = examples_from_lengths(&[2, 2, 6])
+            .remove("input_ids")
+            .unwrap();
+        examples.insert("labels".to_string(), labels);
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .length_field(Some("labels".to_string()))
+            .build()
+            .unwrap();
+        let (result, stats) = stable_pack_with_stats(examples, "nemo", pack_options);
+        // packed by the length of labels, every token of input_ids is still in the rows
+        assert_eq!(stats.bin_seq_counts, vec![2, 1]);
+        let row_lens = result
+            .sequences("input_ids")
+            .iter()
+            .map(Vec::len)
+            .collect::<Vec<_>>();
+        assert_eq!(row_lens, vec![12, 2]);
+
+        let both = PackOptions::builder()
+            .length_key(Some("packed_len".to_string()))
+            .length_field(Some("labels".to_string()))
+            .build();
+        assert!(both.is_err());
+
+        // a missing or non-token length_field is a ValueError naming it, not a panic
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+for labels in [[[1], 2], [[1]], None]:
+    examples = {"input_ids": [[1, 1], [2, 2]]}
+    if labels is not None:
+        examples["labels"] = labels
+    try:
+        fast_pack(examples, 8, "first_fit", "composer", 0, length_field="labels")
+        raise AssertionError(f"Expected {labels} to be rejected")
+    except ValueError as err:
+        assert "labels" in str(err)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_solo_threshold() {
+        let lengths = [7, 1, 6, 2, 1, 5, 3, 8, 2];
+        let histogram = count_lengths(&lengths, 8).unwrap();
+        let algorithm = packing::PackingAlgo::FirstFitDecreasing;
+        let assignments =
+            create_packing_strategy(histogram, 8, algorithm, None, Some(0.75)).unwrap();
+        // 6, 7 and 8 are at least 0.75 * 8, every one alone in its bin
+        for seq_len in [6, 7, 8] {
+            assert!(assignments.contains(&vec![seq_len]), "{seq_len}");
+        }
+        let shared = assignments.iter().filter(|bin| bin.len() > 1).flatten();
+        assert!(shared.clone().all(|&seq_len| seq_len < 6));
+        let mut packed = assignments.concat();
+        packed.sort_unstable();
+        assert_eq!(packed, [1, 1, 2, 2, 3, 5, 6, 7, 8]);
+
+        for threshold in [0.0, 1.5] {
+            let options = PackOptions::builder()
+                .solo_threshold(Some(threshold))
+                .build();
+            assert!(options.is_err());
+        }
+    }
+
+    #[test]
+    fn test_no_truncation() {
+        pyo3::prepare_freethreaded_python();
+        let lengths = (0..200).map(|i| i * 7 % 16 + 1).collect::<Vec<usize>>();
+        for algorithm in [
+            "first_fit",
+            "first_fit_decreasing",
+            "best_fit_decreasing",
+            "stable",
+        ] {
+            let pack_options = PackOptions::builder().no_truncation(true).build().unwrap();
+            let (result, _) = pack(
+                examples_from_lengths(&lengths),
+                16,
+                algorithm.parse().unwrap(),
+                Some(0),
+                ReturnFormat::Composer(Columns::new()),
+                &pack_options,
+                None,
+            );
+            assert!(result.is_ok(), "{algorithm}");
+        }
+
+        // packed by the 2 token labels, the 6 input_ids of each do not fit one row
+        let mut examples = examples_from_lengths(&[6, 6]);
+        let labels = examples_from_lengths(&[2, 2]).remove("input_ids").unwrap();
+        examples.insert("labels".to_string(), labels);
+        let pack_options = PackOptions::builder()
+            .length_field(Some("labels".to_string()))
+            .no_truncation(true)
+            .build()
+            .unwrap();
+        let (result, _) = pack(
+            examples,
+            8,
+            packing::PackingAlgo::FirstFit,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &pack_options,
+            None,
+        );
+        assert!(result.unwrap_err().to_string().contains("no_truncation"));
+    }
+
+    #[test]
+    fn test_position_offset() {
+        pyo3::prepare_freethreaded_python();
+        let mut examples = examples_from_lengths(&[2, 2, 3]);
+        let offsets = [100, 7, 0].map(Feature::Int).to_vec();
+        examples.insert("position_offset".to_string(), offsets);
+        let pack_options = PackOptions::builder().shuffle(false).build().unwrap();
+        let (result, _) = stable_pack_with_stats(examples, "composer", pack_options);
+        // every sequence counts up from its own offset, the padding stays at 0
+        assert_eq!(
+            result.sequences("positions_ids"),
+            &[vec![100, 101, 7, 8, 0, 1, 2, 0]]
+        );
+
+        // an offset past i32::MAX does not fit the int32 position ids
+        let mut examples = examples_from_lengths(&[2]);
+        let offsets = vec![Feature::Int(i32::MAX as i64)];
+        examples.insert("position_offset".to_string(), offsets);
+        let pack_options = PackOptions::builder()
+            .position_id_dtype(Some(options::PositionIdDtype::Int32))
+            .build()
+            .unwrap();
+        let (result, _) = pack(
+            examples,
+            8,
+            packing::PackingAlgo::Stable,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &pack_options,
+            None,
+        );
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("Position id 2147483648 does not fit in the position_id_dtype int32"));
+    }
+
+    #[test]
+    fn test_time_budget() {
+        let lengths = (0..3000).map(|i| i % 7 + 1).collect::<Vec<usize>>();
+        let pack_options = PackOptions::builder()
+            .time_budget_ms(Some(0))
+            .build()
+            .unwrap();
+        let (result, stats) = pack(
+            examples_from_lengths(&lengths),
+            8,
+            packing::PackingAlgo::FirstFit,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &pack_options,
+            None,
+        );
+        let tokens = result.unwrap().sequences("tokens").concat();
+        let packed = tokens.iter().filter(|&&id| id != 0).count();
+        // the budget ran out right away, the first sequences are packed anyway
+        assert!(packed > 0);
+        assert!(!stats.unplaced.is_empty());
+        assert_eq!(
+            packed + stats.unplaced.iter().sum::<usize>(),
+            lengths.iter().sum::<usize>()
+        );
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let lengths = (0..2000).map(|i| i * 7 % 13 + 1).collect::<Vec<usize>>();
+        let pack_options = PackOptions::builder().deterministic(true).build().unwrap();
+        let run = |num_threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .unwrap();
+            let (result, _) = pool.install(|| {
+                pack(
+                    examples_from_lengths(&lengths),
+                    16,
+                    packing::PackingAlgo::FirstFitShuffle,
+                    Some(0),
+                    ReturnFormat::Composer(Columns::new()),
+                    &pack_options,
+                    None,
+                )
+            });
+            result.unwrap()
+        };
+        // without a seed, the shuffles are the same on every run and for any number of
+        // threads
+        let first = run(1);
+        for num_threads in [1, 2, 4, 4] {
+            assert_eq!(run(num_threads), first, "{num_threads} threads");
+        }
+        let time_budget = PackOptions::builder()
+            .deterministic(true)
+            .time_budget_ms(Some(10));
+        assert!(time_budget.build().is_err());
+    }
+
+    #[test]
+    fn test_csr() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let examples = examples_from_lengths(&[3, 5, 2, 4, 7]);
+            let result = fast_pack(
+                py,
+                examples,
+                8,
+                "stable".into(),
+                "csr".into(),
+                Some(0),
+                None,
+            );
+            let Ok(PackOutput::Result(result)) = result else {
+                panic!("Expected the csr result");
+            };
+            let result: HashMap<String, Feature> = result.extract(py).unwrap();
+            let flat = |key: &str| result[key].as_tokens().unwrap().clone();
+            let (offsets, tokens) = (flat("offsets"), flat("tokens"));
+            let (positions_ids, loss_mask) = (flat("positions_ids"), flat("loss_mask"));
+            assert_eq!(*offsets.last().unwrap() as usize, tokens.len());
+            for row in offsets.windows(2) {
+                let row = row[0] as usize..row[1] as usize;
+                assert_eq!(row.len(), 8);
+                for i in row.clone() {
+                    // a position id of 0 starts a sequence or is padding
+                    let starts = i == row.start || tokens[i] != tokens[i - 1];
+                    assert_eq!(positions_ids[i] == 0, starts || tokens[i] == 0);
+                    assert_eq!(loss_mask[i], (tokens[i] != 0) as TokenId);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_zero_pack_size() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let examples = examples_from_lengths(&[3, 2]);
+            let result = fast_pack(
+                py,
+                examples,
+                0,
+                "stable".into(),
+                "composer".into(),
+                None,
+                None,
+            );
+            let Err(err) = result else {
+                panic!("Expected a pack size of 0 to be rejected");
+            };
+            assert!(err.is_instance_of::<PyValueError>(py));
+            assert_eq!(
+                err.to_string(),
+                "ValueError: target_pack_size must be greater than 0"
+            );
+        });
+    }
+
+    #[test]
+    fn test_iterator_preserves_columns() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+input_ids = [[1] * (i % 5 + 1) for i in range(20)]
+examples = {
+    "input_ids": input_ids,
+    "labels": [list(range(len(ids))) for ids in input_ids],
+    "attention_mask": [[1] * len(ids) for ids in input_ids],
+    "difficulty": [i % 3 for i in range(20)],
+}
+rows = list(fast_pack(examples, 8, "first_fit", "iterator", 0))
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let rows: Vec<strategy::iterator::Row> = globals
+                .get_item("rows")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert!(!rows.is_empty());
+            for row in rows {
+                let mut keys = row.keys().map(String::as_str).collect::<Vec<_>>();
+                keys.sort();
+                assert_eq!(
+                    keys,
+                    [
+                        "attention_mask",
+                        "difficulty",
+                        "labels",
+                        "positions_ids",
+                        "tokens"
+                    ]
+                );
+                let Feature::Tokens(tokens) = &row["tokens"] else {
+                    panic!("Expected tokens")
+                };
+                let Feature::Tokens(mask) = &row["attention_mask"] else {
+                    panic!("Expected a list of ints for attention_mask")
+                };
+                assert_eq!(mask.len(), 8);
+                // the mask lines up with the real tokens, padding is 0
+                let mask_of = |&id: &TokenId| if id == 0 { 0 } else { 1 };
+                assert_eq!(*mask, tokens.iter().map(mask_of).collect::<Sequence>());
+                let Feature::Tokens(labels) = &row["labels"] else {
+                    panic!("Expected labels")
+                };
+                assert_eq!(labels.len(), 8);
+                // one difficulty per sequence, python ints come back as a list of ids
+                let Feature::Tokens(difficulty) = &row["difficulty"] else {
+                    panic!("Expected the difficulty of every sequence")
+                };
+                assert!(!difficulty.is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn test_iterator_mixed_column_types() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("fast_pack", wrap_pyfunction!(fast_pack, py).unwrap())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+input_ids = [[1] * (i % 5 + 1) for i in range(20)]
+examples = {
+    "input_ids": input_ids,
+    # the first value is an int, later ones floats
+    "score": [1] + [i / 2 for i in range(1, 20)],
+    # the first list is empty, so it is extracted as ints, later ones hold floats
+    "weights": [[]] + [[0.5] * len(ids) for ids in input_ids[1:]],
+}
+examples["input_ids"][0] = []
+rows, stats = fast_pack(examples, 8, "first_fit", "iterator", 0, return_stats=True)
+rows = list(rows)
+assert len(rows) == len(stats["bin_seq_counts"])
+for row, num_seqs in zip(rows, stats["bin_seq_counts"]):
+    assert len(row["weights"]) == len(row["tokens"]) == 8
+    assert all(isinstance(w, float) for w in row["weights"])
+    assert len(row["score"]) == num_seqs
+    assert all(isinstance(s, float) for s in row["score"])
+
+# a number and a list cannot share a column
+examples = {"input_ids": [[1], [2]], "score": [1, [2]]}
+try:
+    list(fast_pack(examples, 8, "first_fit", "iterator", 0))
+    raise AssertionError("Expected a mixed column to be rejected")
+except ValueError as err:
+    assert "score" in str(err)
+"#
+                ),
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_seed_is_reproducible() {
+        let lengths = [3, 5, 3, 2, 3, 8, 1, 5, 3, 2, 4, 4, 6, 1];
+        let pack = |seed| {
+            let pack_options = PackOptions::builder().seed(seed).build().unwrap();
+            let (result, _) = pack(
+                examples_from_lengths(&lengths),
+                8,
+                packing::PackingAlgo::FirstFitShuffle,
+                Some(0),
+                ReturnFormat::Composer(Columns::new()),
+                &pack_options,
+                None,
+            );
+            result.unwrap()
+        };
+        let first = pack(Some(42));
+        for _ in 0..5 {
+            assert_eq!(pack(Some(42)), first);
+        }
+        // the seeded stream is fixed, a change here means the packing of existing seeds
+        // changed as well
+        assert_eq!(packing::rng(Some(42)).next_u64(), 12578764544318200737);
+    }
+
+    #[test]
+    fn test_target_efficiency() {
+        // first fit leaves the 3s together and the 7s alone, 30 tokens in 4 bins
+        let seq_lens = [3, 3, 3, 7, 7, 7];
+        let histogram = count_lengths(&seq_lens, 10).unwrap();
+        let (assignments, algorithm) = escalate_packing(histogram, 10, 0.95, None);
+        assert_eq!(algorithm, "first_fit_decreasing");
+        assert_eq!(assignments, vec![vec![7, 3]; 3]);
+        // nothing reaches the target, the most efficient is kept
+        let histogram = count_lengths(&[6, 6], 10).unwrap();
+        let (_, algorithm) = escalate_packing(histogram, 10, 0.95, None);
+        assert_eq!(algorithm, "first_fit");
+
+        let examples = HashMap::from([(
+            "input_ids".to_string(),
+            seq_lens
+                .iter()
+                .map(|&len| Feature::Tokens(vec![1; len]))
+                .collect(),
+        )]);
+        let pack_options = PackOptions::builder()
+            .target_efficiency(Some(0.95))
+            .build()
+            .unwrap();
+        assert!(pack_options.returns_stats());
+        let algorithm = packing::PackingAlgo::FirstFit;
+        let (_, stats) = pack_with(examples, 10, algorithm, &pack_options, |_, _, _| {
+            PyResult::Ok(())
+        });
+        assert_eq!(stats.algorithm.as_deref(), Some("first_fit_decreasing"));
+        assert_eq!(stats.bin_seq_counts, vec![2, 2, 2]);
+    }
+
+    #[test]
+    fn test_given_position_ids() {
+        let mut examples = examples_from_lengths(&[3, 2, 3]);
+        // RoPE offsets that do not start at 0
+        let position_ids = vec![
+            Feature::Tokens(vec![10, 11, 12]),
+            Feature::Tokens(vec![0, 1]),
+            Feature::Tokens(vec![5, 5, 6]),
+        ];
+        examples.insert("position_ids".to_string(), position_ids);
+        let result = stable_pack(examples, "composer");
+        let tokens = result.sequences("tokens").concat();
+        let positions = result.sequences("positions_ids").concat();
+        let positions_of = |id: TokenId| {
+            tokens
+                .iter()
+                .zip(&positions)
+                .filter(|(&token, _)| token == id)
+                .map(|(_, &position)| position)
+                .collect::<Sequence>()
+        };
+        assert_eq!(positions_of(1), vec![10, 11, 12]);
+        assert_eq!(positions_of(2), vec![0, 1]);
+        assert_eq!(positions_of(3), vec![5, 5, 6]);
+    }
+
+    #[test]
+    fn test_stable_decreasing() {
+        // many sequences of the same length, told apart by their token ids
+        let lengths = [2, 4, 2, 2, 4, 6, 2, 2, 4, 2, 6, 2];
+        let pack = || {
+            let algorithm: packing::PackingAlgo = "stable_decreasing".parse().unwrap();
+            let pack_options = PackOptions::builder()
+                .shuffle(algorithm.shuffles_buckets(None))
+                .build()
+                .unwrap();
+            let (result, _) = pack(
+                examples_from_lengths(&lengths),
+                8,
+                algorithm,
+                Some(0),
+                ReturnFormat::Composer(Columns::new()),
+                &pack_options,
+                None,
+            );
+            result.unwrap()
+        };
+        let first = pack();
+        for _ in 0..5 {
+            assert_eq!(pack(), first);
+        }
+        // the 6s (ids 6 and 11), 4s (2, 5, 9) and 2s (1, 3, 4, ...) are each used in
+        // input order
+        let order = first.sequences("tokens").concat();
+        let mut order = order
+            .into_iter()
+            .filter(|&id| id != 0)
+            .collect::<Sequence>();
+        order.dedup();
+        assert_eq!(order, vec![6, 1, 11, 3, 2, 5, 9, 4, 7, 8, 10, 12]);
+    }
+
+    #[test]
+    fn test_estimate_memory() {
+        let lengths = [3, 5, 3, 2, 3, 8, 1, 5, 3, 2, 7, 6, 4, 4];
+        let seq_lens = lengths.to_vec();
+        for return_format in ["composer", "nemo"] {
+            let result = stable_pack(examples_from_lengths(&lengths), return_format);
+            let allocated = result
+                .data()
+                .values()
+                .map(|column| match column {
+                    Column::Tokens(rows) => {
+                        rows.iter().map(Vec::len).sum::<usize>() * std::mem::size_of::<TokenId>()
+                    }
+                    other => panic!("Expected token columns, got {other:?}"),
+                })
+                .sum::<usize>();
+            let estimate = memory_estimate(
+                &seq_lens,
+                8,
+                &return_format.parse().unwrap(),
+                packing::PackingAlgo::Stable,
+            )
+            .unwrap();
+            assert!(
+                estimate <= 2 * allocated,
+                "{return_format}: {estimate} vs {allocated}"
+            );
+            assert!(
+                allocated <= 2 * estimate,
+                "{return_format}: {estimate} vs {allocated}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_batch_size() {
+        use crate::options::LastBatch;
+
+        // 5 rows of 8 tokens
+        let lengths = [8, 8, 8, 8, 8];
+        let batches = |last_batch| {
+            let pack_options = PackOptions::builder()
+                .shuffle(false)
+                .batch_size(Some(2))
+                .last_batch(last_batch)
+                .build()
+                .unwrap();
+            let (result, stats) =
+                stable_pack_with_stats(examples_from_lengths(&lengths), "composer", pack_options);
+            let batches = into_batches(result.into_data(), 2);
+            let sizes = batches
+                .iter()
+                .map(|batch| batch["tokens"].len())
+                .collect::<Vec<_>>();
+            (batches, sizes, stats)
+        };
+        let (_, sizes, _) = batches(LastBatch::Keep);
+        assert_eq!(sizes, vec![2, 2, 1]);
+
+        let (batches_padded, sizes, stats) = batches(LastBatch::Pad);
+        assert_eq!(sizes, vec![2, 2, 2]);
+        assert_eq!(stats.bin_seq_counts, vec![1, 1, 1, 1, 1, 0]);
+        // the padding row has no tokens, only pad ids
+        let Column::Tokens(last) = &batches_padded[2]["tokens"] else {
+            panic!("Expected tokens");
+        };
+        assert_eq!(last[0], vec![5; 8]);
+        assert_eq!(last[1], vec![0; 8]);
+
+        let (batches_dropped, sizes, _) = batches(LastBatch::Drop);
+        assert_eq!(sizes, vec![2, 2]);
+        assert!(batches_dropped
+            .iter()
+            .all(|batch| batch.contains_key("positions_ids")));
+    }
+
+    #[test]
+    fn test_return_format_from_str() {
+        let parse = |s: &str| s.parse::<ReturnFormat>();
+        assert_eq!(parse(" nemo\n"), Ok(ReturnFormat::Nemo(Columns::new())));
+        assert_eq!(
+            parse("Composer "),
+            Ok(ReturnFormat::Composer(Columns::new()))
+        );
+        assert_eq!(parse("HF"), Ok(ReturnFormat::Composer(Columns::new())));
+        assert_eq!(
+            parse("huggingface"),
+            Ok(ReturnFormat::Composer(Columns::new()))
+        );
+        assert_eq!(parse("iter"), Ok(ReturnFormat::Iterator(Columns::new())));
+        assert_eq!(parse("nemo composer"), Err("Invalid return format"));
+        assert_eq!(parse("nem"), Err("Invalid return format"));
+        assert_eq!(parse(""), Err("Invalid return format"));
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let pack = |return_format: &str| {
+                let examples = examples_from_lengths(&[3, 2]);
+                fast_pack(
+                    py,
+                    examples,
+                    8,
+                    "stable".into(),
+                    return_format.into(),
+                    None,
+                    None,
+                )
+            };
+            assert!(pack(" Nemo ").is_ok());
+            let Err(err) = pack("nemo2") else {
+                panic!("Expected an unknown return format to be rejected");
+            };
+            assert_eq!(err.to_string(), "ValueError: Unknown format");
+        });
+    }
+
+    #[test]
+    fn test_no_pack() {
+        let lengths = [3, 5, 3, 2, 1, 8, 2];
+        let algorithm: packing::PackingAlgo = "none".parse().unwrap();
+        assert!(!algorithm.shuffles_buckets(None));
+        let pack_options = PackOptions::builder()
+            .shuffle(false)
+            .return_stats(true)
+            .build()
+            .unwrap();
+        let (result, stats) = pack(
+            examples_from_lengths(&lengths),
+            8,
+            algorithm,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &pack_options,
+            None,
+        );
+        let tokens = result.unwrap().sequences("tokens").clone();
+        assert_eq!(tokens.len(), lengths.len());
+        assert_eq!(stats.bin_seq_counts, vec![1; lengths.len()]);
+        for row in tokens {
+            // a single sequence, padded to the pack size
+            let mut ids = row.into_iter().filter(|&id| id != 0).collect::<Sequence>();
+            ids.dedup();
+            assert_eq!(ids.len(), 1);
+        }
+    }
+}
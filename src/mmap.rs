@@ -0,0 +1,250 @@
+use memmap2::{Mmap, MmapMut};
+use pyo3::prelude::*;
+use std::fs::{File, OpenOptions};
+use std::io;
+
+use crate::{Column, Columns, TokenId};
+
+// Where write_mmap put the packed outputs and how to read them back
+#[derive(Debug, PartialEq, IntoPyObject)]
+pub struct MmapOutput {
+    pub path: String,
+    // little-endian u64 row offsets, num_rows + 1 of them, then the checksum of every row
+    pub index_path: String,
+    // the outputs in the order of their blocks in the file
+    pub outputs: Vec<String>,
+    // (number of outputs, values per output), each output is a block of the file
+    pub shape: (usize, usize),
+    pub num_rows: usize,
+    pub dtype: &'static str,
+}
+
+// The outputs with one value per token, laid out like the token output. Outputs with
+// another length per row (e.g. seq_start_id, doc_lengths) are left out
+fn token_outputs(result: &Columns) -> Vec<(&String, &Vec<Vec<TokenId>>)> {
+    let token_key = if result.contains_key("input_ids") {
+        "input_ids"
+    } else {
+        "tokens"
+    };
+    let row_lens = result
+        .get(token_key)
+        .map(Column::row_lens)
+        .unwrap_or_default();
+    let mut outputs = result
+        .iter()
+        .filter_map(|(key, column)| match column {
+            Column::Tokens(rows) if column.row_lens() == row_lens => Some((key, rows)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    outputs.sort_by_key(|(key, _)| key.as_str());
+    outputs
+}
+
+// 64-bit FNV-1a, continued from `hash`
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+// The checksum of every row over its bytes in each block, in block order
+fn row_checksums(blocks: &[&[u8]], offsets: &[u64]) -> Vec<u64> {
+    let token_size = std::mem::size_of::<TokenId>();
+    offsets
+        .windows(2)
+        .map(|row| {
+            let (start, end) = (row[0] as usize * token_size, row[1] as usize * token_size);
+            blocks.iter().fold(0xcbf29ce484222325, |hash, block| {
+                fnv1a(hash, &block[start..end])
+            })
+        })
+        .collect()
+}
+
+// Same checksums as row_checksums, computed from the rows of every output before they are
+// written, so the blocks are never built in memory
+fn output_checksums(outputs: &[(&String, &Vec<Vec<TokenId>>)], num_rows: usize) -> Vec<u64> {
+    (0..num_rows)
+        .map(|row| {
+            outputs
+                .iter()
+                .flat_map(|(_, rows)| &rows[row])
+                .fold(0xcbf29ce484222325, |hash, id| {
+                    fnv1a(hash, &id.to_le_bytes())
+                })
+        })
+        .collect()
+}
+
+fn write_file(path: &str, bytes: impl Iterator<Item = u8>, len: usize) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.set_len(len as u64)?;
+    // an empty file cannot be mapped
+    if len == 0 {
+        return Ok(());
+    }
+    let mut mmap = unsafe { MmapMut::map_mut(&file)? };
+    for (slot, byte) in mmap.iter_mut().zip(bytes) {
+        *slot = byte;
+    }
+    mmap.flush()
+}
+
+// Writes every token aligned output to `path`, one block per output (sorted by name) with
+// the rows back to back as little-endian token ids, and the row offsets into each block
+// to `path.idx`. The bytes are written straight from the rows into the map, so the rows
+// are only held in memory once, not as python objects or as a copy of the blocks
+pub fn write_mmap(result: &Columns, path: &str) -> io::Result<MmapOutput> {
+    let outputs = token_outputs(result);
+    let row_lens = outputs
+        .first()
+        .map(|(_, rows)| rows.iter().map(Vec::len).collect());
+    let row_lens: Vec<usize> = row_lens.unwrap_or_default();
+    let mut offsets = vec![0u64];
+    offsets.extend(row_lens.iter().scan(0u64, |end, &len| {
+        *end += len as u64;
+        Some(*end)
+    }));
+    let values_per_output = *offsets.last().unwrap() as usize;
+
+    let token_size = std::mem::size_of::<TokenId>();
+    let checksums = output_checksums(&outputs, row_lens.len());
+    write_file(
+        path,
+        outputs
+            .iter()
+            .flat_map(|(_, rows)| rows.iter().flatten().flat_map(|id| id.to_le_bytes())),
+        outputs.len() * values_per_output * token_size,
+    )?;
+    let index_path = format!("{path}.idx");
+    write_file(
+        &index_path,
+        offsets
+            .iter()
+            .chain(&checksums)
+            .flat_map(|value| value.to_le_bytes()),
+        (offsets.len() + checksums.len()) * std::mem::size_of::<u64>(),
+    )?;
+    Ok(MmapOutput {
+        path: path.to_string(),
+        index_path,
+        shape: (outputs.len(), values_per_output),
+        outputs: outputs.into_iter().map(|(key, _)| key.clone()).collect(),
+        num_rows: row_lens.len(),
+        dtype: if token_size == 4 { "uint32" } else { "uint64" },
+    })
+}
+
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    let file = File::open(path)?;
+    // an empty file cannot be mapped
+    if file.metadata()?.len() == 0 {
+        return Ok(Vec::new());
+    }
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok(mmap.to_vec())
+}
+
+// The rows of a file written by write_mmap whose checksum does not match their values
+pub fn corrupt_rows(path: &str) -> io::Result<Vec<usize>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let index = read_file(&format!("{path}.idx"))?
+        .chunks(8)
+        .map(|bytes| bytes.try_into().map(u64::from_le_bytes))
+        .collect::<Result<Vec<u64>, _>>()
+        .map_err(|_| invalid("The index is not a whole number of u64 values"))?;
+    if index.len() % 2 == 0 {
+        return Err(invalid(
+            "The index does not hold num_rows + 1 offsets and num_rows checksums",
+        ));
+    }
+    let (offsets, checksums) = index.split_at(index.len() / 2 + 1);
+    let block_len = *offsets.last().unwrap() as usize * std::mem::size_of::<TokenId>();
+    let values = read_file(path)?;
+    if values.is_empty() {
+        return Ok(Vec::new());
+    }
+    if block_len == 0 || values.len() % block_len != 0 {
+        return Err(invalid("The file size does not match the index"));
+    }
+    let blocks = values.chunks(block_len).collect::<Vec<_>>();
+    Ok(row_checksums(&blocks, offsets)
+        .into_iter()
+        .zip(checksums)
+        .enumerate()
+        .filter(|(_, (checksum, expected))| checksum != *expected)
+        .map(|(row, _)| row)
+        .collect())
+}
+
+/// Checks the rows written with `output_path` against their checksums in the index and
+/// returns the rows that do not match, empty if the file is intact.
+#[pyfunction]
+pub fn verify_output(py: Python<'_>, path: String) -> PyResult<Vec<usize>> {
+    Ok(py.allow_threads(|| corrupt_rows(&path))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_mmap() {
+        let dir = std::env::temp_dir().join(format!("binpack_rs_mmap_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("packed.bin").to_string_lossy().into_owned();
+        let result = Columns::from([
+            (
+                "tokens".to_string(),
+                Column::Tokens(vec![vec![1, 2, 3], vec![4, 5]]),
+            ),
+            (
+                "positions_ids".to_string(),
+                Column::Tokens(vec![vec![0, 1, 0], vec![0, 1]]),
+            ),
+            // one value per row, not written
+            (
+                "doc_lengths".to_string(),
+                Column::Tokens(vec![vec![2], vec![2]]),
+            ),
+        ]);
+        let output = write_mmap(&result, &path).unwrap();
+        assert_eq!(output.outputs, vec!["positions_ids", "tokens"]);
+        assert_eq!(output.shape, (2, 5));
+        assert_eq!(output.num_rows, 2);
+
+        let read = |path: &str, size: usize| {
+            let mmap = unsafe { Mmap::map(&File::open(path).unwrap()).unwrap() };
+            mmap.chunks(size)
+                .map(|bytes| bytes.to_vec())
+                .collect::<Vec<_>>()
+        };
+        let values = read(&output.path, std::mem::size_of::<TokenId>())
+            .into_iter()
+            .map(|bytes| TokenId::from_le_bytes(bytes.try_into().unwrap()))
+            .collect::<Vec<TokenId>>();
+        let offsets = read(&output.index_path, 8)
+            .into_iter()
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+            .collect::<Vec<usize>>();
+        assert_eq!(offsets[..3], [0, 3, 5]);
+        let (positions_ids, tokens) = values.split_at(output.shape.1);
+        assert_eq!(tokens[offsets[1]..offsets[2]], [4, 5]);
+        assert_eq!(positions_ids[offsets[0]..offsets[1]], [0, 1, 0]);
+        assert_eq!(corrupt_rows(&path).unwrap(), Vec::<usize>::new());
+
+        // flipping a byte of the second row of the tokens block
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[(output.shape.1 + offsets[1]) * std::mem::size_of::<TokenId>()] ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(corrupt_rows(&path).unwrap(), vec![1]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,868 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use std::sync::Arc;
+
+use crate::common::{Sequence, TokenId};
+use crate::packing::BinOrder;
+
+// Position id of the separator between two sequences of a row
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SeparatorPosition {
+    // the separator ends the previous sequence, its position follows on from it
+    #[default]
+    Continue,
+    // the separator starts the next sequence at 0, the sequence follows on from it
+    Reset,
+}
+
+impl std::str::FromStr for SeparatorPosition {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "continue" => Ok(SeparatorPosition::Continue),
+            "reset" => Ok(SeparatorPosition::Reset),
+            _ => Err("Invalid separator position"),
+        }
+    }
+}
+
+// How the position ids of a composer row are numbered
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PositionMode {
+    // every sequence starts at 0, for document aware attention and RoPE
+    #[default]
+    Reset,
+    // 0..len across the whole row, ignoring the sequence boundaries (plain concatenation)
+    Continuous,
+}
+
+impl std::str::FromStr for PositionMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reset" => Ok(PositionMode::Reset),
+            "continuous" => Ok(PositionMode::Continuous),
+            _ => Err("Invalid position mode"),
+        }
+    }
+}
+
+// What create_hist does with sequences without any tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ZeroLengthPolicy {
+    // leave them out, they would only take up a slot in a bin
+    #[default]
+    Drop,
+    Keep,
+    Error,
+}
+
+impl std::str::FromStr for ZeroLengthPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "drop" => Ok(ZeroLengthPolicy::Drop),
+            "keep" => Ok(ZeroLengthPolicy::Keep),
+            "error" => Ok(ZeroLengthPolicy::Error),
+            _ => Err("Invalid zero length policy"),
+        }
+    }
+}
+
+// Integer width of the position ids output, the token id type is used without one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PositionIdDtype {
+    Int32,
+    Int64,
+}
+
+impl std::str::FromStr for PositionIdDtype {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "int32" => Ok(PositionIdDtype::Int32),
+            "int64" => Ok(PositionIdDtype::Int64),
+            _ => Err("Invalid position id dtype"),
+        }
+    }
+}
+
+// Narrower type for the token ids output, the token id type is used without one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenDtype {
+    UInt8,
+    UInt16,
+}
+
+impl std::str::FromStr for TokenDtype {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "uint8" => Ok(TokenDtype::UInt8),
+            "uint16" => Ok(TokenDtype::UInt16),
+            _ => Err("Invalid token dtype"),
+        }
+    }
+}
+
+// Which end of an over-length row is cut off
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TruncationSide {
+    #[default]
+    Right,
+    Left,
+}
+
+impl std::str::FromStr for TruncationSide {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "right" => Ok(TruncationSide::Right),
+            "left" => Ok(TruncationSide::Left),
+            _ => Err("Invalid truncation side"),
+        }
+    }
+}
+
+impl TruncationSide {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TruncationSide::Right => "right",
+            TruncationSide::Left => "left",
+        }
+    }
+}
+
+// What happens to the last batch when the rows do not fill it, with batch_size
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LastBatch {
+    // the last batch has fewer rows
+    #[default]
+    Keep,
+    // rows without any sequences (only padding) fill up the last batch
+    Pad,
+    // the rows of the last batch are left out
+    Drop,
+}
+
+impl std::str::FromStr for LastBatch {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(LastBatch::Keep),
+            "pad" => Ok(LastBatch::Pad),
+            "drop" => Ok(LastBatch::Drop),
+            _ => Err("Invalid last batch policy"),
+        }
+    }
+}
+
+// Seed of the shuffles with deterministic=True when no seed is given
+pub const DETERMINISTIC_SEED: u64 = 0;
+
+// Options that apply to every return format, parsed from the same kwargs as the
+// format specific options
+#[derive(Clone)]
+pub struct PackOptions {
+    pub sort_key: Option<String>,
+    // shuffle sequences of the same length, set from the packing algorithm
+    pub shuffle: bool,
+    pub min_seqs_per_bin: Option<usize>,
+    // sequences of at least this fraction of the pack size get a bin of their own
+    pub solo_threshold: Option<f64>,
+    pub return_stats: bool,
+    // time each phase, this also returns the stats
+    pub return_timing: bool,
+    // return the order each length bucket was used in, this also returns the stats
+    pub return_permutation: bool,
+    // add the length of every sequence in each row as a doc_lengths output
+    pub return_doc_lengths: bool,
+    // add the start and length of every sequence in each row as a seq_spans output
+    pub return_seq_spans: bool,
+    // add the index of every token in its row as a global_position_ids output, next to
+    // the position ids that restart for every sequence
+    pub return_global_position_ids: bool,
+    // return a pyarrow RecordBatch instead of a dict of lists
+    pub as_arrow: bool,
+    // return a list with a dict per row instead of a dict of lists, for every format
+    pub row_major: bool,
+    pub sort_bins_by: BinOrder,
+    // pad to the next multiple instead of the pack size, never past the pack size
+    pub pad_to_multiple_of: Option<usize>,
+    // pad composer rows to the longest row instead of the pack size
+    pub pad_to_longest: bool,
+    // raise instead of padding a row that is not exactly full
+    pub forbid_padding: bool,
+    // bins with fewer tokens are left out of the result
+    pub min_tokens: Option<usize>,
+    // the examples are grouped by length, see create_hist
+    pub assume_sorted: bool,
+    // prepended to every row, the bins are packed with the remaining capacity
+    pub shared_prefix: Sequence,
+    // tokens left free in every bin, the rows are still padded to the pack size
+    pub reserve_slots: usize,
+    // check that every input token ends up in the result
+    pub verify: bool,
+    // raise instead of truncating a row that is longer than the pack size
+    pub no_truncation: bool,
+    // inserted between the sequences of a composer row, the packing leaves room for it
+    pub separator_id: Option<TokenId>,
+    pub separator_position_id: SeparatorPosition,
+    // counts per length from compute_histogram, create_hist skips counting the lengths
+    pub histogram: Option<Vec<usize>>,
+    pub zero_length: ZeroLengthPolicy,
+    pub position_id_dtype: Option<PositionIdDtype>,
+    // shuffles use a ChaCha8 rng seeded with this, so the packing is the same on every
+    // platform
+    pub seed: Option<u64>,
+    // try first fit, first fit decreasing and best fit decreasing until one packs this
+    // efficiently, instead of the packing algorithm passed in. This also returns the stats
+    pub target_efficiency: Option<f64>,
+    // group the rows into batches of this many rows
+    pub batch_size: Option<usize>,
+    pub last_batch: LastBatch,
+    // rows longer than the pack size lose their last tokens (right) or first tokens (left)
+    pub truncation_side: TruncationSide,
+    // every token id (and the pad id) must fit, checked when filling
+    pub token_dtype: Option<TokenDtype>,
+    // once the packing takes longer, the first fit algorithms stop opening bins and the
+    // sequences that are left go to the stats. This also returns the stats
+    pub time_budget_ms: Option<u64>,
+    // name of an int column used as the length of every sequence when packing, instead of
+    // the length of its input_ids
+    pub length_key: Option<String>,
+    // name of the token column whose length is used for every sequence when packing, by
+    // default input_ids. Every column is still packed
+    pub length_field: Option<String>,
+    // with the spill feature, length buckets with more than spill_threshold examples are
+    // written to this directory while filling and read back spill_threshold at a time
+    pub spill_dir: Option<String>,
+    pub spill_threshold: usize,
+    pub position_mode: PositionMode,
+    // position id of the padding, by default 0 with reset and continuing the row with
+    // continuous
+    pub pad_position_id: Option<TokenId>,
+    // also return the stats, with the settings the result was packed with
+    pub return_meta: bool,
+    // return the datasets feature type of every output in the stats
+    pub return_schema: bool,
+    // only the first of the examples with identical input_ids is packed
+    pub dedup: bool,
+    // the same result on every run and any number of threads, the shuffles fall back to
+    // DETERMINISTIC_SEED without a seed
+    pub deterministic: bool,
+    // write the token outputs to this memory-mapped file instead of returning them
+    pub output_path: Option<String>,
+    // a callable that gets the tokens of every sequence and its index in the bin, and
+    // returns the tokens to pack instead. Shared, since the options are cloned
+    pub token_transform: Option<Arc<Py<PyAny>>>,
+}
+
+impl PackOptions {
+    pub fn builder() -> PackOptionsBuilder {
+        PackOptionsBuilder::default()
+    }
+
+    // Whether fast_pack returns the stats next to the result
+    pub fn returns_stats(&self) -> bool {
+        self.return_stats
+            || self.return_timing
+            || self.return_permutation
+            || self.target_efficiency.is_some()
+            || self.time_budget_ms.is_some()
+            || self.return_meta
+            || self.return_schema
+    }
+}
+
+pub struct PackOptionsBuilder {
+    sort_key: Option<String>,
+    shuffle: bool,
+    min_seqs_per_bin: Option<usize>,
+    solo_threshold: Option<f64>,
+    return_stats: bool,
+    return_timing: bool,
+    return_permutation: bool,
+    return_doc_lengths: bool,
+    return_seq_spans: bool,
+    return_global_position_ids: bool,
+    as_arrow: bool,
+    row_major: bool,
+    sort_bins_by: BinOrder,
+    pad_to_multiple_of: Option<usize>,
+    pad_to_longest: bool,
+    forbid_padding: bool,
+    min_tokens: Option<usize>,
+    assume_sorted: bool,
+    shared_prefix: Sequence,
+    reserve_slots: usize,
+    verify: bool,
+    no_truncation: bool,
+    separator_id: Option<TokenId>,
+    separator_position_id: SeparatorPosition,
+    histogram: Option<Vec<usize>>,
+    zero_length: ZeroLengthPolicy,
+    position_id_dtype: Option<PositionIdDtype>,
+    seed: Option<u64>,
+    target_efficiency: Option<f64>,
+    batch_size: Option<usize>,
+    last_batch: LastBatch,
+    truncation_side: TruncationSide,
+    token_dtype: Option<TokenDtype>,
+    time_budget_ms: Option<u64>,
+    length_key: Option<String>,
+    length_field: Option<String>,
+    spill_dir: Option<String>,
+    spill_threshold: usize,
+    position_mode: PositionMode,
+    pad_position_id: Option<TokenId>,
+    return_meta: bool,
+    return_schema: bool,
+    dedup: bool,
+    deterministic: bool,
+    output_path: Option<String>,
+    token_transform: Option<Arc<Py<PyAny>>>,
+}
+
+impl Default for PackOptionsBuilder {
+    fn default() -> Self {
+        Self {
+            sort_key: None,
+            shuffle: true,
+            min_seqs_per_bin: None,
+            solo_threshold: None,
+            return_stats: false,
+            return_timing: false,
+            return_permutation: false,
+            return_doc_lengths: false,
+            return_seq_spans: false,
+            return_global_position_ids: false,
+            as_arrow: false,
+            row_major: false,
+            sort_bins_by: BinOrder::None,
+            pad_to_multiple_of: None,
+            pad_to_longest: false,
+            forbid_padding: false,
+            min_tokens: None,
+            assume_sorted: false,
+            shared_prefix: Vec::new(),
+            reserve_slots: 0,
+            verify: false,
+            no_truncation: false,
+            separator_id: None,
+            separator_position_id: SeparatorPosition::Continue,
+            histogram: None,
+            zero_length: ZeroLengthPolicy::Drop,
+            position_id_dtype: None,
+            seed: None,
+            target_efficiency: None,
+            batch_size: None,
+            last_batch: LastBatch::Keep,
+            truncation_side: TruncationSide::Right,
+            token_dtype: None,
+            time_budget_ms: None,
+            length_key: None,
+            length_field: None,
+            spill_dir: None,
+            spill_threshold: 100_000,
+            position_mode: PositionMode::Reset,
+            pad_position_id: None,
+            return_meta: false,
+            return_schema: false,
+            dedup: false,
+            deterministic: false,
+            output_path: None,
+            token_transform: None,
+        }
+    }
+}
+
+impl PackOptionsBuilder {
+    pub fn sort_key(mut self, key: Option<String>) -> Self {
+        self.sort_key = key;
+        self
+    }
+
+    pub fn shuffle(mut self, shuffle: bool) -> Self {
+        self.shuffle = shuffle;
+        self
+    }
+
+    pub fn min_seqs_per_bin(mut self, min_seqs: Option<usize>) -> Self {
+        self.min_seqs_per_bin = min_seqs;
+        self
+    }
+
+    pub fn solo_threshold(mut self, threshold: Option<f64>) -> Self {
+        self.solo_threshold = threshold;
+        self
+    }
+
+    pub fn return_stats(mut self, return_stats: bool) -> Self {
+        self.return_stats = return_stats;
+        self
+    }
+
+    pub fn return_timing(mut self, return_timing: bool) -> Self {
+        self.return_timing = return_timing;
+        self
+    }
+
+    pub fn return_permutation(mut self, return_permutation: bool) -> Self {
+        self.return_permutation = return_permutation;
+        self
+    }
+
+    pub fn return_doc_lengths(mut self, return_doc_lengths: bool) -> Self {
+        self.return_doc_lengths = return_doc_lengths;
+        self
+    }
+
+    pub fn return_seq_spans(mut self, return_seq_spans: bool) -> Self {
+        self.return_seq_spans = return_seq_spans;
+        self
+    }
+
+    pub fn return_global_position_ids(mut self, return_global_position_ids: bool) -> Self {
+        self.return_global_position_ids = return_global_position_ids;
+        self
+    }
+
+    pub fn as_arrow(mut self, as_arrow: bool) -> Self {
+        self.as_arrow = as_arrow;
+        self
+    }
+
+    pub fn row_major(mut self, row_major: bool) -> Self {
+        self.row_major = row_major;
+        self
+    }
+
+    pub fn sort_bins_by(mut self, order: BinOrder) -> Self {
+        self.sort_bins_by = order;
+        self
+    }
+
+    pub fn pad_to_multiple_of(mut self, multiple: Option<usize>) -> Self {
+        self.pad_to_multiple_of = multiple;
+        self
+    }
+
+    pub fn pad_to_longest(mut self, pad_to_longest: bool) -> Self {
+        self.pad_to_longest = pad_to_longest;
+        self
+    }
+
+    pub fn forbid_padding(mut self, forbid_padding: bool) -> Self {
+        self.forbid_padding = forbid_padding;
+        self
+    }
+
+    pub fn min_tokens(mut self, min_tokens: Option<usize>) -> Self {
+        self.min_tokens = min_tokens;
+        self
+    }
+
+    pub fn assume_sorted(mut self, assume_sorted: bool) -> Self {
+        self.assume_sorted = assume_sorted;
+        self
+    }
+
+    pub fn shared_prefix(mut self, prefix: Sequence) -> Self {
+        self.shared_prefix = prefix;
+        self
+    }
+
+    pub fn reserve_slots(mut self, reserve_slots: usize) -> Self {
+        self.reserve_slots = reserve_slots;
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn no_truncation(mut self, no_truncation: bool) -> Self {
+        self.no_truncation = no_truncation;
+        self
+    }
+
+    pub fn separator_id(mut self, separator_id: Option<TokenId>) -> Self {
+        self.separator_id = separator_id;
+        self
+    }
+
+    pub fn separator_position_id(mut self, position: SeparatorPosition) -> Self {
+        self.separator_position_id = position;
+        self
+    }
+
+    pub fn histogram(mut self, histogram: Option<Vec<usize>>) -> Self {
+        self.histogram = histogram;
+        self
+    }
+
+    pub fn zero_length(mut self, policy: ZeroLengthPolicy) -> Self {
+        self.zero_length = policy;
+        self
+    }
+
+    pub fn position_id_dtype(mut self, dtype: Option<PositionIdDtype>) -> Self {
+        self.position_id_dtype = dtype;
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn target_efficiency(mut self, target: Option<f64>) -> Self {
+        self.target_efficiency = target;
+        self
+    }
+
+    pub fn batch_size(mut self, batch_size: Option<usize>) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    pub fn last_batch(mut self, last_batch: LastBatch) -> Self {
+        self.last_batch = last_batch;
+        self
+    }
+
+    pub fn truncation_side(mut self, side: TruncationSide) -> Self {
+        self.truncation_side = side;
+        self
+    }
+
+    pub fn token_dtype(mut self, dtype: Option<TokenDtype>) -> Self {
+        self.token_dtype = dtype;
+        self
+    }
+
+    pub fn time_budget_ms(mut self, budget: Option<u64>) -> Self {
+        self.time_budget_ms = budget;
+        self
+    }
+
+    pub fn length_key(mut self, length_key: Option<String>) -> Self {
+        self.length_key = length_key;
+        self
+    }
+
+    pub fn length_field(mut self, length_field: Option<String>) -> Self {
+        self.length_field = length_field;
+        self
+    }
+
+    pub fn spill_dir(mut self, spill_dir: Option<String>) -> Self {
+        self.spill_dir = spill_dir;
+        self
+    }
+
+    pub fn spill_threshold(mut self, spill_threshold: usize) -> Self {
+        self.spill_threshold = spill_threshold;
+        self
+    }
+
+    pub fn position_mode(mut self, mode: PositionMode) -> Self {
+        self.position_mode = mode;
+        self
+    }
+
+    pub fn pad_position_id(mut self, pad_position_id: Option<TokenId>) -> Self {
+        self.pad_position_id = pad_position_id;
+        self
+    }
+
+    pub fn return_meta(mut self, return_meta: bool) -> Self {
+        self.return_meta = return_meta;
+        self
+    }
+
+    pub fn return_schema(mut self, return_schema: bool) -> Self {
+        self.return_schema = return_schema;
+        self
+    }
+
+    pub fn dedup(mut self, dedup: bool) -> Self {
+        self.dedup = dedup;
+        self
+    }
+
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    pub fn output_path(mut self, output_path: Option<String>) -> Self {
+        self.output_path = output_path;
+        self
+    }
+
+    pub fn token_transform(mut self, token_transform: Option<Py<PyAny>>) -> Self {
+        self.token_transform = token_transform.map(Arc::new);
+        self
+    }
+
+    pub fn from_py_dict(mut self, kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
+        if let Some(kwargs) = kwargs {
+            for (key, value) in kwargs.iter() {
+                if let Ok(key_str) = key.extract::<&str>() {
+                    match key_str {
+                        "sort_key" => self.sort_key = value.extract()?,
+                        "assume_sorted" => self.assume_sorted = value.extract()?,
+                        "min_tokens" => self.min_tokens = value.extract()?,
+                        "min_seqs_per_bin" => self.min_seqs_per_bin = value.extract()?,
+                        "solo_threshold" => self.solo_threshold = value.extract()?,
+                        "return_stats" => self.return_stats = value.extract()?,
+                        "return_timing" => self.return_timing = value.extract()?,
+                        "return_permutation" => self.return_permutation = value.extract()?,
+                        "return_meta" => self.return_meta = value.extract()?,
+                        "return_schema" => self.return_schema = value.extract()?,
+                        "dedup" => self.dedup = value.extract()?,
+                        "deterministic" => self.deterministic = value.extract()?,
+                        "output_path" => self.output_path = value.extract()?,
+                        "token_transform" if value.is_none() => self.token_transform = None,
+                        "token_transform" if !value.is_callable() => {
+                            return Err(PyValueError::new_err("token_transform must be callable"))
+                        }
+                        "token_transform" => {
+                            self.token_transform = Some(Arc::new(value.clone().unbind()))
+                        }
+                        "return_doc_lengths" => self.return_doc_lengths = value.extract()?,
+                        "return_seq_spans" => self.return_seq_spans = value.extract()?,
+                        "return_global_position_ids" => {
+                            self.return_global_position_ids = value.extract()?
+                        }
+                        "as_arrow" => self.as_arrow = value.extract()?,
+                        "row_major" => self.row_major = value.extract()?,
+                        "separator_id" => self.separator_id = value.extract()?,
+                        "separator_position_id" => {
+                            self.separator_position_id =
+                                value.extract::<String>()?.parse().map_err(|_| {
+                                    PyValueError::new_err(
+                                        "Invalid separator_position_id. Use 'continue' or 'reset'.",
+                                    )
+                                })?
+                        }
+                        "zero_length" => {
+                            self.zero_length = value.extract::<String>()?.parse().map_err(|_| {
+                                PyValueError::new_err(
+                                    "Invalid zero_length. Use 'drop', 'keep', or 'error'.",
+                                )
+                            })?
+                        }
+                        "position_id_dtype" => {
+                            let dtype = value.extract::<Option<String>>()?;
+                            self.position_id_dtype =
+                                dtype.map(|dtype| dtype.parse()).transpose().map_err(|_| {
+                                    PyValueError::new_err(
+                                        "Invalid position_id_dtype. Use 'int32' or 'int64'.",
+                                    )
+                                })?
+                        }
+                        "token_dtype" => {
+                            let dtype = value.extract::<Option<String>>()?;
+                            self.token_dtype =
+                                dtype.map(|dtype| dtype.parse()).transpose().map_err(|_| {
+                                    PyValueError::new_err(
+                                        "Invalid token_dtype. Use 'uint8' or 'uint16'.",
+                                    )
+                                })?
+                        }
+                        "verify" => self.verify = value.extract()?,
+                        "no_truncation" => self.no_truncation = value.extract()?,
+                        "seed" => self.seed = value.extract()?,
+                        "target_efficiency" => self.target_efficiency = value.extract()?,
+                        "time_budget_ms" => self.time_budget_ms = value.extract()?,
+                        "length_key" => self.length_key = value.extract()?,
+                        "length_field" => self.length_field = value.extract()?,
+                        "spill_dir" => self.spill_dir = value.extract()?,
+                        "spill_threshold" => self.spill_threshold = value.extract()?,
+                        "position_mode" => {
+                            self.position_mode =
+                                value.extract::<String>()?.parse().map_err(|_| {
+                                    PyValueError::new_err(
+                                        "Invalid position_mode. Use 'reset' or 'continuous'.",
+                                    )
+                                })?
+                        }
+                        "pad_position_id" => self.pad_position_id = value.extract()?,
+                        "batch_size" => self.batch_size = value.extract()?,
+                        "truncation_side" => {
+                            self.truncation_side =
+                                value.extract::<String>()?.parse().map_err(|_| {
+                                    PyValueError::new_err(
+                                        "Invalid truncation_side. Use 'right' or 'left'.",
+                                    )
+                                })?
+                        }
+                        "last_batch" => {
+                            self.last_batch = value.extract::<String>()?.parse().map_err(|_| {
+                                PyValueError::new_err(
+                                    "Invalid last_batch. Use 'keep', 'pad', or 'drop'.",
+                                )
+                            })?
+                        }
+                        "reserve_slots" => self.reserve_slots = value.extract()?,
+                        "shared_prefix" => self.shared_prefix = value.extract()?,
+                        "pad_to_multiple_of" => self.pad_to_multiple_of = value.extract()?,
+                        "pad_to_longest" => self.pad_to_longest = value.extract()?,
+                        "forbid_padding" => self.forbid_padding = value.extract()?,
+                        "sort_bins_by" => {
+                            self.sort_bins_by =
+                                value.extract::<String>()?.parse().map_err(|_| {
+                                    PyValueError::new_err(
+                                    "Invalid sort_bins_by. Use 'fill_desc', 'fill_asc', or 'none'.",
+                                )
+                                })?
+                        }
+                        _ => continue,
+                    }
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn build(self) -> PyResult<PackOptions> {
+        if self.pad_to_multiple_of == Some(0) {
+            return Err(PyValueError::new_err(
+                "pad_to_multiple_of must be greater than 0",
+            ));
+        }
+        if self.spill_threshold == 0 {
+            return Err(PyValueError::new_err(
+                "spill_threshold must be greater than 0",
+            ));
+        }
+        if self.length_key.is_some() && self.length_field.is_some() {
+            return Err(PyValueError::new_err(
+                "length_key cannot be combined with length_field",
+            ));
+        }
+        if self.pad_to_longest && self.pad_to_multiple_of.is_some() {
+            return Err(PyValueError::new_err(
+                "pad_to_longest cannot be combined with pad_to_multiple_of",
+            ));
+        }
+        if self.verify && self.min_tokens.is_some() {
+            return Err(PyValueError::new_err(
+                "verify cannot be used with min_tokens, the residual bins are dropped",
+            ));
+        }
+        if self.batch_size == Some(0) {
+            return Err(PyValueError::new_err("batch_size must be greater than 0"));
+        }
+        if self.verify && self.batch_size.is_some() && self.last_batch == LastBatch::Drop {
+            return Err(PyValueError::new_err(
+                "verify cannot be used with last_batch='drop', the last rows are dropped",
+            ));
+        }
+        if self
+            .target_efficiency
+            .is_some_and(|target| !(target > 0.0 && target <= 1.0))
+        {
+            return Err(PyValueError::new_err("target_efficiency must be in (0, 1]"));
+        }
+        if self
+            .solo_threshold
+            .is_some_and(|threshold| !(threshold > 0.0 && threshold <= 1.0))
+        {
+            return Err(PyValueError::new_err("solo_threshold must be in (0, 1]"));
+        }
+        // both pack without create_packing_strategy
+        if self.solo_threshold.is_some()
+            && (self.target_efficiency.is_some() || self.time_budget_ms.is_some())
+        {
+            return Err(PyValueError::new_err(
+                "solo_threshold cannot be combined with target_efficiency or time_budget_ms",
+            ));
+        }
+        if self.dedup && self.histogram.is_some() {
+            return Err(PyValueError::new_err(
+                "dedup cannot be used with pack_from_histogram, the histogram counts the \
+                 duplicates",
+            ));
+        }
+        if self.output_path.is_some() && self.token_dtype.is_some() {
+            return Err(PyValueError::new_err(
+                "output_path writes the token id type, token_dtype is not supported",
+            ));
+        }
+        if self.deterministic && self.time_budget_ms.is_some() {
+            return Err(PyValueError::new_err(
+                "deterministic cannot be used with time_budget_ms, the packing depends on the \
+                 clock",
+            ));
+        }
+        if self.verify && self.time_budget_ms.is_some() {
+            return Err(PyValueError::new_err(
+                "verify cannot be used with time_budget_ms, the unplaced sequences are dropped",
+            ));
+        }
+        Ok(PackOptions {
+            sort_key: self.sort_key,
+            shuffle: self.shuffle,
+            min_seqs_per_bin: self.min_seqs_per_bin,
+            solo_threshold: self.solo_threshold,
+            return_stats: self.return_stats,
+            return_timing: self.return_timing,
+            return_permutation: self.return_permutation,
+            return_doc_lengths: self.return_doc_lengths,
+            return_seq_spans: self.return_seq_spans,
+            return_global_position_ids: self.return_global_position_ids,
+            as_arrow: self.as_arrow,
+            row_major: self.row_major,
+            sort_bins_by: self.sort_bins_by,
+            pad_to_multiple_of: self.pad_to_multiple_of,
+            pad_to_longest: self.pad_to_longest,
+            forbid_padding: self.forbid_padding,
+            min_tokens: self.min_tokens,
+            assume_sorted: self.assume_sorted,
+            shared_prefix: self.shared_prefix,
+            reserve_slots: self.reserve_slots,
+            verify: self.verify,
+            no_truncation: self.no_truncation,
+            separator_id: self.separator_id,
+            separator_position_id: self.separator_position_id,
+            histogram: self.histogram,
+            zero_length: self.zero_length,
+            position_id_dtype: self.position_id_dtype,
+            seed: match self.deterministic {
+                true => self.seed.or(Some(DETERMINISTIC_SEED)),
+                false => self.seed,
+            },
+            target_efficiency: self.target_efficiency,
+            batch_size: self.batch_size,
+            last_batch: self.last_batch,
+            truncation_side: self.truncation_side,
+            token_dtype: self.token_dtype,
+            time_budget_ms: self.time_budget_ms,
+            length_key: self.length_key,
+            length_field: self.length_field,
+            spill_dir: self.spill_dir,
+            spill_threshold: self.spill_threshold,
+            position_mode: self.position_mode,
+            pad_position_id: self.pad_position_id,
+            return_meta: self.return_meta,
+            return_schema: self.return_schema,
+            dedup: self.dedup,
+            deterministic: self.deterministic,
+            output_path: self.output_path,
+            token_transform: self.token_transform,
+        })
+    }
+}
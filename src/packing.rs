@@ -1,16 +1,132 @@
+use crate::options::LastBatch;
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashSet};
+use std::time::Instant;
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PackingAlgo {
     FirstFit,
     FirstFitShuffle,
     FirstFitDecreasing,
+    // first fit without any shuffling, sequences of the same length are used in input order
+    Stable,
+    // few distinct bin shapes, at the cost of some padding
+    MinShapes,
+    // first fit, but a bin that cannot fit an item is closed for good
+    StrictFirstFit,
+    // sorted longest first, only the last bin is open. Low memory, for streaming sorted data
+    NextFitDecreasing,
+    // a fixed number of bins, each sequence goes into the emptiest bin, longest first
+    Balanced(usize),
+    // sorted longest first, each sequence goes into the fullest bin it fits in
+    BestFitDecreasing,
+    // first fit decreasing without any shuffling, sequences of the same length are used
+    // in input order
+    StableDecreasing,
+    // first fit decreasing, but each sequence goes into the bin where it adds the least
+    // attention across documents instead of the first bin it fits in
+    FirstFitDecreasingAttention,
+    // every sequence in a bin of its own, a baseline for testing the fill
+    NoPack,
+    // bins of a fixed number of sequences regardless of their length, longest first so
+    // sequences of similar length share a bin
+    SeqsPerBin(usize),
+    // first fit after a shuffle weighted by length^bias, between first_fit_shuffle (0) and
+    // first_fit_decreasing (a large bias)
+    FirstFitWeighted(f64),
+    // the bins come from the callable registered with register_packing_algorithm
+    Custom,
 }
 
 impl PackingAlgo {
-    pub fn pack(&self, seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    pub fn pack(
+        &self,
+        seqlens: Vec<usize>,
+        pack_size: usize,
+        seed: Option<u64>,
+    ) -> Vec<Vec<usize>> {
         match self {
             PackingAlgo::FirstFit => first_fit(seqlens, pack_size),
-            PackingAlgo::FirstFitShuffle => first_fit_shuffle(seqlens, pack_size),
+            PackingAlgo::FirstFitShuffle => first_fit_shuffle(seqlens, pack_size, seed),
             PackingAlgo::FirstFitDecreasing => first_fit_decreasing(seqlens, pack_size),
+            PackingAlgo::FirstFitDecreasingAttention => {
+                first_fit_decreasing_attention(seqlens, pack_size)
+            }
+            PackingAlgo::Stable => first_fit(seqlens, pack_size),
+            PackingAlgo::MinShapes => min_shapes(seqlens, pack_size),
+            PackingAlgo::StrictFirstFit => strict_first_fit(seqlens, pack_size),
+            PackingAlgo::NextFitDecreasing => next_fit_decreasing(seqlens, pack_size),
+            PackingAlgo::Balanced(num_bins) => balanced(seqlens, *num_bins),
+            PackingAlgo::BestFitDecreasing => best_fit_decreasing(seqlens, pack_size),
+            PackingAlgo::StableDecreasing => first_fit_decreasing(seqlens, pack_size),
+            PackingAlgo::NoPack => seqlens.into_iter().map(|s| vec![s]).collect(),
+            PackingAlgo::SeqsPerBin(seqs_per_bin) => seqs_per_bin_groups(seqlens, *seqs_per_bin),
+            PackingAlgo::FirstFitWeighted(bias) => {
+                first_fit(weighted_shuffle(seqlens, *bias, seed), pack_size)
+            }
+            // calling python can fail, so custom packings go through custom::pack
+            PackingAlgo::Custom => unreachable!("Expected custom packings to use custom::pack"),
+        }
+    }
+
+    // Same as pack, but the first fit algorithms stop opening bins after the deadline.
+    // Returns the bins and the lengths of the sequences that were not placed
+    pub fn pack_until(
+        &self,
+        seqlens: Vec<usize>,
+        pack_size: usize,
+        seed: Option<u64>,
+        deadline: Instant,
+    ) -> (Vec<Vec<usize>>, Vec<usize>) {
+        let mut seqlens = seqlens;
+        match self {
+            PackingAlgo::FirstFit | PackingAlgo::Stable => {}
+            PackingAlgo::FirstFitShuffle => seqlens.shuffle(&mut rng(seed)),
+            PackingAlgo::FirstFitWeighted(bias) => seqlens = weighted_shuffle(seqlens, *bias, seed),
+            PackingAlgo::FirstFitDecreasing | PackingAlgo::StableDecreasing => {
+                seqlens.sort_by(|a, b| b.cmp(a))
+            }
+            PackingAlgo::FirstFitDecreasingAttention => {
+                seqlens.sort_by(|a, b| b.cmp(a));
+                return first_fit_until(seqlens, pack_size, Some(deadline), Fit::LeastAttention);
+            }
+            _ => return (self.pack(seqlens, pack_size, seed), Vec::new()),
+        }
+        first_fit_until(seqlens, pack_size, Some(deadline), Fit::First)
+    }
+
+    // The algorithms that do not look at the pack size can give a bin over it, which would
+    // lose tokens when it is filled. Returns why the bins do not fit
+    pub fn check_fit(&self, assignments: &[Vec<usize>], pack_size: usize) -> Result<(), String> {
+        let overfull = assignments
+            .iter()
+            .map(|bin| bin.iter().sum::<usize>())
+            .enumerate()
+            .find(|&(_, len)| len > pack_size);
+        match (self, overfull) {
+            (PackingAlgo::Balanced(num_bins), Some((index, len))) => Err(format!(
+                "balanced:{num_bins} cannot fit the sequences, bin {index} holds {len} tokens, \
+                 more than the pack size {pack_size}. Use more bins"
+            )),
+            (PackingAlgo::SeqsPerBin(seqs_per_bin), Some((index, len))) => Err(format!(
+                "seqs_per_bin:{seqs_per_bin} gives bin {index} with {len} tokens, more than \
+                 the pack size {pack_size}. Use fewer sequences per bin"
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // Whether sequences of the same length should be shuffled before filling. Without a
+    // seed, first fit decreasing breaks the ties between equal lengths by input order so
+    // its output is the same on every run
+    pub fn shuffles_buckets(&self, seed: Option<u64>) -> bool {
+        match self {
+            PackingAlgo::Stable | PackingAlgo::StableDecreasing | PackingAlgo::NoPack => false,
+            PackingAlgo::FirstFitDecreasing | PackingAlgo::FirstFitDecreasingAttention => {
+                seed.is_some()
+            }
+            _ => true,
         }
     }
 }
@@ -18,29 +134,229 @@ impl std::str::FromStr for PackingAlgo {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the number of bins is part of the name, e.g. "balanced:8"
+        if let Some(num_bins) = s.to_lowercase().strip_prefix("balanced:") {
+            return match num_bins.parse() {
+                Ok(num_bins) if num_bins > 0 => Ok(PackingAlgo::Balanced(num_bins)),
+                _ => Err("Invalid number of bins for balanced, use e.g. 'balanced:8'"),
+            };
+        }
+        if let Some(seqs_per_bin) = s.to_lowercase().strip_prefix("seqs_per_bin:") {
+            return match seqs_per_bin.parse() {
+                Ok(seqs_per_bin) if seqs_per_bin > 0 => Ok(PackingAlgo::SeqsPerBin(seqs_per_bin)),
+                _ => Err("Invalid number of sequences for seqs_per_bin, use e.g. 'seqs_per_bin:4'"),
+            };
+        }
+        if let Some(bias) = s.to_lowercase().strip_prefix("first_fit_weighted:") {
+            return match bias.parse::<f64>() {
+                Ok(bias) if bias >= 0.0 && bias.is_finite() => {
+                    Ok(PackingAlgo::FirstFitWeighted(bias))
+                }
+                _ => Err("Invalid bias for first_fit_weighted, use e.g. 'first_fit_weighted:1.0'"),
+            };
+        }
         match s.to_lowercase().as_str() {
             "first_fit" => Ok(PackingAlgo::FirstFit),
             "first_fit_shuffle" => Ok(PackingAlgo::FirstFitShuffle),
             "first_fit_decreasing" => Ok(PackingAlgo::FirstFitDecreasing),
+            "first_fit_decreasing_attention" => Ok(PackingAlgo::FirstFitDecreasingAttention),
+            "stable" => Ok(PackingAlgo::Stable),
+            "min_shapes" => Ok(PackingAlgo::MinShapes),
+            "strict_first_fit" => Ok(PackingAlgo::StrictFirstFit),
+            "next_fit_decreasing" => Ok(PackingAlgo::NextFitDecreasing),
+            "best_fit_decreasing" => Ok(PackingAlgo::BestFitDecreasing),
+            "stable_decreasing" => Ok(PackingAlgo::StableDecreasing),
+            "none" => Ok(PackingAlgo::NoPack),
+            "custom" => Ok(PackingAlgo::Custom),
             _ => Err("Invalid packing algorithm"),
         }
     }
 }
 
+// Order of the packed bins in the output
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BinOrder {
+    #[default]
+    None,
+    FillDesc,
+    FillAsc,
+}
+
+impl std::str::FromStr for BinOrder {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(BinOrder::None),
+            "fill_desc" => Ok(BinOrder::FillDesc),
+            "fill_asc" => Ok(BinOrder::FillAsc),
+            _ => Err("Invalid bin order"),
+        }
+    }
+}
+
+// Sort the bins by their total length, bins with the same total keep their order
+pub fn sort_bins(assignments: &mut [Vec<usize>], order: BinOrder) {
+    let fill = |bin: &Vec<usize>| bin.iter().sum::<usize>();
+    match order {
+        BinOrder::None => {}
+        BinOrder::FillDesc => assignments.sort_by_key(|bin| std::cmp::Reverse(fill(bin))),
+        BinOrder::FillAsc => assignments.sort_by_key(fill),
+    }
+}
+
+// Makes the number of bins a multiple of `batch_size`, by adding empty bins (rows of only
+// padding) or by dropping the bins of the last, incomplete batch
+pub fn fit_to_batches(assignments: &mut Vec<Vec<usize>>, batch_size: usize, last_batch: LastBatch) {
+    let missing = assignments.len().next_multiple_of(batch_size) - assignments.len();
+    match last_batch {
+        LastBatch::Keep => {}
+        LastBatch::Pad => assignments.resize(assignments.len() + missing, Vec::new()),
+        LastBatch::Drop => assignments.truncate(assignments.len() / batch_size * batch_size),
+    }
+}
+
+// Sum of the squared sequence lengths of each bin. Attention within each sequence of a
+// bin costs about this much, so it compares the compute of different packings
+pub fn attention_cost(assignments: &[Vec<usize>]) -> Vec<usize> {
+    assignments
+        .iter()
+        .map(|bin| bin.iter().map(|s| s * s).sum())
+        .collect()
+}
+
+// Fraction of the packed tokens that are not padding, 0 without any bins
+pub fn efficiency(assignments: &[Vec<usize>], pack_size: usize) -> f64 {
+    if assignments.is_empty() {
+        return 0.0;
+    }
+    let total_tokens = assignments.iter().flatten().sum::<usize>();
+    total_tokens as f64 / (assignments.len() * pack_size) as f64
+}
+
+// Splits off the bins holding fewer than `min_tokens` tokens. The pack size is already
+// the ceiling, so the kept bins hold between `min_tokens` and pack size tokens.
+// Returns the kept bins and the residual bins, both in their original order
+pub fn split_residual_bins(
+    assignments: Vec<Vec<usize>>,
+    min_tokens: usize,
+) -> (Vec<Vec<usize>>, Vec<Vec<usize>>) {
+    assignments
+        .into_iter()
+        .partition(|bin| bin.iter().sum::<usize>() >= min_tokens)
+}
+
+// Number of distinct bin shapes, a shape is the lengths of a bin in order
+pub fn count_shapes(assignments: &[Vec<usize>]) -> usize {
+    assignments.iter().collect::<HashSet<_>>().len()
+}
+
+// Empty bins would become empty rows after filling, the remaining bins keep their order
+pub fn drop_empty_bins(assignments: &mut Vec<Vec<usize>>) {
+    assignments.retain(|bin| !bin.is_empty());
+}
+
 fn first_fit(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    first_fit_until(seqlens, pack_size, None, Fit::First).0
+}
+
+// Which of the open bins that fit a sequence gets it
+#[derive(Clone, Copy)]
+enum Fit {
+    // the first one
+    First,
+    // the emptiest one. Every token of a row attends to the tokens of the other sequences
+    // before it, so a sequence of length s in a row filled to f adds (f + s)^2 - f^2 =
+    // 2fs + s^2 to the squared row length, the least in the emptiest bin. Ties go to the
+    // first bin
+    LeastAttention,
+}
+
+const DEADLINE_CHECK_INTERVAL: usize = 1024;
+
+// First fit, but once the deadline has passed no more bins are opened. Sequences that do
+// not fit into an open bin are returned instead. The clock is only read every
+// DEADLINE_CHECK_INTERVAL sequences, the first ones are always packed
+fn first_fit_until(
+    seqlens: Vec<usize>,
+    pack_size: usize,
+    deadline: Option<Instant>,
+    fit: Fit,
+) -> (Vec<Vec<usize>>, Vec<usize>) {
     let mut res: Vec<Vec<usize>> = Vec::new(); // Holds the packed bins
     let mut sum_of_bin: Vec<usize> = Vec::new(); // Holds the sum of each bin
-    'outer: for s in seqlens {
-        for i in 0..res.len() {
-            if sum_of_bin[i] + s <= pack_size {
+                                                 // indices of the bins that are not full yet, in order. Full bins can only take
+                                                 // sequences of length 0, which always go to the first bin, so they are never scanned
+    let mut open: Vec<usize> = Vec::new();
+    let mut unplaced = Vec::new();
+    let mut expired = false;
+    for (n, s) in seqlens.into_iter().enumerate() {
+        if let Some(deadline) = deadline {
+            if !expired && n > 0 && n % DEADLINE_CHECK_INTERVAL == 0 {
+                expired = Instant::now() >= deadline;
+            }
+        }
+        if s == 0 && !res.is_empty() {
+            res[0].push(s);
+            continue;
+        }
+        let fits = |&(_, &i): &(usize, &usize)| sum_of_bin[i] + s <= pack_size;
+        let pos = match fit {
+            Fit::First => open.iter().enumerate().find(fits),
+            Fit::LeastAttention => open
+                .iter()
+                .enumerate()
+                .filter(fits)
+                .min_by_key(|&(_, &i)| sum_of_bin[i]),
+        };
+        match pos.map(|(pos, _)| pos) {
+            Some(pos) => {
+                let i = open[pos];
+                res[i].push(s);
+                sum_of_bin[i] += s;
+                if sum_of_bin[i] == pack_size {
+                    open.remove(pos);
+                }
+            }
+            None if expired => unplaced.push(s),
+            // If no bin fits, create a new one
+            None => {
+                if s < pack_size {
+                    open.push(res.len());
+                }
+                res.push(vec![s]);
+                sum_of_bin.push(s);
+            }
+        }
+    }
+    (res, unplaced)
+}
+
+// Bins that are skipped because the current item does not fit are never reconsidered,
+// even if a later, smaller item would fit. Matches reference implementations that only
+// look at the open bins, useful for debugging
+fn strict_first_fit(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    let mut res: Vec<Vec<usize>> = Vec::new();
+    let mut sum_of_bin: Vec<usize> = Vec::new();
+    // indices of the bins that are still open, in the order they were created
+    let mut open: Vec<usize> = Vec::new();
+    for s in seqlens {
+        let fits = open.iter().position(|&i| sum_of_bin[i] + s <= pack_size);
+        match fits {
+            Some(pos) => {
+                // every open bin before the one that fits is closed
+                let i = open[pos];
+                open.drain(..pos);
                 res[i].push(s);
                 sum_of_bin[i] += s;
-                continue 'outer;
+            }
+            None => {
+                open.clear();
+                open.push(res.len());
+                res.push(vec![s]);
+                sum_of_bin.push(s);
             }
         }
-        // If no bin fits, create a new one
-        res.push(vec![s]);
-        sum_of_bin.push(s);
     }
     res
 }
@@ -50,14 +366,361 @@ fn first_fit_decreasing(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>
     seqlens.sort_by(|a, b| b.cmp(a));
     first_fit(seqlens, pack_size)
 }
+
+fn first_fit_decreasing_attention(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    let mut seqlens = seqlens;
+    seqlens.sort_by(|a, b| b.cmp(a));
+    first_fit_until(seqlens, pack_size, None, Fit::LeastAttention).0
+}
+
+// The fullest bin that still fits the sequence. Bins are scanned by index and ties go to
+// the lowest index, so the result only depends on the lengths
+fn best_fit_decreasing(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    let mut seqlens = seqlens;
+    seqlens.sort_by(|a, b| b.cmp(a));
+    let mut res: Vec<Vec<usize>> = Vec::new();
+    let mut sum_of_bin: Vec<usize> = Vec::new();
+    for s in seqlens {
+        let best = (0..res.len())
+            .filter(|&i| sum_of_bin[i] + s <= pack_size)
+            .max_by_key(|&i| (sum_of_bin[i], std::cmp::Reverse(i)));
+        match best {
+            Some(i) => {
+                res[i].push(s);
+                sum_of_bin[i] += s;
+            }
+            None => {
+                res.push(vec![s]);
+                sum_of_bin.push(s);
+            }
+        }
+    }
+    res
+}
+
+// Only the last bin is open, a sequence that does not fit starts a new bin
+fn next_fit_decreasing(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    let mut seqlens = seqlens;
+    seqlens.sort_by(|a, b| b.cmp(a));
+    let mut res: Vec<Vec<usize>> = Vec::new();
+    let mut open_sum = 0;
+    for s in seqlens {
+        match res.last_mut() {
+            Some(bin) if open_sum + s <= pack_size => {
+                bin.push(s);
+                open_sum += s;
+            }
+            _ => {
+                res.push(vec![s]);
+                open_sum = s;
+            }
+        }
+    }
+    res
+}
+
+// Longest processing time first: sorted longest first, every sequence goes into the bin
+// with the fewest tokens (the first one on ties). The number of bins is fixed, so a bin
+// can go over the pack size when the sequences do not fit, check_fit rejects it. The
+// largest bin is at most 4/3 of the best possible largest bin
+fn balanced(seqlens: Vec<usize>, num_bins: usize) -> Vec<Vec<usize>> {
+    let mut seqlens = seqlens;
+    seqlens.sort_by(|a, b| b.cmp(a));
+    let mut res: Vec<Vec<usize>> = vec![Vec::new(); num_bins];
+    let mut sum_of_bin: Vec<usize> = vec![0; num_bins];
+    for s in seqlens {
+        let emptiest = (0..num_bins).min_by_key(|&i| sum_of_bin[i]).unwrap();
+        res[emptiest].push(s);
+        sum_of_bin[emptiest] += s;
+    }
+    res
+}
+
+// Groups of `seqs_per_bin` sequences, sorted longest first. Only the last bin can hold
+// fewer. The pack size is not looked at, check_fit rejects a bin over it
+fn seqs_per_bin_groups(seqlens: Vec<usize>, seqs_per_bin: usize) -> Vec<Vec<usize>> {
+    let mut seqlens = seqlens;
+    seqlens.sort_by(|a, b| b.cmp(a));
+    seqlens
+        .chunks(seqs_per_bin)
+        .map(<[usize]>::to_vec)
+        .collect()
+}
+
+// Sequences of the same length are packed into bins holding only that length, so every
+// length adds a single shape. The leftovers that cannot fill such a bin use first fit decreasing
+fn min_shapes(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+    let mut counts: BTreeMap<usize, usize> = BTreeMap::new();
+    for s in seqlens {
+        *counts.entry(s).or_default() += 1;
+    }
+    let mut res: Vec<Vec<usize>> = Vec::new();
+    let mut leftovers: Vec<usize> = Vec::new();
+    for (&s, &count) in counts.iter().rev() {
+        if s == 0 {
+            leftovers.extend(std::iter::repeat_n(s, count));
+            continue;
+        }
+        let per_bin = pack_size / s;
+        res.extend(std::iter::repeat_n(vec![s; per_bin], count / per_bin));
+        leftovers.extend(std::iter::repeat_n(s, count % per_bin));
+    }
+    res.extend(first_fit_decreasing(leftovers, pack_size));
+    res
+}
+
+// The rng for every shuffle. A seeded ChaCha8 gives the same stream on every platform
+// and rand version, without a seed the thread rng is used
+pub fn rng(seed: Option<u64>) -> Box<dyn RngCore> {
+    match seed {
+        Some(seed) => Box::new(ChaCha8Rng::seed_from_u64(seed)),
+        None => Box::new(rand::rng()),
+    }
+}
+
+// The rng for the bucket of one length, each bucket is shuffled on its own (possibly in
+// parallel). With a seed every bucket gets its own ChaCha8 stream, so the shuffle does not
+// depend on the order the buckets are processed in
+pub fn bucket_rng(seed: Option<u64>, seq_len: usize) -> Box<dyn RngCore> {
+    match seed {
+        Some(seed) => {
+            let mut rng = ChaCha8Rng::seed_from_u64(seed);
+            rng.set_stream(seq_len as u64);
+            Box::new(rng)
+        }
+        None => Box::new(rand::rng()),
+    }
+}
+
 // Shuffle won't be tested
-fn first_fit_shuffle(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+fn first_fit_shuffle(seqlens: Vec<usize>, pack_size: usize, seed: Option<u64>) -> Vec<Vec<usize>> {
     let mut seqlens = seqlens;
-    let mut rng = rand::rng();
+    let mut rng = rng(seed);
     seqlens.shuffle(&mut rng);
     first_fit(seqlens, pack_size)
 }
 
+// A random order where longer sequences tend to come first. Every sequence gets the key
+// ln(u) / len^bias for a uniform u (Efraimidis-Spirakis), sorting the keys in decreasing
+// order draws the sequences one by one with a probability proportional to len^bias
+fn weighted_shuffle(seqlens: Vec<usize>, bias: f64, seed: Option<u64>) -> Vec<usize> {
+    let mut rng = rng(seed);
+    let mut keyed = seqlens
+        .into_iter()
+        .map(|s| {
+            let u = 1.0 - rng.random::<f64>();
+            (u.ln() / (s.max(1) as f64).powf(bias), s)
+        })
+        .collect::<Vec<(f64, usize)>>();
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, s)| s).collect()
+}
+
+// Merges bins holding fewer than `min_seqs` sequences into other bins when the combined
+// length still fits, preferring other under-count bins. Bins that cannot be brought up to
+// `min_seqs` are kept as they are, see small_bins
+// The small bins are walked once in order. The bins are kept ordered by (sum, index), so
+// the fullest bin that still fits is found in O(log n) instead of scanning every bin
+pub fn merge_small_bins(
+    assignments: Vec<Vec<usize>>,
+    pack_size: usize,
+    min_seqs: usize,
+) -> Vec<Vec<usize>> {
+    let mut bins: Vec<Option<Vec<usize>>> = assignments.into_iter().map(Some).collect();
+    let mut sums: Vec<usize> = bins.iter().flatten().map(|bin| bin.iter().sum()).collect();
+    // the bins that can take a merge, the small ones are also in `small`
+    let mut all: BTreeSet<(usize, usize)> = sums.iter().copied().zip(0..).collect();
+    let mut small: BTreeSet<(usize, usize)> = bins
+        .iter()
+        .enumerate()
+        .filter(|(_, bin)| bin.as_ref().is_some_and(|bin| bin.len() < min_seqs))
+        .map(|(i, _)| (sums[i], i))
+        .collect();
+    // the fullest bin with at most `room` tokens
+    let fullest = |bins: &BTreeSet<(usize, usize)>, room: usize| {
+        bins.range(..=(room, usize::MAX))
+            .next_back()
+            .map(|&(_, j)| j)
+    };
+
+    for i in 0..bins.len() {
+        // the merged bin keeps the earlier position, which can be before `i`
+        let mut current = i;
+        while bins[current]
+            .as_ref()
+            .is_some_and(|bin| bin.len() < min_seqs)
+        {
+            all.remove(&(sums[current], current));
+            small.remove(&(sums[current], current));
+            let room = pack_size.saturating_sub(sums[current]);
+            let Some(j) = fullest(&small, room).or_else(|| fullest(&all, room)) else {
+                // nothing fits, other bins can still be merged into it
+                all.insert((sums[current], current));
+                small.insert((sums[current], current));
+                break;
+            };
+            all.remove(&(sums[j], j));
+            small.remove(&(sums[j], j));
+            let (keep, remove) = (current.min(j), current.max(j));
+            let bin = bins[remove].take().unwrap();
+            let merged = bins[keep].as_mut().unwrap();
+            merged.extend(bin);
+            sums[keep] += sums[remove];
+            all.insert((sums[keep], keep));
+            if merged.len() < min_seqs {
+                small.insert((sums[keep], keep));
+            }
+            current = keep;
+        }
+    }
+
+    bins.into_iter().flatten().collect()
+}
+
+// Indices of the bins holding fewer than `min_seqs` sequences. Empty bins are only padding
+// (see fit_to_batches), so they are left out
+pub fn small_bins(assignments: &[Vec<usize>], min_seqs: usize) -> Vec<usize> {
+    assignments
+        .iter()
+        .enumerate()
+        .filter(|(_, bin)| !bin.is_empty() && bin.len() < min_seqs)
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Combines the bins of two packings, e.g. of two shards packed separately. Full bins are
+// kept as they are, with `repack_tails` the sequences of the partially filled bins are
+// packed again with first fit decreasing, otherwise every bin is kept
+pub fn merge_packings(
+    a: Vec<Vec<usize>>,
+    b: Vec<Vec<usize>>,
+    pack_size: usize,
+    repack_tails: bool,
+) -> Vec<Vec<usize>> {
+    if !repack_tails {
+        return a.into_iter().chain(b).collect();
+    }
+    let (mut merged, tails): (Vec<Vec<usize>>, Vec<Vec<usize>>) = a
+        .into_iter()
+        .chain(b)
+        .partition(|bin| bin.iter().sum::<usize>() >= pack_size);
+    merged.extend(first_fit_decreasing(tails.concat(), pack_size));
+    merged
+}
+
+// Packs the sequences of the bins filled below `threshold` (a fraction of the pack size)
+// again with first fit decreasing, e.g. the sparse last bins of a packing. The other bins
+// are kept in order and the repacked bins go last. The tail is only replaced when it
+// needs fewer bins
+pub fn repack_tail(
+    assignments: Vec<Vec<usize>>,
+    pack_size: usize,
+    threshold: f64,
+) -> Vec<Vec<usize>> {
+    let (mut kept, tail): (Vec<Vec<usize>>, Vec<Vec<usize>>) = assignments
+        .into_iter()
+        .partition(|bin| bin.iter().sum::<usize>() as f64 >= threshold * pack_size as f64);
+    let repacked = first_fit_decreasing(tail.concat(), pack_size);
+    kept.extend(if repacked.len() < tail.len() {
+        repacked
+    } else {
+        tail
+    });
+    kept
+}
+
+// Checks that `after` holds the same sequence lengths as `before` and that no bin is over
+// the pack size, e.g. after moving sequences between bins
+pub fn validate_packing(
+    before: &[Vec<usize>],
+    after: &[Vec<usize>],
+    pack_size: usize,
+) -> Result<(), String> {
+    if let Some(bin) = after
+        .iter()
+        .find(|bin| bin.iter().sum::<usize>() > pack_size)
+    {
+        return Err(format!(
+            "The bin {bin:?} holds more than {pack_size} tokens"
+        ));
+    }
+    let sorted = |bins: &[Vec<usize>]| {
+        let mut lengths = bins.concat();
+        lengths.sort_unstable();
+        lengths
+    };
+    if sorted(before) != sorted(after) {
+        return Err("The packing does not hold the same sequences".to_string());
+    }
+    Ok(())
+}
+
+// The (length, count) runs of every bin in bin order, consecutive sequences of the same
+// length are one segment. The segments of a bin add up to its total length
+pub fn bin_segments(assignments: &[Vec<usize>]) -> Vec<Vec<(usize, usize)>> {
+    assignments
+        .iter()
+        .map(|bin| {
+            bin.chunk_by(|a, b| a == b)
+                .map(|run| (run[0], run.len()))
+                .collect()
+        })
+        .collect()
+}
+
+// The indices of the bins of every shard, whole bins spread so the shards hold about the
+// same number of tokens. Longest processing time first: from the most tokens to the
+// fewest, every bin goes to the shard with the fewest tokens so far (ties to the lowest
+// shard). The shards differ by at most the tokens of the largest bin
+pub fn shard_bins(assignments: &[Vec<usize>], num_shards: usize) -> Vec<Vec<usize>> {
+    let tokens = assignments
+        .iter()
+        .map(|bin| bin.iter().sum())
+        .collect::<Vec<usize>>();
+    let mut order = (0..assignments.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&bin| Reverse(tokens[bin]));
+    let mut shards = vec![Vec::new(); num_shards];
+    let mut totals = (0..num_shards)
+        .map(|shard| Reverse((0, shard)))
+        .collect::<BinaryHeap<_>>();
+    for bin in order {
+        let Some(Reverse((total, shard))) = totals.pop() else {
+            break;
+        };
+        shards[shard].push(bin);
+        totals.push(Reverse((total + tokens[bin], shard)));
+    }
+    for shard in &mut shards {
+        shard.sort();
+    }
+    shards
+}
+
+// The indices of the sequences of every chunk, e.g. to pack the chunks separately and
+// merge them. Cutting the input into runs can give one chunk the short sequences and
+// another the long ones, which pack worse than a mix. Instead the sequences are dealt out
+// in length order, the deal turning around at the last chunk (0, 1, 2, 2, 1, 0, ...), so
+// every chunk gets the same mix of lengths and about the same number of tokens. Within a
+// chunk the indices keep input order
+pub fn stratified_chunks(seq_lens: &[usize], num_chunks: usize) -> Vec<Vec<usize>> {
+    let mut order = (0..seq_lens.len()).collect::<Vec<usize>>();
+    order.sort_by_key(|&i| seq_lens[i]);
+    let mut chunks = vec![Vec::new(); num_chunks];
+    for (n, i) in order.into_iter().enumerate() {
+        let (round, pos) = (n / num_chunks, n % num_chunks);
+        let chunk = if round % 2 == 0 {
+            pos
+        } else {
+            num_chunks - 1 - pos
+        };
+        chunks[chunk].push(i);
+    }
+    for chunk in &mut chunks {
+        chunk.sort();
+    }
+    chunks
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -86,4 +749,385 @@ mod tests {
         assert_eq!(result[1], vec![4, 1]);
         assert_eq!(result[2], vec![3, 2]);
     }
+
+    #[test]
+    fn test_merge_small_bins() {
+        let assignments = vec![vec![3, 2], vec![4], vec![1, 1], vec![3], vec![6]];
+        let merged = merge_small_bins(assignments, 7, 2);
+        // the two singletons [4] and [3] are merged, [6] has nowhere to go
+        assert_eq!(merged, vec![vec![3, 2], vec![4, 3], vec![1, 1], vec![6]]);
+        assert_eq!(small_bins(&merged, 2), vec![3]);
+        // empty padding bins are not counted
+        assert_eq!(small_bins(&[vec![], vec![6]], 2), vec![1]);
+
+        // many small bins, every one is looked at once
+        let assignments = (0..100_000).map(|i| vec![i % 5 + 1]).collect::<Vec<_>>();
+        let merged = merge_small_bins(assignments, 10, 3);
+        assert!(small_bins(&merged, 3).iter().all(|&i| merged[i].len() < 3));
+        assert!(merged.iter().all(|bin| bin.iter().sum::<usize>() <= 10));
+        assert_eq!(merged.iter().map(Vec::len).sum::<usize>(), 100_000);
+    }
+
+    #[test]
+    fn test_drop_empty_bins() {
+        let mut assignments = vec![vec![3], vec![], vec![2, 1], vec![]];
+        drop_empty_bins(&mut assignments);
+        assert_eq!(assignments, vec![vec![3], vec![2, 1]]);
+    }
+
+    #[test]
+    fn test_min_tokens_floor() {
+        let assignments = first_fit(vec![5, 4, 3, 2, 1, 1], 6);
+        assert_eq!(assignments, vec![vec![5, 1], vec![4, 2], vec![3, 1]]);
+        let (kept, residual) = split_residual_bins(assignments, 5);
+        assert_eq!(kept, vec![vec![5, 1], vec![4, 2]]);
+        assert_eq!(residual, vec![vec![3, 1]]);
+    }
+
+    #[test]
+    fn test_min_tokens_ceiling() {
+        let pack_size = 10;
+        let seqlens = vec![9, 8, 7, 3, 2, 2, 1, 6, 5, 4];
+        let (kept, residual) = split_residual_bins(first_fit(seqlens, pack_size), 8);
+        for bin in &kept {
+            let total = bin.iter().sum::<usize>();
+            assert!((8..=pack_size).contains(&total));
+        }
+        assert!(residual.iter().all(|bin| bin.iter().sum::<usize>() < 8));
+        let placed = kept.iter().chain(&residual).flatten().sum::<usize>();
+        assert_eq!(placed, 47);
+    }
+
+    #[test]
+    fn test_min_shapes() {
+        // a varied distribution, every length shows up many times
+        let seqlens = (0..400)
+            .map(|i| [3, 5, 7, 11, 13][i % 5] + i % 3)
+            .collect::<Vec<_>>();
+        let total = seqlens.iter().sum::<usize>();
+        let pack_size = 32;
+        let first_fit_bins = first_fit(seqlens.clone(), pack_size);
+        let min_shapes_bins = min_shapes(seqlens, pack_size);
+
+        assert!(count_shapes(&min_shapes_bins) < count_shapes(&first_fit_bins));
+        assert_eq!(min_shapes_bins.iter().flatten().sum::<usize>(), total);
+        assert!(min_shapes_bins
+            .iter()
+            .all(|bin| bin.iter().sum::<usize>() <= pack_size));
+    }
+
+    #[test]
+    fn test_strict_first_fit() {
+        let seqlens = vec![4, 5, 2, 1];
+        // first fit reopens the bins for the small sequences
+        assert_eq!(first_fit(seqlens.clone(), 6), vec![vec![4, 2], vec![5, 1]]);
+        // the bins of 4 and 5 are closed once they cannot fit 5 and 2
+        let result = strict_first_fit(seqlens, 6);
+        assert_eq!(result, vec![vec![4], vec![5], vec![2, 1]]);
+    }
+
+    #[test]
+    fn test_next_fit_decreasing() {
+        let seqlens = vec![1, 4, 2, 5];
+        let result = next_fit_decreasing(seqlens.clone(), 6);
+        // a single open bin, so the bins are consecutive runs of the sorted lengths
+        assert_eq!(result, vec![vec![5], vec![4, 2], vec![1]]);
+        assert_eq!(result.concat(), vec![5, 4, 2, 1]);
+        // first fit decreasing goes back to the first bin for the 1
+        assert_eq!(
+            first_fit_decreasing(seqlens, 6),
+            vec![vec![5, 1], vec![4, 2]]
+        );
+    }
+
+    #[test]
+    fn test_best_fit_decreasing() {
+        let seqlens = vec![5, 4, 4, 1, 8];
+        // the 1 goes into the fullest bin instead of the first one with room
+        let result = best_fit_decreasing(seqlens.clone(), 10);
+        assert_eq!(result, vec![vec![8], vec![5, 4, 1], vec![4]]);
+        assert_eq!(
+            first_fit_decreasing(seqlens, 10),
+            vec![vec![8, 1], vec![5, 4], vec![4]]
+        );
+
+        // both bins have 4 tokens of room, the 2 always goes into the first one
+        for _ in 0..10 {
+            let result = best_fit_decreasing(vec![6, 2, 6, 3], 10);
+            assert_eq!(result, vec![vec![6, 3], vec![6, 2]]);
+        }
+        assert_eq!(
+            best_fit_decreasing(vec![6, 6, 2], 10),
+            vec![vec![6, 2], vec![6]]
+        );
+    }
+
+    #[test]
+    fn test_repack_tail() {
+        let assignments = vec![vec![8, 2], vec![9], vec![3], vec![2, 1], vec![4]];
+        let repacked = repack_tail(assignments.clone(), 10, 0.5);
+        // the three bins under 5 tokens fit into one
+        assert_eq!(repacked, vec![vec![8, 2], vec![9], vec![4, 3, 2, 1]]);
+        assert!(validate_packing(&assignments, &repacked, 10).is_ok());
+        // nothing to gain, the tail is kept as it is
+        assert_eq!(
+            repack_tail(vec![vec![6], vec![6]], 10, 1.0),
+            vec![vec![6], vec![6]]
+        );
+
+        assert!(validate_packing(&assignments, &[vec![8, 2, 9, 3, 2, 1, 4]], 10).is_err());
+        assert!(validate_packing(&assignments, &[vec![8, 2], vec![9]], 10).is_err());
+    }
+
+    #[test]
+    fn test_bin_segments() {
+        let assignments = vec![vec![4, 4, 1], vec![9], vec![3, 2, 3], vec![]];
+        let segments = bin_segments(&assignments);
+        assert_eq!(segments[0], vec![(4, 2), (1, 1)]);
+        assert_eq!(segments[2], vec![(3, 1), (2, 1), (3, 1)]);
+        assert_eq!(segments[3], vec![]);
+        for (bin, segments) in assignments.iter().zip(&segments) {
+            let total = segments
+                .iter()
+                .map(|(len, count)| len * count)
+                .sum::<usize>();
+            assert_eq!(total, bin.iter().sum::<usize>());
+        }
+    }
+
+    #[test]
+    fn test_first_fit_decreasing_attention() {
+        // a varied distribution, many short sequences and a few long ones
+        let seqlens = (0..500).map(|i| (i * 37 % 97) * (i % 5 + 1) % 400 + 1);
+        let seqlens = seqlens.collect::<Vec<usize>>();
+        let pack = |algorithm: PackingAlgo| algorithm.pack(seqlens.clone(), 512, None);
+        let attention = pack(PackingAlgo::FirstFitDecreasingAttention);
+        let ffd = pack(PackingAlgo::FirstFitDecreasing);
+        validate_packing(&[seqlens], &attention, 512).unwrap();
+        // the attention across the documents of a row is its squared length minus the
+        // attention within every document
+        let cross_attention = |bins: &[Vec<usize>]| {
+            let squared = bins
+                .iter()
+                .map(|bin| bin.iter().sum::<usize>().pow(2))
+                .sum::<usize>();
+            squared - attention_cost(bins).iter().sum::<usize>()
+        };
+        assert!(cross_attention(&attention) < cross_attention(&ffd));
+        // at the cost of a few more bins
+        assert!(attention.len() <= ffd.len() * 11 / 10);
+    }
+
+    #[test]
+    fn test_shard_bins() {
+        let assignments = (0..50)
+            .map(|i| vec![i * 7 % 13 + 1, i % 5 + 1, i * 3 % 4])
+            .collect::<Vec<Vec<usize>>>();
+        let tokens = |bin: &usize| assignments[*bin].iter().sum::<usize>();
+        let shards = shard_bins(&assignments, 4);
+        // every bin is in one shard
+        let mut bins = shards.concat();
+        bins.sort();
+        assert_eq!(bins, (0..50).collect::<Vec<_>>());
+        let totals = shards.iter().map(|shard| shard.iter().map(tokens).sum());
+        let totals = totals.collect::<Vec<usize>>();
+        let largest = (0..50).map(|bin| tokens(&bin)).max().unwrap();
+        assert!(totals.iter().max().unwrap() - totals.iter().min().unwrap() <= largest);
+        // more shards than bins leaves some empty
+        assert_eq!(
+            shard_bins(&assignments[..2], 3),
+            vec![vec![1], vec![0], vec![]]
+        );
+    }
+
+    #[test]
+    fn test_stratified_chunks() {
+        // sorted by length, cutting it into runs gives each chunk a narrow range of lengths
+        let mut seq_lens = (0..400).map(|i| i * 7919 % 300 + 1).collect::<Vec<usize>>();
+        seq_lens.sort();
+        let runs = (0..4)
+            .map(|c| (c * 100..(c + 1) * 100).collect())
+            .collect::<Vec<Vec<_>>>();
+        let stratified = stratified_chunks(&seq_lens, 4);
+        let mut all = stratified.concat();
+        all.sort();
+        assert_eq!(all, (0..400).collect::<Vec<_>>());
+
+        let efficiencies = |chunks: &[Vec<usize>]| {
+            chunks
+                .iter()
+                .map(|chunk| {
+                    let lens = chunk.iter().map(|&i| seq_lens[i]).collect::<Vec<_>>();
+                    efficiency(&PackingAlgo::FirstFitDecreasing.pack(lens, 512, None), 512)
+                })
+                .collect::<Vec<f64>>()
+        };
+        // (worst, best) efficiency of the chunks
+        let range = |chunks: &[Vec<usize>]| {
+            let efficiencies = efficiencies(chunks).into_iter();
+            efficiencies.fold((1.0, 0.0), |(min, max): (f64, f64), e| {
+                (min.min(e), max.max(e))
+            })
+        };
+        let (stratified, runs) = (range(&stratified), range(&runs));
+        // every chunk packs about as well as the others, and the worst one better
+        assert!(stratified.1 - stratified.0 < runs.1 - runs.0);
+        assert!(stratified.0 > runs.0);
+    }
+
+    #[test]
+    fn test_attention_cost() {
+        let assignments = vec![vec![4, 3, 1], vec![8], vec![]];
+        // 16 + 9 + 1, a single sequence of the same total costs 64
+        assert_eq!(attention_cost(&assignments), vec![26, 64, 0]);
+    }
+
+    #[test]
+    fn test_seqs_per_bin() {
+        let seqlens = vec![3, 7, 2, 5, 6, 4, 1, 8];
+        let algorithm: PackingAlgo = "seqs_per_bin:2".parse().unwrap();
+        let result = algorithm.pack(seqlens.clone(), 15, None);
+        assert!(result.iter().all(|bin| bin.len() == 2));
+        // similar lengths share a bin
+        assert_eq!(result, vec![vec![8, 7], vec![6, 5], vec![4, 3], vec![2, 1]]);
+        assert!(algorithm.check_fit(&result, 15).is_ok());
+        // every token fits its bin, none are lost when filling
+        let mut packed = result.concat();
+        packed.sort_unstable();
+        assert_eq!(packed, (1..=8).collect::<Vec<usize>>());
+        // 8 + 7 tokens do not fit a bin of 10
+        let result = algorithm.pack(seqlens, 10, None);
+        assert!(algorithm.check_fit(&result, 10).is_err());
+        assert!("seqs_per_bin:0".parse::<PackingAlgo>().is_err());
+    }
+
+    #[test]
+    fn test_weighted_shuffle() {
+        let seqlens = (0..100).map(|i| i % 10 + 1).collect::<Vec<usize>>();
+        // average position of the sequences of a length, over many seeds
+        let mean_position = |bias: f64, seq_len: usize| {
+            let positions = (0..200).flat_map(|seed| {
+                let order = weighted_shuffle(seqlens.clone(), bias, Some(seed));
+                order
+                    .into_iter()
+                    .enumerate()
+                    .filter(move |&(_, s)| s == seq_len)
+                    .map(|(i, _)| i)
+            });
+            let positions = positions.collect::<Vec<usize>>();
+            positions.iter().sum::<usize>() as f64 / positions.len() as f64
+        };
+        assert!(mean_position(1.0, 10) < mean_position(1.0, 5));
+        assert!(mean_position(1.0, 5) < mean_position(1.0, 1));
+        // a stronger bias moves the long sequences further to the front
+        assert!(mean_position(4.0, 10) < mean_position(1.0, 10));
+        // without a bias it is a plain shuffle, every length is in the middle on average
+        assert!((mean_position(0.0, 10) - 49.5).abs() < 5.0);
+
+        let seeded = |seed| weighted_shuffle(seqlens.clone(), 1.0, Some(seed));
+        assert_eq!(seeded(3), seeded(3));
+        assert!("first_fit_weighted:-1".parse::<PackingAlgo>().is_err());
+        assert_eq!(
+            "first_fit_weighted:2".parse::<PackingAlgo>(),
+            Ok(PackingAlgo::FirstFitWeighted(2.0))
+        );
+    }
+
+    #[test]
+    fn test_balanced() {
+        let seqlens = vec![3, 7, 2, 5, 6, 4];
+        let result = balanced(seqlens, 2);
+        assert_eq!(result, vec![vec![7, 4, 3], vec![6, 5, 2]]);
+        // the best split of the 27 tokens is 14 and 13
+        let max_fill = result
+            .iter()
+            .map(|bin| bin.iter().sum::<usize>())
+            .max()
+            .unwrap();
+        assert!(max_fill as f64 <= 14.0 * 4.0 / 3.0);
+        assert_eq!(max_fill, 14);
+        let algorithm = PackingAlgo::Balanced(2);
+        assert!(algorithm.check_fit(&result, 14).is_ok());
+        // 27 tokens do not fit 2 bins of 13
+        assert!(algorithm.check_fit(&result, 13).is_err());
+
+        assert!(matches!("balanced:4".parse(), Ok(PackingAlgo::Balanced(4))));
+        assert!("balanced:0".parse::<PackingAlgo>().is_err());
+    }
+
+    #[test]
+    fn test_merge_packings() {
+        let pack_size = 8;
+        let a = first_fit(vec![5, 6, 8], pack_size);
+        let b = first_fit(vec![3, 2, 8], pack_size);
+        let total = |bins: &[Vec<usize>]| bins.iter().flatten().sum::<usize>();
+
+        let kept = merge_packings(a.clone(), b.clone(), pack_size, false);
+        assert_eq!(kept.len(), a.len() + b.len());
+
+        let merged = merge_packings(a.clone(), b.clone(), pack_size, true);
+        assert_eq!(total(&merged), total(&a) + total(&b));
+        assert!(merged
+            .iter()
+            .all(|bin| bin.iter().sum::<usize>() <= pack_size));
+        // the full bins come first and are untouched
+        assert_eq!(merged[..2], [vec![8], vec![8]]);
+        assert!(merged.len() < kept.len());
+    }
+
+    // first fit scanning every bin, the reference for the pruned first_fit
+    fn first_fit_unpruned(seqlens: Vec<usize>, pack_size: usize) -> Vec<Vec<usize>> {
+        let mut res: Vec<Vec<usize>> = Vec::new();
+        let mut sum_of_bin: Vec<usize> = Vec::new();
+        'outer: for s in seqlens {
+            for i in 0..res.len() {
+                if sum_of_bin[i] + s <= pack_size {
+                    res[i].push(s);
+                    sum_of_bin[i] += s;
+                    continue 'outer;
+                }
+            }
+            res.push(vec![s]);
+            sum_of_bin.push(s);
+        }
+        res
+    }
+
+    // lengths that fill many bins exactly, with a few zero length sequences
+    fn tight_lengths(n: usize) -> Vec<usize> {
+        (0..n).map(|i| [4, 4, 8, 2, 6, 0, 3, 5][i % 8]).collect()
+    }
+
+    #[test]
+    fn test_first_fit_pruning() {
+        for pack_size in [1, 8, 10, 16] {
+            let seqlens = tight_lengths(1000)
+                .into_iter()
+                .map(|s| s.min(pack_size))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                first_fit(seqlens.clone(), pack_size),
+                first_fit_unpruned(seqlens, pack_size),
+                "pack size {pack_size}"
+            );
+        }
+        assert_eq!(first_fit(vec![0, 3, 0], 3), vec![vec![0, 3, 0]]);
+    }
+
+    // cargo test --release -- --ignored bench_first_fit --nocapture
+    #[test]
+    #[ignore]
+    fn bench_first_fit() {
+        let seqlens = tight_lengths(50_000);
+        let start = std::time::Instant::now();
+        let pruned = first_fit(seqlens.clone(), 8);
+        let pruned_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let unpruned = first_fit_unpruned(seqlens, 8);
+        println!(
+            "first_fit: {pruned_time:?} pruned, {:?} unpruned",
+            start.elapsed()
+        );
+        assert_eq!(pruned, unpruned);
+    }
 }
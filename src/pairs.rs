@@ -0,0 +1,179 @@
+use pyo3::exceptions::PyValueError;
+use pyo3::PyResult;
+use std::collections::HashMap;
+
+use crate::common::{Feature, Sequence, TokenId};
+
+// Rows of the dataset with the same `pair_ids` value (e.g. a prompt and its response) are
+// merged into a single example before packing, so they always share a bin and stay
+// adjacent in input order. Each member keeps its own position ids
+pub fn group_pairs(
+    dataset: HashMap<String, Vec<Feature>>,
+) -> PyResult<HashMap<String, Vec<Feature>>> {
+    let Some(pair_ids) = dataset.get("pair_ids") else {
+        return Ok(dataset);
+    };
+    // checked before anything is grouped
+    let pair_ids = pair_ids
+        .iter()
+        .map(|pair_id| match pair_id {
+            Feature::Int(pair_id) => Ok(*pair_id),
+            other => Err(PyValueError::new_err(format!(
+                "Expected key 'pair_ids' to be an int, got {other:?}"
+            ))),
+        })
+        .collect::<PyResult<Vec<i64>>>()?;
+    // the rows of every group, in the order the groups first appear
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of: HashMap<i64, usize> = HashMap::new();
+    for (row, pair_id) in pair_ids.iter().enumerate() {
+        let group = *group_of.entry(*pair_id).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(row);
+    }
+    if groups.len() == pair_ids.len() {
+        return Ok(dataset);
+    }
+    let seq_lens = dataset
+        .get("input_ids")
+        .expect("Expected key 'input_ids' in the dataset")
+        .iter()
+        .map(|seq| {
+            seq.as_tokens()
+                .expect("Expected key 'input_ids' to be a list of token ids")
+                .len()
+        })
+        .collect::<Vec<usize>>();
+
+    let mut grouped = dataset
+        .iter()
+        .map(|(key, values)| {
+            let merged = groups
+                .iter()
+                .map(|rows| merge_rows(rows.iter().map(|&row| (&values[row], seq_lens[row]))))
+                .collect();
+            (key.clone(), merged)
+        })
+        .collect::<HashMap<String, Vec<Feature>>>();
+    // without position_ids, the fill would number a pair as a single sequence
+    grouped
+        .entry("position_ids".to_string())
+        .or_insert_with(|| {
+            groups
+                .iter()
+                .map(|rows| {
+                    let positions = rows.iter().flat_map(|&row| 0..seq_lens[row] as TokenId);
+                    Feature::Tokens(positions.collect())
+                })
+                .collect()
+        });
+    Ok(grouped)
+}
+
+// The value of a merged example. Lists with one value per token (e.g. input_ids, labels or
+// loss_weight) are concatenated, anything else is taken from the first member
+fn merge_rows<'a>(mut members: impl Iterator<Item = (&'a Feature, usize)>) -> Feature {
+    let (first, first_len) = members.next().expect("Expected a group to have a row");
+    let mut merged = first.clone();
+    let mut per_token = is_per_token(first, first_len);
+    for (value, seq_len) in members {
+        per_token = per_token && is_per_token(value, seq_len);
+        merged = match (merged, value) {
+            (Feature::Tokens(mut merged), Feature::Tokens(values)) if per_token => {
+                merged.extend(values);
+                Feature::Tokens(merged)
+            }
+            // whole numbers are extracted as tokens, e.g. a loss_weight of [1, 1]
+            (Feature::Tokens(merged), Feature::Floats(values)) if per_token => {
+                Feature::Floats(as_floats(&merged).chain(values.iter().copied()).collect())
+            }
+            (Feature::Floats(mut merged), Feature::Tokens(values)) if per_token => {
+                merged.extend(as_floats(values));
+                Feature::Floats(merged)
+            }
+            (Feature::Floats(mut merged), Feature::Floats(values)) if per_token => {
+                merged.extend(values);
+                Feature::Floats(merged)
+            }
+            _ => first.clone(),
+        };
+    }
+    merged
+}
+
+fn is_per_token(value: &Feature, seq_len: usize) -> bool {
+    match value {
+        Feature::Tokens(values) => values.len() == seq_len,
+        Feature::Floats(values) => values.len() == seq_len,
+        _ => false,
+    }
+}
+
+fn as_floats(values: &Sequence) -> impl Iterator<Item = f32> + '_ {
+    values.iter().map(|&v| v as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_group_pairs() {
+        let dataset = HashMap::from([
+            (
+                "input_ids".to_string(),
+                vec![
+                    Feature::Tokens(vec![1, 1]),
+                    Feature::Tokens(vec![2]),
+                    Feature::Tokens(vec![3, 3, 3]),
+                ],
+            ),
+            (
+                "loss_weight".to_string(),
+                vec![
+                    Feature::Tokens(vec![0, 0]),
+                    Feature::Floats(vec![0.5]),
+                    Feature::Floats(vec![1.0, 1.0, 1.0]),
+                ],
+            ),
+            (
+                "pair_ids".to_string(),
+                vec![Feature::Int(4), Feature::Int(9), Feature::Int(4)],
+            ),
+        ]);
+        let grouped = group_pairs(dataset).unwrap();
+        assert_eq!(
+            grouped["input_ids"],
+            vec![
+                Feature::Tokens(vec![1, 1, 3, 3, 3]),
+                Feature::Tokens(vec![2])
+            ]
+        );
+        assert_eq!(
+            grouped["loss_weight"],
+            vec![
+                Feature::Floats(vec![0.0, 0.0, 1.0, 1.0, 1.0]),
+                Feature::Floats(vec![0.5])
+            ]
+        );
+        assert_eq!(grouped["pair_ids"], vec![Feature::Int(4), Feature::Int(9)]);
+        assert_eq!(
+            grouped["position_ids"],
+            vec![
+                Feature::Tokens(vec![0, 1, 0, 1, 2]),
+                Feature::Tokens(vec![0])
+            ]
+        );
+
+        let dataset = HashMap::from([
+            ("input_ids".to_string(), vec![Feature::Tokens(vec![1]); 2]),
+            (
+                "pair_ids".to_string(),
+                vec![Feature::Int(1), Feature::Float(1.5)],
+            ),
+        ]);
+        assert!(group_pairs(dataset).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::{Example, Feature, TokenId};
+
+// Numbers the spill files of this process, so buckets spilled at the same time do not
+// share a file
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+// The examples of a length bucket written to a file in the order they are popped, read
+// back chunk_size at a time. Every chunk is reversed like the ifile_handles, so the next
+// example is popped from the back. The file is removed when the bucket is dropped
+pub struct SpilledBucket {
+    path: PathBuf,
+    reader: BufReader<File>,
+    remaining: usize,
+    chunk_size: usize,
+}
+
+impl Iterator for SpilledBucket {
+    type Item = io::Result<Vec<Example>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let len = self.chunk_size.min(self.remaining);
+        self.remaining -= len;
+        let chunk = (0..len).map(|_| read_example(&mut self.reader));
+        Some(
+            chunk
+                .collect::<io::Result<Vec<Example>>>()
+                .map(|mut chunk| {
+                    chunk.reverse();
+                    chunk
+                }),
+        )
+    }
+}
+
+impl Drop for SpilledBucket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Writes the examples of a bucket to a new file in `dir`. The last example is popped first,
+// so it is written first
+pub fn spill(dir: &str, entries: Vec<Example>, chunk_size: usize) -> io::Result<SpilledBucket> {
+    let file_id = NEXT_FILE.fetch_add(1, Ordering::Relaxed);
+    let path = Path::new(dir).join(format!("binpack_rs_{}_{file_id}.spill", std::process::id()));
+    let written = File::create(&path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        for entry in entries.iter().rev() {
+            write_example(&mut writer, entry)?;
+        }
+        writer.flush()
+    });
+    if let Err(error) = written {
+        let _ = fs::remove_file(&path);
+        return Err(error);
+    }
+    Ok(SpilledBucket {
+        reader: BufReader::new(File::open(&path)?),
+        path,
+        remaining: entries.len(),
+        chunk_size,
+    })
+}
+
+fn write_len(writer: &mut impl Write, len: usize) -> io::Result<()> {
+    writer.write_all(&(len as u64).to_le_bytes())
+}
+
+fn write_values<T, const N: usize>(
+    writer: &mut impl Write,
+    values: &[T],
+    to_bytes: fn(&T) -> [u8; N],
+) -> io::Result<()> {
+    write_len(writer, values.len())?;
+    values
+        .iter()
+        .try_for_each(|value| writer.write_all(&to_bytes(value)))
+}
+
+// An example is the number of columns, then every column as its name, the variant of its
+// feature and its values, all little-endian
+fn write_example(writer: &mut impl Write, entry: &Example) -> io::Result<()> {
+    write_len(writer, entry.len())?;
+    for (key, feature) in entry {
+        write_values(writer, key.as_bytes(), |byte| [*byte])?;
+        match feature {
+            Feature::Tokens(values) => {
+                writer.write_all(&[0])?;
+                write_values(writer, values, |value| value.to_le_bytes())
+            }
+            Feature::Floats(values) => {
+                writer.write_all(&[1])?;
+                write_values(writer, values, |value| value.to_le_bytes())
+            }
+            Feature::Int(value) => {
+                writer.write_all(&[2])?;
+                writer.write_all(&value.to_le_bytes())
+            }
+            Feature::Float(value) => {
+                writer.write_all(&[3])?;
+                writer.write_all(&value.to_le_bytes())
+            }
+            Feature::Int32s(values) => {
+                writer.write_all(&[4])?;
+                write_values(writer, values, |value| value.to_le_bytes())
+            }
+            Feature::Int64s(values) => {
+                writer.write_all(&[5])?;
+                write_values(writer, values, |value| value.to_le_bytes())
+            }
+            Feature::Bytes(values) => {
+                writer.write_all(&[6])?;
+                write_values(writer, values, |value| [*value])
+            }
+            Feature::UInt8s(values) => {
+                writer.write_all(&[7])?;
+                write_values(writer, values, |value| [*value])
+            }
+            Feature::UInt16s(values) => {
+                writer.write_all(&[8])?;
+                write_values(writer, values, |value| value.to_le_bytes())
+            }
+        }?;
+    }
+    Ok(())
+}
+
+fn read_bytes<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_len(reader: &mut impl Read) -> io::Result<usize> {
+    Ok(u64::from_le_bytes(read_bytes(reader)?) as usize)
+}
+
+fn read_values<T, const N: usize>(
+    reader: &mut impl Read,
+    from_bytes: fn([u8; N]) -> T,
+) -> io::Result<Vec<T>> {
+    let len = read_len(reader)?;
+    (0..len)
+        .map(|_| read_bytes(reader).map(from_bytes))
+        .collect()
+}
+
+fn read_example(reader: &mut impl Read) -> io::Result<Example> {
+    let num_columns = read_len(reader)?;
+    let mut entry = Example::with_capacity(num_columns);
+    for _ in 0..num_columns {
+        let key = String::from_utf8(read_values(reader, |[byte]| byte)?)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let feature = match read_bytes::<1>(reader)? {
+            [0] => Feature::Tokens(read_values(reader, TokenId::from_le_bytes)?),
+            [1] => Feature::Floats(read_values(reader, f32::from_le_bytes)?),
+            [2] => Feature::Int(i64::from_le_bytes(read_bytes(reader)?)),
+            [3] => Feature::Float(f64::from_le_bytes(read_bytes(reader)?)),
+            [4] => Feature::Int32s(read_values(reader, i32::from_le_bytes)?),
+            [5] => Feature::Int64s(read_values(reader, i64::from_le_bytes)?),
+            [6] => Feature::Bytes(read_values(reader, |[byte]| byte)?),
+            [7] => Feature::UInt8s(read_values(reader, |[byte]| byte)?),
+            [8] => Feature::UInt16s(read_values(reader, u16::from_le_bytes)?),
+            [other] => {
+                let message = format!("Unknown feature {other} in a spill file");
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        };
+        entry.insert(key, feature);
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spill_round_trip() {
+        let entries = (0..3)
+            .map(|i| {
+                Example::from([
+                    ("input_ids".to_string(), Feature::Tokens(vec![i; 3])),
+                    ("weights".to_string(), Feature::Floats(vec![0.5, i as f32])),
+                    ("source".to_string(), Feature::Int(-(i as i64))),
+                    ("difficulty".to_string(), Feature::Float(1.5)),
+                    ("ids32".to_string(), Feature::Int32s(vec![-1, 2])),
+                    ("ids64".to_string(), Feature::Int64s(vec![i64::MAX])),
+                    ("mask".to_string(), Feature::Bytes(vec![0b101])),
+                    ("narrow".to_string(), Feature::UInt8s(vec![255, 0])),
+                    ("wide".to_string(), Feature::UInt16s(vec![u16::MAX])),
+                ])
+            })
+            .collect::<Vec<Example>>();
+        let dir = std::env::temp_dir().to_string_lossy().into_owned();
+        let spilled = spill(&dir, entries.clone(), 2).unwrap();
+        let path = spilled.path.clone();
+        // the last entry is popped first, from the back of the first chunk
+        let chunks = spilled.collect::<io::Result<Vec<_>>>().unwrap();
+        let expected = vec![
+            vec![entries[1].clone(), entries[2].clone()],
+            vec![entries[0].clone()],
+        ];
+        assert_eq!(chunks, expected);
+        assert!(!path.exists());
+    }
+}
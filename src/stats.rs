@@ -0,0 +1,157 @@
+use pyo3::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::common::{Column, Columns, TokenId};
+
+// Extra information about a packing run, returned as a dict next to the
+// result when `return_stats=True`
+#[derive(Debug, Default, IntoPyObject)]
+pub struct PackStats {
+    // Indices of the output rows that still hold fewer than min_seqs_per_bin sequences
+    pub unmergeable_bins: Vec<usize>,
+    // Number of sequences in each output row
+    pub bin_seq_counts: Vec<usize>,
+    // Sum of the squared sequence lengths of each row, see packing::attention_cost
+    pub attention_cost: Vec<usize>,
+    // Number of distinct bin shapes (lengths in order)
+    pub num_shapes: usize,
+    // Longest input sequence, to check the data fits or to size the pack
+    pub max_seq_len: usize,
+    // Bins under min_tokens, they are left out of the result
+    pub residual_bins: Vec<Vec<usize>>,
+    // Milliseconds spent in the histogram, packing and fill phases, with return_timing
+    pub timing: Option<BTreeMap<String, f64>>,
+    // With return_permutation, the order the examples of each length were used in, as
+    // indices into that length's examples in input order
+    pub permutations: Option<BTreeMap<usize, Vec<usize>>>,
+    // With target_efficiency, the algorithm that was used
+    pub algorithm: Option<String>,
+    // With time_budget_ms, the lengths of the sequences that were not packed in time.
+    // They are left out of the result
+    pub unplaced: Vec<usize>,
+    // With dedup, the number of examples left out because an earlier example had the same
+    // input_ids
+    pub num_duplicates: usize,
+    // With return_meta, the settings the result was packed with
+    pub meta: Option<PackMeta>,
+    // The distinct values of the `source` column, bin_sources[i][j] counts the tokens of
+    // sources[j]. None without a `source` column
+    pub sources: Option<Vec<i64>>,
+    // With return_schema, the type of every output in the serialized form of a datasets
+    // Features, so `datasets.Features.from_dict(stats["schema"])` describes a row
+    pub schema: Option<BTreeMap<String, FeatureType>>,
+}
+
+// A datasets feature as it is serialized, e.g. {"_type": "Value", "dtype": "int32"}
+#[derive(Debug, PartialEq, IntoPyObject)]
+pub struct ValueType {
+    #[pyo3(item("_type"))]
+    kind: &'static str,
+    #[pyo3(item)]
+    dtype: &'static str,
+}
+
+// A list of values, {"_type": "Sequence", "feature": {"_type": "Value", ...}}
+#[derive(Debug, PartialEq, IntoPyObject)]
+pub struct SequenceType {
+    #[pyo3(item("_type"))]
+    kind: &'static str,
+    #[pyo3(item)]
+    feature: ValueType,
+}
+
+#[derive(Debug, PartialEq, IntoPyObject)]
+pub enum FeatureType {
+    Value(ValueType),
+    Sequence(SequenceType),
+}
+
+impl FeatureType {
+    pub(crate) fn value(dtype: &'static str) -> Self {
+        FeatureType::Value(ValueType {
+            kind: "Value",
+            dtype,
+        })
+    }
+
+    pub(crate) fn sequence(dtype: &'static str) -> Self {
+        FeatureType::Sequence(SequenceType {
+            kind: "Sequence",
+            feature: ValueType {
+                kind: "Value",
+                dtype,
+            },
+        })
+    }
+}
+
+// The feature type of every output, as the rows are returned to python. Lists of u8 are
+// returned as bytes
+pub fn schema(columns: &Columns) -> BTreeMap<String, FeatureType> {
+    let token_dtype = match std::mem::size_of::<TokenId>() {
+        4 => "uint32",
+        _ => "uint64",
+    };
+    columns
+        .iter()
+        .map(|(key, column)| {
+            let feature = match column {
+                Column::Tokens(_) => FeatureType::sequence(token_dtype),
+                Column::Floats(_) => FeatureType::sequence("float32"),
+                Column::Int32(_) => FeatureType::sequence("int32"),
+                Column::Int64(_) => FeatureType::sequence("int64"),
+                Column::UInt16(_) => FeatureType::sequence("uint16"),
+                Column::Bytes(_) | Column::UInt8(_) => FeatureType::value("binary"),
+            };
+            (key.clone(), feature)
+        })
+        .collect()
+}
+
+// The arguments of a fast_pack call, so a saved packed dataset describes how it was made
+#[derive(Debug, Default, PartialEq, IntoPyObject)]
+pub struct PackMeta {
+    // with target_efficiency, the algorithm that was used instead of the one passed in
+    pub algorithm: String,
+    pub pack_size: usize,
+    pub seed: Option<u64>,
+    pub pad_id: Option<TokenId>,
+    pub truncation_side: String,
+    pub return_format: String,
+}
+
+// Length composition of the bins, without any token data. Used for capacity planning
+#[derive(Debug, Default, IntoPyObject)]
+pub struct PackingPlan {
+    pub assignments: Vec<Vec<usize>>,
+    // number of tokens in each bin
+    pub bin_fill: Vec<usize>,
+    pub bin_seq_counts: Vec<usize>,
+    // sum of the squared sequence lengths of each bin, proportional to the attention flops
+    pub attention_cost: Vec<usize>,
+    // number of pad tokens needed to reach the pack size
+    pub bin_padding: Vec<usize>,
+    // fraction of the packed tokens that are not padding
+    pub efficiency: f64,
+}
+
+impl PackingPlan {
+    pub fn new(assignments: Vec<Vec<usize>>, pack_size: usize) -> Self {
+        let bin_fill: Vec<usize> = assignments.iter().map(|bin| bin.iter().sum()).collect();
+        let bin_seq_counts = assignments.iter().map(Vec::len).collect();
+        let attention_cost = crate::packing::attention_cost(&assignments);
+        let bin_padding = bin_fill
+            .iter()
+            .map(|fill| pack_size.saturating_sub(*fill))
+            .collect();
+        let efficiency = crate::packing::efficiency(&assignments, pack_size);
+        PackingPlan {
+            assignments,
+            bin_fill,
+            bin_seq_counts,
+            attention_cost,
+            bin_padding,
+            efficiency,
+        }
+    }
+}
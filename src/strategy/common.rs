@@ -1,90 +1,1373 @@
-use super::composer::composer_packing_strategy;
+use super::composer::{composer_packing_strategy, csr_packing_strategy, filled_len};
+use super::iterator::{into_rows, iterator_packing_strategy, Row};
 use super::nemo::nemo_packing_strategy;
+use crate::common::is_protected;
+use crate::options::{TokenDtype, TruncationSide};
+use crate::stats::PackStats;
 use crate::NemoOptions;
-use crate::{Histogram, IFileHandles, ReturnFormat, Sequence};
+use crate::PackOptions;
+use crate::{
+    Bin, Column, Example, Feature, Histogram, IFileHandles, ReturnFormat, Sequence, TokenId,
+};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::{PyResult, Python};
 use rand::prelude::*;
-use std::collections::HashMap;
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::slice::from_ref;
 
-fn create_position_ids(input_ids: &[Sequence]) -> Vec<Sequence> {
-    // Create position ids based on the input_ids
-    let positions_ids = input_ids
+// Create position ids based on the input_ids, starting at the offset of each sequence.
+// None if a position id goes past the largest token id
+fn create_position_ids(input_ids: &[Sequence], offsets: &[TokenId]) -> Option<Vec<Sequence>> {
+    input_ids
         .iter()
-        .map(|seq| {
-            let pos = seq
-                .iter()
-                .enumerate()
-                .map(|(i, _)| i as u32)
-                .collect::<Sequence>();
-            pos
+        .zip(offsets)
+        .map(|(seq, &offset)| {
+            (0..seq.len())
+                .map(|i| offset.checked_add(TokenId::try_from(i).ok()?))
+                .collect()
         })
-        .collect::<Vec<Sequence>>();
-    positions_ids
+        .collect()
 }
 
+// The first position id of an example, from its `position_offset` column (e.g. the position
+// of the document in a longer text), 0 without one. Checked to fit by create_hist
+fn position_offset(entry: &Example) -> TokenId {
+    match entry.get("position_offset") {
+        None => 0,
+        Some(Feature::Int(offset)) => {
+            TokenId::try_from(*offset).expect("Expected key 'position_offset' to fit a token id")
+        }
+        Some(other) => {
+            panic!("Expected key 'position_offset' to be a non-negative integer, got {other:?}")
+        }
+    }
+}
+
+// The sort_key column is checked to be numeric by create_hist
+fn sort_value(entry: &Example, sort_key: &str) -> f64 {
+    entry
+        .get(sort_key)
+        .and_then(Feature::as_f64)
+        .unwrap_or_else(|| panic!("Expected key '{sort_key}' to be a number in the dataset entry"))
+}
+
+// A length bucket written to spill_dir, yielding chunks of its examples to pop from
+pub(super) type SpilledBucket = Box<dyn Iterator<Item = io::Result<Vec<Example>>> + Send>;
+
+// The order the examples of each length are used in, as indices into the length bucket
+// of the histogram, by length
+type Permutations = BTreeMap<usize, Vec<usize>>;
+
+// Returns the order the examples of each length are used in and the buckets that were
+// spilled, which are left empty in the ifile_handles. The examples of one length are
+// interchangeable, so every bucket is prepared in its own rayon task
 fn populate_ifile_handles(
     ifile_handles: &mut IFileHandles,
     sequences: &Histogram,
     pack_size: &usize,
-) {
-    let mut rng = rand::rng();
-    for seq_len in 0..(pack_size + 1) {
-        // Try to replicate python behavior
-        let per_seq_data = sequences.get(&seq_len);
-        let per_seq_len = per_seq_data.map_or(0, |v| v.len());
-        if per_seq_len > 0 {
-            let mut input_ids = per_seq_data
-                .unwrap() // can be safely unwrapped, since we checked above
+    options: &PackOptions,
+) -> PyResult<(Permutations, HashMap<usize, SpilledBucket>)> {
+    let buckets = (0..(pack_size + 1))
+        .into_par_iter()
+        .filter_map(|seq_len| {
+            let bucket = sequences
+                .get(&seq_len)
+                .filter(|bucket| !bucket.is_empty())?;
+            let (entries, order) = populate_bucket(seq_len, bucket, options);
+            Some(spill_bucket(entries, options).map(|entries| (seq_len, entries, order)))
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+    let mut permutations = BTreeMap::new();
+    let mut spilled = HashMap::new();
+    for (seq_len, (entries, spilled_bucket), order) in buckets {
+        permutations.insert(seq_len, order);
+        ifile_handles.insert(seq_len, entries);
+        if let Some(spilled_bucket) = spilled_bucket {
+            spilled.insert(seq_len, spilled_bucket);
+        }
+    }
+    Ok((permutations, spilled))
+}
+
+// Only the order each length bucket is used in, nothing is kept or spilled
+pub fn populate_order(
+    sequences: &Histogram,
+    pack_size: usize,
+    options: &PackOptions,
+) -> BTreeMap<usize, Vec<usize>> {
+    (0..(pack_size + 1))
+        .into_par_iter()
+        .filter_map(|seq_len| {
+            let bucket = sequences
+                .get(&seq_len)
+                .filter(|bucket| !bucket.is_empty())?;
+            Some((seq_len, populate_bucket(seq_len, bucket, options).1))
+        })
+        .collect()
+}
+
+// With spill_dir, a bucket with more than spill_threshold examples is written to disk
+// instead of being held until it is filled. Buckets with protected examples are kept, the
+// bins they go to are searched for
+#[cfg(feature = "spill")]
+fn spill_bucket(
+    entries: Vec<Example>,
+    options: &PackOptions,
+) -> PyResult<(Vec<Example>, Option<SpilledBucket>)> {
+    let spill = entries.len() > options.spill_threshold && !entries.iter().any(is_protected);
+    match &options.spill_dir {
+        Some(dir) if spill => {
+            let spilled = crate::spill::spill(dir, entries, options.spill_threshold)?;
+            let spilled: SpilledBucket = Box::new(spilled);
+            Ok((Vec::new(), Some(spilled)))
+        }
+        _ => Ok((entries, None)),
+    }
+}
+
+#[cfg(not(feature = "spill"))]
+fn spill_bucket(
+    entries: Vec<Example>,
+    options: &PackOptions,
+) -> PyResult<(Vec<Example>, Option<SpilledBucket>)> {
+    match options.spill_dir {
+        Some(_) => Err(PyValueError::new_err(
+            "spill_dir requires binpack_rs to be built with the 'spill' feature",
+        )),
+        None => Ok((entries, None)),
+    }
+}
+
+// The examples of one length in the order they are popped, with their positions_ids, and
+// the order they are used in as indices into the bucket
+fn populate_bucket(
+    seq_len: usize,
+    bucket: &[Example],
+    options: &PackOptions,
+) -> (Vec<Example>, Vec<usize>) {
+    // the order of the entries in the vec, the fill strategies pop from the back
+    let mut order: Vec<usize> = (0..bucket.len()).collect();
+    match &options.sort_key {
+        Some(sort_key) => {
+            // the largest key goes first in the vec and the smallest key is used first
+            let key = |i: usize| sort_value(&bucket[i], sort_key);
+            order.sort_by(|&a, &b| key(b).total_cmp(&key(a)));
+        }
+        // shuffle the input_ids
+        None if options.shuffle => {
+            order.shuffle(&mut crate::packing::bucket_rng(options.seed, seq_len))
+        }
+        // keep the input order, reversed since the last entry is used first
+        None => order.reverse(),
+    }
+    let mut entries = order
+        .iter()
+        .map(|&i| bucket[i].clone())
+        .collect::<Vec<Example>>();
+    order.reverse();
+    let input_ids = entries
+        .iter()
+        .map(|entry| tokens(entry, "input_ids").clone())
+        .collect::<Vec<Sequence>>();
+
+    let offsets = entries.iter().map(position_offset).collect::<Sequence>();
+    // position ids from the dataset (e.g. custom RoPE offsets) are kept as they are
+    let position_ids = create_position_ids(&input_ids, &offsets)
+        .expect("Expected the position ids to fit a token id");
+    // seq_len is the packing length, which differs from the number of tokens with
+    // length_key
+    entries
+        .iter_mut()
+        .zip(position_ids)
+        .for_each(|(entry, position_ids)| {
+            // given ones were checked to have as many ids as input_ids by create_hist
+            entry
+                .entry("position_ids".to_string())
+                .or_insert(Feature::Tokens(position_ids));
+        });
+    (entries, order)
+}
+
+// Get a token column from an example, every example is expected to have it
+pub(super) fn tokens<'a>(entry: &'a Example, key: &str) -> &'a Sequence {
+    entry
+        .get(key)
+        .and_then(Feature::as_tokens)
+        .unwrap_or_else(|| panic!("Expected key '{key}' in the dataset entry"))
+}
+
+// Take the examples for every slot of the assignments out of the ifile_handles. A slot
+// without an example left in its length bucket means the assignments do not match the
+// lengths of the dataset, which would silently give a shorter bin. An empty bucket that
+// was spilled is first refilled with its next chunk from disk
+pub(super) fn resolve_bins(
+    ifile_handles: &mut IFileHandles,
+    spilled: &mut HashMap<usize, SpilledBucket>,
+    assignments: &[Vec<usize>],
+) -> PyResult<Vec<Bin>> {
+    let has_protected = ifile_handles.values().flatten().any(is_protected);
+    assignments
+        .iter()
+        .enumerate()
+        .map(|(bin, assignment)| {
+            resolve_bin(ifile_handles, spilled, bin, assignment, has_protected)
+        })
+        .collect()
+}
+
+// The examples of bin number `bin`, see resolve_bins
+fn resolve_bin(
+    ifile_handles: &mut IFileHandles,
+    spilled: &mut HashMap<usize, SpilledBucket>,
+    bin: usize,
+    assignment: &[usize],
+    has_protected: bool,
+) -> PyResult<Bin> {
+    // protected examples only go to bins with a single slot, which the packing added for
+    // them
+    let alone = assignment.len() == 1;
+    assignment
+        .iter()
+        .map(|seq_len| {
+            let bucket = (ifile_handles.get_mut(seq_len), spilled.get_mut(seq_len));
+            if let (Some(entries), Some(spilled)) = bucket {
+                if entries.is_empty() {
+                    *entries = spilled.next().transpose()?.unwrap_or_default();
+                }
+            }
+            let entries = match ifile_handles.get_mut(seq_len) {
+                Some(entries) if !entries.is_empty() => entries,
+                entries => {
+                    let reason = match entries {
+                        Some(_) => "no sequences of that length are left",
+                        None => "the dataset has no sequences of that length",
+                    };
+                    return Err(PyValueError::new_err(format!(
+                        "Bin {bin} has a sequence of length {seq_len}, but {reason}. The \
+                         assignments do not match the lengths of input_ids"
+                    )));
+                }
+            };
+            let index = match has_protected {
+                true => entries
+                    .iter()
+                    .rposition(|entry| is_protected(entry) == alone),
+                false => None,
+            };
+            Ok(match index {
+                Some(index) => entries.remove(index),
+                None => entries.pop().unwrap(),
+            })
+        })
+        .collect()
+}
+
+// Replaces the tokens of every sequence with what token_transform returns for them, given
+// the tokens and the index of the sequence in its bin. A sequence that changes length gets
+// new position ids from its position_offset, and its bin must still fit the pack size with
+// the reserved slots free. Only input_ids is transformed, so a sequence with another
+// per-token column (e.g. labels, or custom position_ids) must keep its length
+fn transform_tokens(bins: Vec<Bin>, pack_size: usize, options: &PackOptions) -> PyResult<Vec<Bin>> {
+    let Some(transform) = &options.token_transform else {
+        return Ok(bins);
+    };
+    let separator_len = options.separator_id.map_or(0, |_| 1);
+    Python::with_gil(|py| {
+        bins.into_iter()
+            .enumerate()
+            .map(|(bin_index, mut bin)| {
+                let mut resized = false;
+                for (index, entry) in bin.iter_mut().enumerate() {
+                    let input_ids = tokens(entry, "input_ids");
+                    let transformed: Sequence = transform
+                        .call1(py, (input_ids.clone(), index))?
+                        .extract(py)?;
+                    if transformed.len() != input_ids.len() {
+                        resized = true;
+                        let per_token = entry.iter().find(|(key, value)| match key.as_str() {
+                            "input_ids" | "position_ids" => false,
+                            "loss_weight" => true,
+                            _ => matches!(value, Feature::Tokens(_) | Feature::Floats(_)),
+                        });
+                        if let Some((key, _)) = per_token {
+                            return Err(PyValueError::new_err(format!(
+                                "token_transform cannot change the length of a sequence with \
+                                 a '{key}' column"
+                            )));
+                        }
+                        let offset = position_offset(entry);
+                        let old = create_position_ids(from_ref(input_ids), &[offset]);
+                        let old = old.map(|mut old| Feature::Tokens(old.remove(0)));
+                        if entry.get("position_ids") != old.as_ref() {
+                            return Err(PyValueError::new_err(
+                                "token_transform cannot change the length of a sequence with \
+                                 custom position_ids",
+                            ));
+                        }
+                        let Some(mut positions) =
+                            create_position_ids(from_ref(&transformed), &[offset])
+                        else {
+                            return Err(PyValueError::new_err(format!(
+                                "position_offset {offset} with {} tokens after \
+                                 token_transform goes past the largest token id",
+                                transformed.len()
+                            )));
+                        };
+                        let positions = Feature::Tokens(positions.remove(0));
+                        entry.insert("position_ids".to_string(), positions);
+                    }
+                    entry.insert("input_ids".to_string(), Feature::Tokens(transformed));
+                }
+                let seq_lens = bin.iter().map(|entry| tokens(entry, "input_ids").len());
+                let row_len = options.shared_prefix.len()
+                    + seq_lens.sum::<usize>()
+                    + separator_len * bin.len().saturating_sub(1);
+                if resized && row_len + options.reserve_slots > pack_size {
+                    let reserve_slots = options.reserve_slots;
+                    return Err(PyValueError::new_err(format!(
+                        "Bin {bin_index} holds {row_len} tokens after token_transform, more \
+                         than the pack size {pack_size} leaves with {reserve_slots} \
+                         reserve_slots"
+                    )));
+                }
+                Ok(bin)
+            })
+            .collect()
+    })
+}
+
+// With forbid_padding, every row must be exactly full. Only the formats that pad their
+// rows are checked, and only when there is a pad_id
+fn check_padding(
+    bins: &[Bin],
+    return_format: &ReturnFormat,
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    options: &PackOptions,
+) -> PyResult<()> {
+    if !options.forbid_padding || pad_id.is_none() {
+        return Ok(());
+    }
+    if let ReturnFormat::Nemo(_) = return_format {
+        return Ok(());
+    }
+    let row_lens = bins
+        .iter()
+        .map(|bin| filled_len(bin, options))
+        .collect::<Vec<usize>>();
+    let longest = row_lens
+        .iter()
+        .max()
+        .map_or(0, |&longest| longest.min(pack_size));
+    // the length composer_packing_strategy pads a row to
+    let padded_len = |len: usize| match options.pad_to_multiple_of {
+        Some(multiple) => len.next_multiple_of(multiple).min(pack_size),
+        None if options.pad_to_longest => longest,
+        None => pack_size,
+    };
+    match row_lens
+        .iter()
+        .enumerate()
+        .find(|(_, &len)| len < padded_len(len))
+    {
+        Some((index, &len)) => Err(PyValueError::new_err(format!(
+            "Bin {index} holds {len} of {} tokens and would be padded, which forbid_padding \
+             does not allow",
+            padded_len(len)
+        ))),
+        None => Ok(()),
+    }
+}
+
+// With no_truncation, no row may be longer than the pack size: neither by the lengths the
+// bins were packed with (e.g. of length_key) nor by the tokens they were filled with, which
+// would otherwise be truncated
+fn check_truncation(
+    assignments: &[Vec<usize>],
+    bins: &[Bin],
+    pack_size: usize,
+    options: &PackOptions,
+) -> PyResult<()> {
+    if !options.no_truncation {
+        return Ok(());
+    }
+    let separator_len = options.separator_id.map_or(0, |_| 1);
+    let packed_len = |bin: &Vec<usize>| {
+        let seq_lens = bin.iter().sum::<usize>();
+        options.shared_prefix.len() + seq_lens + separator_len * bin.len().saturating_sub(1)
+    };
+    if let Some((index, len)) = assignments
+        .iter()
+        .map(packed_len)
+        .enumerate()
+        .find(|&(_, len)| len > pack_size)
+    {
+        return Err(PyRuntimeError::new_err(format!(
+            "Bin {index} was packed with {len} of {pack_size} tokens, which no_truncation does \
+             not allow"
+        )));
+    }
+    let row_lens = bins.iter().map(|bin| filled_len(bin, options));
+    match row_lens.enumerate().find(|&(_, len)| len > pack_size) {
+        Some((index, len)) => Err(PyRuntimeError::new_err(format!(
+            "Bin {index} holds {len} tokens, more than the {pack_size} of a row, which \
+             no_truncation does not allow"
+        ))),
+        None => Ok(()),
+    }
+}
+
+// The `source` of an example, checked to be an int by create_hist
+fn source_of(entry: &Example) -> Option<i64> {
+    match entry.get("source")? {
+        Feature::Int(source) => Some(*source),
+        other => panic!("Expected key 'source' to be an integer, got {other:?}"),
+    }
+}
+
+// The distinct `source` values in order, bin_sources has a count for each. Collected over
+// the whole histogram so every row has the same width however the bins are formatted, and
+// a sparse id (e.g. a hash) only takes one column. None when the dataset has no `source`
+pub fn sources(sequences: &Histogram) -> Option<Vec<i64>> {
+    let sources = sequences
+        .values()
+        .flatten()
+        .filter_map(source_of)
+        .collect::<BTreeSet<i64>>();
+    (!sources.is_empty()).then(|| sources.into_iter().collect())
+}
+
+// Number of tokens each source contributed to each bin, in the order of `sources`
+fn bin_sources(bins: &[Bin], sources: &[i64]) -> Vec<Sequence> {
+    bins.iter()
+        .map(|bin| {
+            let mut counts: Sequence = vec![0; sources.len()];
+            for entry in bin {
+                let source = source_of(entry).expect("Expected key 'source' in the dataset");
+                let index = sources
+                    .binary_search(&source)
+                    .expect("Expected a known source");
+                counts[index] += tokens(entry, "input_ids").len() as TokenId;
+            }
+            counts
+        })
+        .collect()
+}
+
+// Length of every sequence in each bin. Composer rows longer than the pack size are
+// truncated, so the lengths are cut to what is left of the row after the shared prefix
+// and separators, `row_limit` is None for formats that do not truncate
+fn doc_lengths(bins: &[Bin], row_limit: Option<usize>, options: &PackOptions) -> Vec<Sequence> {
+    let separator_len = options.separator_id.map_or(0, |_| 1);
+    bins.iter()
+        .map(|bin| match options.truncation_side {
+            TruncationSide::Right => {
+                let mut left = row_limit.map_or(usize::MAX, |limit| {
+                    limit.saturating_sub(options.shared_prefix.len())
+                });
+                bin.iter()
+                    .enumerate()
+                    .map(|(i, entry)| {
+                        if i > 0 {
+                            left = left.saturating_sub(separator_len);
+                        }
+                        let seq_len = tokens(entry, "input_ids").len().min(left);
+                        left -= seq_len;
+                        seq_len as TokenId
+                    })
+                    .collect()
+            }
+            // the start of the row is cut, so the last sequences are kept whole
+            TruncationSide::Left => {
+                let mut left = row_limit.unwrap_or(usize::MAX);
+                let mut lengths = bin
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(i, entry)| {
+                        let seq_len = tokens(entry, "input_ids").len().min(left);
+                        left -= seq_len;
+                        if i > 0 {
+                            left = left.saturating_sub(separator_len);
+                        }
+                        seq_len as TokenId
+                    })
+                    .collect::<Sequence>();
+                lengths.reverse();
+                lengths
+            }
+        })
+        .collect()
+}
+
+// Start and length of every sequence in each bin, flattened as [start, length, start,
+// length, ...] per row. The starts count the shared prefix and separators, and the spans
+// are cut like the row, so a truncated sequence is shorter and a cut one has length 0
+fn seq_spans(bins: &[Bin], row_limit: Option<usize>, options: &PackOptions) -> Vec<Sequence> {
+    let separator_len = options.separator_id.map_or(0, |_| 1);
+    bins.iter()
+        .map(|bin| {
+            // the spans in the row before truncation
+            let mut end = options.shared_prefix.len();
+            let spans = bin
                 .iter()
-                .map(|entry| {
-                    entry
-                        .get("input_ids")
-                        .expect("Expected key 'input_ids' in the dataset entry")
-                        .clone()
+                .enumerate()
+                .map(|(i, entry)| {
+                    if i > 0 {
+                        end += separator_len;
+                    }
+                    let start = end;
+                    end += tokens(entry, "input_ids").len();
+                    (start, end)
+                })
+                .collect::<Vec<_>>();
+            let limit = row_limit.unwrap_or(usize::MAX).min(end);
+            // the number of tokens cut from the start of the row
+            let cut = match options.truncation_side {
+                TruncationSide::Right => 0,
+                TruncationSide::Left => end - limit,
+            };
+            spans
+                .into_iter()
+                .flat_map(|(start, end)| {
+                    let start = start.clamp(cut, cut + limit) - cut;
+                    let end = end.clamp(cut, cut + limit) - cut;
+                    [start as TokenId, (end - start) as TokenId]
                 })
-                .collect::<Vec<Sequence>>();
-            // shuffle the input_ids
-            input_ids.shuffle(&mut rng);
+                .collect()
+        })
+        .collect()
+}
 
-            let position_ids = create_position_ids(&input_ids);
+// The output holding the token ids
+fn token_key(return_format: &ReturnFormat) -> &'static str {
+    match return_format {
+        ReturnFormat::Nemo(_) => "input_ids",
+        ReturnFormat::Composer(_) | ReturnFormat::Iterator(_) | ReturnFormat::Csr(_) => "tokens",
+    }
+}
 
-            ifile_handles.insert(seq_len, (input_ids, position_ids));
+// Format the bins into the return format, with the bin_sources output when the dataset
+// has a source column (`sources` is set)
+#[allow(clippy::too_many_arguments)]
+fn format_bins(
+    bins: &[Bin],
+    return_format: &ReturnFormat,
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    pack_options: &PackOptions,
+    options: Option<&NemoOptions>,
+    sources: Option<&[i64]>,
+) -> PyResult<ReturnFormat> {
+    let mut result = match return_format {
+        ReturnFormat::Nemo(_) => {
+            let options = options.expect("PackingOptions is required for Nemo");
+            nemo_packing_strategy(bins, options, pad_id, pack_options)
         }
+        ReturnFormat::Composer(_) => {
+            composer_packing_strategy(bins, pack_size, pad_id, pack_options)?
+        }
+        ReturnFormat::Iterator(_) => {
+            iterator_packing_strategy(bins, pack_size, pad_id, pack_options)?
+        }
+        ReturnFormat::Csr(_) => csr_packing_strategy(bins, pack_size, pad_id, pack_options)?,
+    };
+    if let Some(sources) = sources {
+        result.insert("bin_sources", bin_sources(bins, sources));
+    }
+    let row_limit = match return_format {
+        ReturnFormat::Nemo(_) => None,
+        ReturnFormat::Composer(_) | ReturnFormat::Iterator(_) | ReturnFormat::Csr(_) => {
+            Some(pack_size)
+        }
+    };
+    if pack_options.return_doc_lengths {
+        result.insert("doc_lengths", doc_lengths(bins, row_limit, pack_options));
+    }
+    if pack_options.return_seq_spans {
+        result.insert("seq_spans", seq_spans(bins, row_limit, pack_options));
+    }
+    if pack_options.return_global_position_ids {
+        let key = token_key(return_format);
+        // the index of every token in its row, including the padding and separators
+        let positions = result
+            .sequences(key)
+            .iter()
+            .map(|row| (0..row.len() as TokenId).collect())
+            .collect::<Vec<Sequence>>();
+        result.insert("global_position_ids", positions);
     }
+    Ok(result)
 }
 
+// Convert the token ids output to token_dtype. An id that does not fit is an error
+// instead of wrapping around
+fn narrow_tokens(result: &mut ReturnFormat, options: &PackOptions) -> PyResult<()> {
+    let Some(dtype) = options.token_dtype else {
+        return Ok(());
+    };
+    let key = token_key(result);
+    let Some(Column::Tokens(rows)) = result.data_mut().remove(key) else {
+        panic!("Expected '{key}' to be a token output");
+    };
+    fn narrow<T: TryFrom<TokenId>>(rows: Vec<Sequence>, name: &str) -> PyResult<Vec<Vec<T>>> {
+        rows.into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|id| {
+                        T::try_from(id).map_err(|_| {
+                            PyValueError::new_err(format!(
+                                "Token id {id} does not fit in the token_dtype {name}"
+                            ))
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+    let column = match dtype {
+        TokenDtype::UInt8 => Column::UInt8(narrow(rows, "uint8")?),
+        TokenDtype::UInt16 => Column::UInt16(narrow(rows, "uint16")?),
+    };
+    result.data_mut().insert(key.to_string(), column);
+    Ok(())
+}
+
+// Number of ids that are not the pad id
+fn real_tokens<T: Copy + Into<TokenId>>(ids: &[T], pad_id: Option<TokenId>) -> usize {
+    ids.iter().filter(|&&id| Some(id.into()) != pad_id).count()
+}
+
+fn sequence_tokens(entry: &Example, pad_id: Option<TokenId>) -> usize {
+    real_tokens(tokens(entry, "input_ids"), pad_id)
+}
+
+// The tokens of the sequences the bins are filled from. The input is the histogram, unless
+// token_transform replaced the tokens, then bin_tokens counts the tokens of the bins
+fn input_tokens(sequences: &Histogram, pad_id: Option<TokenId>, options: &PackOptions) -> usize {
+    match options.token_transform {
+        Some(_) => 0,
+        None => sequences
+            .values()
+            .flatten()
+            .map(|entry| sequence_tokens(entry, pad_id))
+            .sum(),
+    }
+}
+
+// The tokens the rows of the bins must hold besides the input: the shared prefix and the
+// separators, and with token_transform the transformed sequences
+fn bin_tokens(bins: &[Bin], pad_id: Option<TokenId>, options: &PackOptions) -> usize {
+    let prefix = real_tokens(&options.shared_prefix, pad_id);
+    let separator = options
+        .separator_id
+        .map_or(0, |id| real_tokens(&[id], pad_id));
+    let transformed = options.token_transform.is_some();
+    bins.iter()
+        .map(|bin| {
+            let sequences = match transformed {
+                true => bin.iter().map(|entry| sequence_tokens(entry, pad_id)).sum(),
+                false => 0,
+            };
+            sequences + prefix + separator * bin.len().saturating_sub(1)
+        })
+        .sum()
+}
+
+// The tokens that are not padding in the token output of the formatted rows
+fn output_tokens(result: &ReturnFormat, pad_id: Option<TokenId>) -> usize {
+    match &result.data()[token_key(result)] {
+        Column::Tokens(rows) => rows.iter().map(|row| real_tokens(row, pad_id)).sum(),
+        Column::UInt8(rows) => rows.iter().map(|row| real_tokens(row, pad_id)).sum(),
+        Column::UInt16(rows) => rows.iter().map(|row| real_tokens(row, pad_id)).sum(),
+        other => panic!("Expected the token output to hold token ids, got {other:?}"),
+    }
+}
+
+// Every input token must be in the returned rows: the packing plans a bin for every
+// sequence, and neither the fill nor the formatting may lose any of them
+fn verify_tokens(tokens_in: usize, tokens_out: usize) -> PyResult<()> {
+    if tokens_in != tokens_out {
+        return Err(PyRuntimeError::new_err(format!(
+            "Token count mismatch after filling: {tokens_in} tokens in, {tokens_out} tokens out"
+        )));
+    }
+    Ok(())
+}
+
+// The histogram is only borrowed, every length bucket is cloned before its examples are
+// used, so the same histogram can be filled with several packings. The order the examples
+// were used in goes to the stats with return_permutation
+#[allow(clippy::too_many_arguments)]
 pub fn fill_packing_strategy(
     assignments: Vec<Vec<usize>>,
-    sequences: Histogram,
+    sequences: &Histogram,
     pack_size: usize,
-    pad_id: Option<u32>,
+    pad_id: Option<TokenId>,
     return_format: ReturnFormat,
+    pack_options: &PackOptions,
     options: Option<NemoOptions>,
-) -> ReturnFormat {
+    stats: &mut PackStats,
+) -> PyResult<ReturnFormat> {
     let mut ifile_handles: IFileHandles = HashMap::new();
-    // Populate the ifile_handles with shuffled input_ids and positions_ids
-    populate_ifile_handles(&mut ifile_handles, &sequences, &pack_size);
+    // Populate the ifile_handles with shuffled (or sorted) examples and their positions_ids
+    let (permutations, mut spilled) =
+        populate_ifile_handles(&mut ifile_handles, sequences, &pack_size, pack_options)?;
+    if pack_options.return_permutation {
+        stats.permutations = Some(permutations);
+    }
+    let bins = resolve_bins(&mut ifile_handles, &mut spilled, &assignments)?;
+    let bins = transform_tokens(bins, pack_size, pack_options)?;
+    check_truncation(&assignments, &bins, pack_size, pack_options)?;
+    check_padding(&bins, &return_format, pack_size, pad_id, pack_options)?;
 
-    // Create the packing strategy
-    match return_format {
-        ReturnFormat::Nemo(_) => {
-            let options = options.expect("PackingOptions is required for Nemo");
-            nemo_packing_strategy(&mut ifile_handles, assignments, options, pad_id)
-        }
-        ReturnFormat::Composer(_) => {
-            composer_packing_strategy(&mut ifile_handles, assignments, pack_size, pad_id)
+    stats.sources = sources(sequences);
+    let mut result = format_bins(
+        &bins,
+        &return_format,
+        pack_size,
+        pad_id,
+        pack_options,
+        options.as_ref(),
+        stats.sources.as_deref(),
+    )?;
+    narrow_tokens(&mut result, pack_options)?;
+    if pack_options.verify {
+        let tokens_in =
+            input_tokens(sequences, pad_id, pack_options) + bin_tokens(&bins, pad_id, pack_options);
+        verify_tokens(tokens_in, output_tokens(&result, pad_id))?;
+    }
+    Ok(result)
+}
+
+// Same as fill_packing_strategy, but every row is handed to `on_bin` as soon as it is
+// formatted instead of being collected. Stops at the first error
+#[allow(clippy::too_many_arguments)]
+pub fn stream_packing_strategy<E: From<pyo3::PyErr>>(
+    assignments: Vec<Vec<usize>>,
+    sequences: &Histogram,
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    return_format: ReturnFormat,
+    pack_options: &PackOptions,
+    options: Option<NemoOptions>,
+    stats: &mut PackStats,
+    mut on_bin: impl FnMut(Row) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut ifile_handles: IFileHandles = HashMap::new();
+    let (permutations, mut spilled) =
+        populate_ifile_handles(&mut ifile_handles, sequences, &pack_size, pack_options)?;
+    if pack_options.return_permutation {
+        stats.permutations = Some(permutations);
+    }
+    let has_protected = ifile_handles.values().flatten().any(is_protected);
+    stats.sources = sources(sequences);
+    let mut tokens_in = input_tokens(sequences, pad_id, pack_options);
+    let mut tokens_out = 0;
+    // every bin is resolved and formatted only when it is its turn, so the first rows go
+    // out before the last bins are filled
+    for (index, assignment) in assignments.iter().enumerate() {
+        let bin = resolve_bin(
+            &mut ifile_handles,
+            &mut spilled,
+            index,
+            assignment,
+            has_protected,
+        )?;
+        let bins = transform_tokens(vec![bin], pack_size, pack_options)?;
+        check_truncation(from_ref(assignment), &bins, pack_size, pack_options)?;
+        check_padding(&bins, &return_format, pack_size, pad_id, pack_options)?;
+        let mut result = format_bins(
+            &bins,
+            &return_format,
+            pack_size,
+            pad_id,
+            pack_options,
+            options.as_ref(),
+            stats.sources.as_deref(),
+        )?;
+        narrow_tokens(&mut result, pack_options)?;
+        tokens_in += bin_tokens(&bins, pad_id, pack_options);
+        tokens_out += output_tokens(&result, pad_id);
+        for row in into_rows(result.into_data()) {
+            on_bin(row)?;
         }
     }
+    if pack_options.verify {
+        verify_tokens(tokens_in, tokens_out)?;
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Columns;
+
+    // `len` tokens with their position ids, as populate_bucket leaves them
+    fn example(len: usize) -> Example {
+        Example::from([
+            ("input_ids".to_string(), Feature::Tokens(vec![1; len])),
+            (
+                "position_ids".to_string(),
+                Feature::Tokens((0..len as TokenId).collect()),
+            ),
+        ])
+    }
+
+    // fill_packing_strategy into composer rows, without the stats
+    fn fill(
+        assignments: Vec<Vec<usize>>,
+        sequences: &Histogram,
+        pack_size: usize,
+        pad_id: Option<TokenId>,
+        options: &PackOptions,
+    ) -> PyResult<ReturnFormat> {
+        fill_packing_strategy(
+            assignments,
+            sequences,
+            pack_size,
+            pad_id,
+            ReturnFormat::Composer(Columns::new()),
+            options,
+            None,
+            &mut PackStats::default(),
+        )
+    }
+
+    // an example that came from `source`
+    fn sourced(len: usize, source: i64) -> Example {
+        let mut entry = example(len);
+        entry.insert("source".to_string(), Feature::Int(source));
+        entry
+    }
 
     #[test]
     fn test_position_ids() {
         let input_ids = vec![vec![1, 2, 3], vec![4, 5, 6, 7]];
-        let position_ids = create_position_ids(&input_ids);
+        let position_ids = create_position_ids(&input_ids, &[0, 0]).unwrap();
         assert_eq!(position_ids[0], vec![0, 1, 2]);
         assert_eq!(position_ids[1], vec![0, 1, 2, 3]);
+        // the last id would go past the largest token id
+        let max = TokenId::MAX;
+        assert_eq!(
+            create_position_ids(&input_ids, &[0, max - 3]).unwrap()[1],
+            vec![max - 3, max - 2, max - 1, max]
+        );
+        assert_eq!(create_position_ids(&input_ids, &[0, max - 2]), None);
+    }
+
+    #[test]
+    fn test_sort_key_ordering() {
+        let difficulties = [3.0, 1.0, 2.0];
+        let examples = difficulties
+            .iter()
+            .enumerate()
+            .map(|(i, &difficulty)| {
+                Example::from([
+                    (
+                        "input_ids".to_string(),
+                        Feature::Tokens(vec![i as TokenId; 2]),
+                    ),
+                    ("difficulty".to_string(), Feature::Float(difficulty)),
+                ])
+            })
+            .collect::<Vec<Example>>();
+        let sequences: Histogram = HashMap::from([(2, examples)]);
+        let options = PackOptions::builder()
+            .sort_key(Some("difficulty".to_string()))
+            .build()
+            .unwrap();
+
+        let mut ifile_handles: IFileHandles = HashMap::new();
+        populate_ifile_handles(&mut ifile_handles, &sequences, &2, &options).unwrap();
+        let entries = ifile_handles.get_mut(&2).unwrap();
+        // popped in order of increasing difficulty
+        let mut pop = || tokens(&entries.pop().unwrap(), "input_ids").clone();
+        assert_eq!(pop(), vec![1, 1]);
+        assert_eq!(pop(), vec![2, 2]);
+        assert_eq!(pop(), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_bin_sources() {
+        // a sparse id only takes one column
+        let sequences: Histogram = HashMap::from([
+            (2, vec![sourced(2, 0), sourced(2, 1_000_000_000)]),
+            (3, vec![sourced(3, 1_000_000_000)]),
+            (5, vec![sourced(5, 0)]),
+        ]);
+        let assignments = vec![vec![5], vec![2, 3], vec![2]];
+        let options = PackOptions::builder().shuffle(false).build().unwrap();
+        let mut stats = PackStats::default();
+        let result = fill_packing_strategy(
+            assignments,
+            &sequences,
+            5,
+            Some(0),
+            ReturnFormat::Composer(Columns::new()),
+            &options,
+            None,
+            &mut stats,
+        )
+        .unwrap();
+        // tokens per source in each bin, the length 2 sequences are used in input order
+        assert_eq!(stats.sources, Some(vec![0, 1_000_000_000]));
+        assert_eq!(
+            *result.sequences("bin_sources"),
+            vec![vec![5, 0], vec![2, 3], vec![0, 2]]
+        );
+    }
+
+    #[test]
+    fn test_stream_bins() {
+        let sequences: Histogram = HashMap::from([
+            (2, vec![sourced(2, 0), sourced(2, 1)]),
+            (3, vec![sourced(3, 2)]),
+        ]);
+        let options = PackOptions::builder().shuffle(false).build().unwrap();
+        let stream = |assignments: Vec<Vec<usize>>| {
+            let mut rows = Vec::new();
+            let result = stream_packing_strategy(
+                assignments,
+                &sequences,
+                5,
+                Some(0),
+                ReturnFormat::Composer(Columns::new()),
+                &options,
+                None,
+                &mut PackStats::default(),
+                |row| {
+                    rows.push(row);
+                    PyResult::Ok(())
+                },
+            );
+            (rows, result)
+        };
+        // every row has a count for each of the 3 sources, not only for its own
+        let (rows, result) = stream(vec![vec![2], vec![3, 2]]);
+        assert!(result.is_ok());
+        assert_eq!(rows[0]["bin_sources"], Feature::Tokens(vec![2, 0, 0]));
+        assert_eq!(rows[1]["bin_sources"], Feature::Tokens(vec![0, 2, 3]));
+
+        // the bins are filled one at a time, the rows before a bad bin are already out
+        let (rows, result) = stream(vec![vec![2], vec![3], vec![7]]);
+        assert_eq!(rows.len(), 2);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_verify_tokens() {
+        let sequences: Histogram =
+            HashMap::from([(2, vec![example(2), example(2)]), (3, vec![example(3)])]);
+        let assignments = vec![vec![3, 2], vec![2]];
+        let options = PackOptions::builder().verify(true).build().unwrap();
+        let result = fill(assignments, &sequences, 5, Some(0), &options).unwrap();
+        let bins = vec![vec![example(3), example(2)], vec![example(2)]];
+        let tokens_in =
+            input_tokens(&sequences, Some(0), &options) + bin_tokens(&bins, Some(0), &options);
+        assert!(verify_tokens(tokens_in, output_tokens(&result, Some(0))).is_ok());
+
+        // a row that lost a token while formatting, padded instead
+        let mut dropped = result;
+        let ReturnFormat::Composer(columns) = &mut dropped else {
+            unreachable!()
+        };
+        let Some(Column::Tokens(rows)) = columns.get_mut("tokens") else {
+            panic!("Expected the tokens output");
+        };
+        rows[1][1] = 0;
+        assert!(verify_tokens(tokens_in, output_tokens(&dropped, Some(0))).is_err());
+    }
+
+    #[test]
+    fn test_token_transform() {
+        use pyo3::ffi::c_str;
+
+        let sequences: Histogram = HashMap::from([(2, vec![example(2)]), (3, vec![example(3)])]);
+        pyo3::prepare_freethreaded_python();
+        let fill_with = |sequences: &Histogram, pack_size: usize, reserve_slots: usize| {
+            // a bos token in front of every sequence, with the index of the sequence
+            let transform = c_str!("lambda tokens, index: [7 + index] + tokens");
+            let transform = Python::with_gil(|py| py.eval(transform, None, None).unwrap().unbind());
+            let options = PackOptions::builder()
+                .token_transform(Some(transform))
+                .reserve_slots(reserve_slots)
+                .build()
+                .unwrap();
+            fill(vec![vec![3, 2]], sequences, pack_size, Some(0), &options)
+        };
+        let pack = |pack_size: usize| fill_with(&sequences, pack_size, 0);
+        let error = |result: PyResult<ReturnFormat>| {
+            Python::with_gil(|py| result.unwrap_err().value(py).to_string())
+        };
+        let result = pack(8).unwrap();
+        assert_eq!(result.sequences("tokens")[0], vec![7, 1, 1, 1, 8, 1, 1, 0]);
+        assert_eq!(
+            result.sequences("positions_ids")[0],
+            vec![0, 1, 2, 3, 0, 1, 2, 0]
+        );
+        // the 5 tokens fit, the 7 transformed ones do not
+        let message = error(pack(5));
+        assert!(
+            message.contains("Bin 0 holds 7 tokens after token_transform"),
+            "{message}"
+        );
+        // the 7 tokens fit 8, but not with a reserved slot
+        let message = error(fill_with(&sequences, 8, 2));
+        assert!(message.contains("with 2 reserve_slots"), "{message}");
+
+        // the new position ids start at the offset
+        let mut offset = sequences.clone();
+        for entry in offset.values_mut().flatten() {
+            let len = tokens(entry, "input_ids").len() as TokenId;
+            entry.insert("position_offset".to_string(), Feature::Int(5));
+            entry.insert(
+                "position_ids".to_string(),
+                Feature::Tokens((5..5 + len).collect()),
+            );
+        }
+        let result = fill_with(&offset, 8, 0).unwrap();
+        assert_eq!(
+            result.sequences("positions_ids")[0],
+            vec![5, 6, 7, 8, 5, 6, 7, 0]
+        );
+
+        // labels would no longer line up with the tokens
+        let mut labels = sequences.clone();
+        for entry in labels.values_mut().flatten() {
+            let labels = tokens(entry, "input_ids").clone();
+            entry.insert("labels".to_string(), Feature::Tokens(labels));
+        }
+        let message = error(fill_with(&labels, 8, 0));
+        assert!(message.contains("with a 'labels' column"), "{message}");
+        // nor would custom position ids
+        let mut custom = sequences.clone();
+        for entry in custom.values_mut().flatten() {
+            let len = tokens(entry, "input_ids").len();
+            entry.insert("position_ids".to_string(), Feature::Tokens(vec![3; len]));
+        }
+        let message = error(fill_with(&custom, 8, 0));
+        assert!(message.contains("custom position_ids"), "{message}");
+    }
+
+    #[test]
+    fn test_forbid_padding() {
+        let sequences: Histogram = HashMap::from([
+            (2, vec![example(2), example(2)]),
+            (3, vec![example(3)]),
+            (5, vec![example(5)]),
+        ]);
+        let options = PackOptions::builder().forbid_padding(true).build().unwrap();
+        pyo3::prepare_freethreaded_python();
+        let pack = |assignments: Vec<Vec<usize>>, pad_id| {
+            fill(assignments, &sequences, 5, pad_id, &options)
+        };
+        // every row is exactly full
+        let result = pack(vec![vec![5], vec![3, 2]], Some(0)).unwrap();
+        assert!(result
+            .sequences("tokens")
+            .iter()
+            .flatten()
+            .all(|&id| id == 1));
+        let error = pack(vec![vec![5], vec![3], vec![2, 2]], Some(0)).unwrap_err();
+        let error = Python::with_gil(|py| error.value(py).to_string());
+        assert!(error.contains("Bin 1 holds 3 of 5 tokens"), "{error}");
+        // nothing is padded without a pad_id
+        assert!(pack(vec![vec![5], vec![3], vec![2, 2]], None).is_ok());
+    }
+
+    #[test]
+    fn test_mismatched_assignments() {
+        let sequences: Histogram = HashMap::from([(2, vec![example(2)]), (3, vec![example(3)])]);
+        let options = PackOptions::builder().build().unwrap();
+        pyo3::prepare_freethreaded_python();
+        let pack = |assignments: Vec<Vec<usize>>| {
+            let error = fill(assignments, &sequences, 5, Some(0), &options).unwrap_err();
+            Python::with_gil(|py| error.value(py).to_string())
+        };
+        // no sequences of length 4 at all
+        let error = pack(vec![vec![3, 2], vec![4]]);
+        assert!(
+            error.contains("Bin 1 has a sequence of length 4"),
+            "{error}"
+        );
+        assert!(
+            error.contains("the dataset has no sequences of that length"),
+            "{error}"
+        );
+        // more sequences of length 2 than the dataset has
+        let error = pack(vec![vec![2, 3], vec![2]]);
+        assert!(
+            error.contains("no sequences of that length are left"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_shuffle_permutation() {
+        let bucket = (0..20)
+            .map(|i| Example::from([("input_ids".to_string(), Feature::Tokens(vec![i; 3]))]))
+            .collect::<Vec<Example>>();
+        let sequences: Histogram = HashMap::from([(3, bucket.clone())]);
+        let options = PackOptions::builder().build().unwrap();
+
+        let mut ifile_handles: IFileHandles = HashMap::new();
+        let (permutations, _) =
+            populate_ifile_handles(&mut ifile_handles, &sequences, &3, &options).unwrap();
+        let entries = ifile_handles.get_mut(&3).unwrap();
+        // applying the permutation to the input order gives the order the examples are used in
+        for &i in &permutations[&3] {
+            let used = entries.pop().unwrap();
+            assert_eq!(tokens(&used, "input_ids"), tokens(&bucket[i], "input_ids"));
+        }
+        let mut sorted = permutations[&3].clone();
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+    }
+
+    // populate_ifile_handles without rayon, one bucket after the other
+    fn populate_serial(
+        sequences: &Histogram,
+        pack_size: usize,
+        options: &PackOptions,
+    ) -> IFileHandles {
+        (0..=pack_size)
+            .filter_map(|seq_len| {
+                let bucket = sequences
+                    .get(&seq_len)
+                    .filter(|bucket| !bucket.is_empty())?;
+                Some((seq_len, populate_bucket(seq_len, bucket, options).0))
+            })
+            .collect()
+    }
+
+    fn histogram(num_examples: usize, pack_size: usize) -> Histogram {
+        let mut sequences: Histogram = HashMap::new();
+        for i in 0..num_examples {
+            let seq_len = i % pack_size + 1;
+            let entry = Example::from([(
+                "input_ids".to_string(),
+                Feature::Tokens(vec![i as TokenId; seq_len]),
+            )]);
+            sequences.entry(seq_len).or_default().push(entry);
+        }
+        sequences
+    }
+
+    #[test]
+    fn test_parallel_populate() {
+        let sequences = histogram(2000, 64);
+        let options = PackOptions::builder().seed(Some(7)).build().unwrap();
+        let mut ifile_handles: IFileHandles = HashMap::new();
+        populate_ifile_handles(&mut ifile_handles, &sequences, &64, &options).unwrap();
+        assert_eq!(ifile_handles, populate_serial(&sequences, 64, &options));
+        // the buckets are not all shuffled the same way
+        let order = |seq_len: usize| {
+            let entries = ifile_handles[&seq_len].iter();
+            entries
+                .map(|entry| tokens(entry, "input_ids")[0] / 64)
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(order(1), order(2));
+    }
+
+    #[test]
+    #[cfg(feature = "spill")]
+    fn test_spill() {
+        let sequences = histogram(200, 8);
+        let assignments = sequences
+            .iter()
+            .flat_map(|(&seq_len, bucket)| std::iter::repeat_n(vec![seq_len], bucket.len()))
+            .collect::<Vec<_>>();
+        let dir = std::env::temp_dir().join(format!("binpack_rs_spill_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pack = |options: PackOptions| {
+            fill(assignments.clone(), &sequences, 8, Some(0), &options).unwrap()
+        };
+        let in_memory = pack(PackOptions::builder().seed(Some(7)).build().unwrap());
+        // every bucket holds 25 examples, read back 3 at a time
+        let options = PackOptions::builder()
+            .seed(Some(7))
+            .spill_dir(Some(dir.to_string_lossy().into_owned()))
+            .spill_threshold(3)
+            .build()
+            .unwrap();
+        let spilled = pack(options);
+        assert_eq!(spilled.data(), in_memory.data());
+        // the spill files are removed once the bins are filled
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    // cargo test --release -- --ignored bench_populate --nocapture
+    #[test]
+    #[ignore]
+    fn bench_populate() {
+        let sequences = histogram(200_000, 512);
+        let options = PackOptions::builder().seed(Some(7)).build().unwrap();
+        let start = std::time::Instant::now();
+        let mut ifile_handles: IFileHandles = HashMap::new();
+        populate_ifile_handles(&mut ifile_handles, &sequences, &512, &options).unwrap();
+        let parallel_time = start.elapsed();
+        let start = std::time::Instant::now();
+        let serial = populate_serial(&sequences, 512, &options);
+        println!(
+            "populate: {parallel_time:?} parallel, {:?} serial",
+            start.elapsed()
+        );
+        assert_eq!(ifile_handles, serial);
+    }
+
+    #[test]
+    fn test_doc_lengths() {
+        let bins = vec![vec![example(2), example(3)], vec![example(4), example(5)]];
+        let options = PackOptions::builder()
+            .return_doc_lengths(true)
+            .build()
+            .unwrap();
+        // the second row is truncated to 6 tokens, so only 2 of the 5 are left
+        assert_eq!(
+            doc_lengths(&bins, Some(6), &options),
+            vec![vec![2, 3], vec![4, 2]]
+        );
+        assert_eq!(
+            doc_lengths(&bins, None, &options),
+            vec![vec![2, 3], vec![4, 5]]
+        );
+        // cut from the start instead, so the 5 is kept
+        let left = PackOptions::builder()
+            .truncation_side(TruncationSide::Left)
+            .build()
+            .unwrap();
+        assert_eq!(
+            doc_lengths(&bins, Some(6), &left),
+            vec![vec![2, 3], vec![1, 5]]
+        );
+
+        let result = format_bins(
+            &bins,
+            &ReturnFormat::Composer(Columns::new()),
+            6,
+            Some(0),
+            &options,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(result.sequences("doc_lengths")[1], vec![4, 2]);
+        assert_eq!(result.sequences("tokens")[1].len(), 6);
+    }
+
+    #[test]
+    fn test_seq_spans() {
+        let bins = vec![vec![example(2), example(3)], vec![example(4), example(5)]];
+        let options = PackOptions::builder()
+            .return_seq_spans(true)
+            .return_doc_lengths(true)
+            .build()
+            .unwrap();
+        let result = format_bins(
+            &bins,
+            &ReturnFormat::Composer(Columns::new()),
+            8,
+            Some(0),
+            &options,
+            None,
+            None,
+        )
+        .unwrap();
+        let spans = result.sequences("seq_spans");
+        // the second row is cut to 8 tokens, so only 4 of the 5 are left
+        assert_eq!(spans, &vec![vec![0, 2, 2, 3], vec![0, 4, 4, 4]]);
+        for ((row_spans, tokens), lengths) in spans
+            .iter()
+            .zip(result.sequences("tokens"))
+            .zip(result.sequences("doc_lengths"))
+        {
+            // the spans tile the row from 0 up to the real tokens, the rest is padding
+            let mut end = 0;
+            for span in row_spans.chunks(2) {
+                assert_eq!(span[0], end);
+                end += span[1];
+            }
+            let real_tokens = tokens.iter().filter(|&&id| id != 0).count() as TokenId;
+            assert_eq!(end, real_tokens);
+            let span_lengths = row_spans.iter().skip(1).step_by(2).copied();
+            assert_eq!(span_lengths.collect::<Sequence>(), *lengths);
+        }
+
+        // the prefix and separator come before the spans, a left cut drops the prefix
+        let options = PackOptions::builder()
+            .shared_prefix(vec![7, 7])
+            .separator_id(Some(9))
+            .truncation_side(TruncationSide::Left)
+            .build()
+            .unwrap();
+        assert_eq!(
+            seq_spans(&bins[..1], Some(8), &options),
+            vec![vec![2, 2, 5, 3]]
+        );
+        assert_eq!(
+            seq_spans(&bins[1..], Some(8), &options),
+            vec![vec![0, 2, 3, 5]]
+        );
+    }
+
+    #[test]
+    fn test_global_position_ids() {
+        let bins = vec![vec![example(2), example(3)], vec![example(4)]];
+        let options = PackOptions::builder()
+            .return_global_position_ids(true)
+            .build()
+            .unwrap();
+        let result = format_bins(
+            &bins,
+            &ReturnFormat::Composer(Columns::new()),
+            6,
+            Some(0),
+            &options,
+            None,
+            None,
+        )
+        .unwrap();
+        let positions = result.sequences("positions_ids");
+        let global = result.sequences("global_position_ids");
+        assert_eq!(positions[0][..5], [0, 1, 0, 1, 2]);
+        assert_eq!(global[0][..5], [0, 1, 2, 3, 4]);
+        // the same within the first sequence, apart from the second one on
+        assert_eq!(positions[0][..2], global[0][..2]);
+        assert_ne!(positions[0][2], global[0][2]);
+        // one per token, padding included
+        assert_eq!(global[1], vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(global[1].len(), result.sequences("tokens")[1].len());
+    }
+
+    #[test]
+    fn test_token_dtype() {
+        pyo3::prepare_freethreaded_python();
+        let pack = |input_ids: Sequence, dtype| {
+            let len = input_ids.len();
+            let example = Example::from([("input_ids".to_string(), Feature::Tokens(input_ids))]);
+            let sequences: Histogram = HashMap::from([(len, vec![example])]);
+            let options = PackOptions::builder()
+                .token_dtype(Some(dtype))
+                .build()
+                .unwrap();
+            fill(vec![vec![len]], &sequences, 4, Some(0), &options)
+        };
+        let result = pack(vec![1, 65535, 300], TokenDtype::UInt16).unwrap();
+        assert_eq!(
+            result.data()["tokens"],
+            Column::UInt16(vec![vec![1, 65535, 300, 0]])
+        );
+        let Err(err) = pack(vec![1, 70000], TokenDtype::UInt16) else {
+            panic!("Expected an id above u16::MAX to be rejected");
+        };
+        let message = "ValueError: Token id 70000 does not fit in the token_dtype uint16";
+        assert_eq!(err.to_string(), message);
+        assert!(pack(vec![300], TokenDtype::UInt8).is_err());
     }
 }
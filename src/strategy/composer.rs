@@ -1,51 +1,455 @@
-use crate::{IFileHandles, ReturnFormat, Sequence};
-use std::collections::HashMap;
+use pyo3::exceptions::PyValueError;
+use pyo3::PyResult;
 
-pub(super) fn composer_packing_strategy(
-    ifile_handles: &mut IFileHandles,
-    assignments: Vec<Vec<usize>>,
+use super::common::tokens;
+use crate::options::{PositionIdDtype, PositionMode, SeparatorPosition, TruncationSide};
+use crate::{Bin, Column, Columns, Example, Feature, PackOptions, ReturnFormat, Sequence, TokenId};
+
+// Concatenate the examples of a bin, then pad or truncate it to the pack size.
+// Returns the tokens and position ids of the row
+pub(super) fn composer_row(
+    bin: &Bin,
     pack_size: usize,
-    pad_id: Option<u32>,
-) -> ReturnFormat {
-    let mut input_ids = HashMap::new();
-    let mut positions_ids = HashMap::new();
-
-    for (oindex, assignment) in assignments.iter().enumerate() {
-        let mut _input_ids: Sequence = Vec::new();
-        let mut _positions_ids: Sequence = Vec::new();
-        for seq_len in assignment {
-            if let Some((input_ids_vec, positions_ids_vec)) = ifile_handles.get_mut(seq_len) {
-                _input_ids.extend(
-                    input_ids_vec
-                        .pop()
-                        .expect("Expected input_ids to be available"),
-                );
-                _positions_ids.extend(
-                    positions_ids_vec
-                        .pop()
-                        .expect("Expected positions_ids to be available"),
-                );
+    pad_id: Option<TokenId>,
+    options: &PackOptions,
+) -> (Sequence, Sequence) {
+    let mut _input_ids: Sequence = options.shared_prefix.clone();
+    let mut _positions_ids: Sequence = (0..options.shared_prefix.len() as TokenId).collect();
+    for (i, entry) in bin.iter().enumerate() {
+        let position_ids = tokens(entry, "position_ids");
+        match options.separator_id {
+            Some(separator_id) if i > 0 => {
+                _input_ids.push(separator_id);
+                match options.separator_position_id {
+                    SeparatorPosition::Continue => {
+                        let previous = _positions_ids.last().map_or(0, |&p| p + 1);
+                        _positions_ids.push(previous);
+                        _positions_ids.extend(position_ids);
+                    }
+                    SeparatorPosition::Reset => {
+                        _positions_ids.push(0);
+                        _positions_ids.extend(position_ids.iter().map(|p| p + 1));
+                    }
+                }
             }
+            _ => _positions_ids.extend(position_ids),
         }
+        _input_ids.extend(tokens(entry, "input_ids"));
+    }
+
+    // Handle padding and truncation here
+    if _input_ids.len() > pack_size {
+        truncate(&mut _input_ids, pack_size, options);
+        truncate(&mut _positions_ids, pack_size, options);
+    }
+    if options.position_mode == PositionMode::Continuous {
+        _positions_ids = (0.._input_ids.len() as TokenId).collect();
+    }
+    if let (Some(pad_id), true) = (pad_id, _input_ids.len() < pack_size) {
+        let target_len = match options.pad_to_multiple_of {
+            Some(multiple) => _input_ids.len().next_multiple_of(multiple).min(pack_size),
+            None => pack_size,
+        };
+        let pad_len = target_len - _input_ids.len();
+        let pad_positions = match (options.pad_position_id, options.position_mode) {
+            (Some(pad_position_id), _) => vec![pad_position_id; pad_len],
+            (None, PositionMode::Reset) => vec![0; pad_len],
+            (None, PositionMode::Continuous) => {
+                (_input_ids.len() as TokenId..target_len as TokenId).collect()
+            }
+        };
+        _input_ids.extend(vec![pad_id; pad_len]);
+        _positions_ids.extend(pad_positions);
+    }
+    (_input_ids, _positions_ids)
+}
 
-        // Handle padding and truncation here
-        if _input_ids.len() > pack_size {
-            _input_ids.truncate(pack_size);
-            _positions_ids.truncate(pack_size);
-        } else if let Some(pad_id) = pad_id {
-            let pad_len = pack_size - _input_ids.len();
-            _input_ids.extend(vec![pad_id; pad_len]);
-            _positions_ids.extend(vec![0; pad_len]); // position ids are all 0
+// Cut a row down to `len` values, from the end or the start depending on truncation_side
+pub(super) fn truncate<T>(row: &mut Vec<T>, len: usize, options: &PackOptions) {
+    match options.truncation_side {
+        TruncationSide::Right => row.truncate(len),
+        TruncationSide::Left => {
+            row.drain(..row.len().saturating_sub(len));
         }
-        input_ids.insert(oindex, _input_ids);
-        positions_ids.insert(oindex, _positions_ids);
     }
+}
+
+// The position ids output, converted to the width asked for with position_id_dtype.
+// A position_offset can push the ids past the width, that is an error instead of wrapping
+pub(super) fn position_column(
+    positions_ids: Vec<Sequence>,
+    options: &PackOptions,
+) -> PyResult<Column> {
+    fn widen<T: TryFrom<TokenId>>(rows: Vec<Sequence>, name: &str) -> PyResult<Vec<Vec<T>>> {
+        rows.into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .map(|p| {
+                        T::try_from(p).map_err(|_| {
+                            PyValueError::new_err(format!(
+                                "Position id {p} does not fit in the position_id_dtype {name}"
+                            ))
+                        })
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+    Ok(match options.position_id_dtype {
+        None => Column::Tokens(positions_ids),
+        Some(PositionIdDtype::Int32) => Column::Int32(widen(positions_ids, "int32")?),
+        Some(PositionIdDtype::Int64) => Column::Int64(widen(positions_ids, "int64")?),
+    })
+}
+
+// Concatenate a list column of every bin the same way composer_row concatenates the tokens
+pub(super) fn lay_out<T: Clone + Default>(
+    bins: &[Bin],
+    row_lens: &[usize],
+    options: &PackOptions,
+    values: impl Fn(&Example) -> Vec<T>,
+) -> Vec<Vec<T>> {
+    bins.iter()
+        .zip(row_lens)
+        .map(|(bin, &row_len)| {
+            let mut row = vec![T::default(); options.shared_prefix.len()];
+            for (i, entry) in bin.iter().enumerate() {
+                if i > 0 && options.separator_id.is_some() {
+                    row.push(T::default());
+                }
+                row.extend(values(entry));
+            }
+            truncate(&mut row, row_len, options);
+            row.resize(row_len, T::default());
+            row
+        })
+        .collect()
+}
+
+// The per-token weights of the `loss_weight` column, one for every input id. The column is
+// checked by create_hist
+fn token_loss_weights(entry: &Example) -> Vec<f32> {
+    let seq_len = tokens(entry, "input_ids").len();
+    let weights = match entry.get("loss_weight") {
+        Some(Feature::Floats(weights)) => weights.clone(),
+        // whole numbers (and empty lists) are extracted as tokens
+        Some(Feature::Tokens(weights)) => weights.iter().map(|&w| w as f32).collect(),
+        other => panic!("Expected key 'loss_weight' to be a list of floats, got {other:?}"),
+    };
+    if weights.len() != seq_len {
+        panic!(
+            "Expected key 'loss_weight' to have {seq_len} weights, got {}",
+            weights.len()
+        );
+    }
+    weights
+}
+
+// The loss weights laid out like the tokens, padding has a weight of 0. None when the
+// dataset has no `loss_weight` column
+pub(super) fn loss_weight_column(
+    bins: &[Bin],
+    row_lens: &[usize],
+    options: &PackOptions,
+) -> Option<Column> {
+    if !bins
+        .iter()
+        .flatten()
+        .any(|entry| entry.contains_key("loss_weight"))
+    {
+        return None;
+    }
+    Some(Column::Floats(lay_out(
+        bins,
+        row_lens,
+        options,
+        token_loss_weights,
+    )))
+}
+
+// Number of tokens of the row of a bin before padding or truncation
+pub(super) fn filled_len(bin: &Bin, options: &PackOptions) -> usize {
+    let separator_len = options.separator_id.map_or(0, |_| 1);
+    let seq_lens = bin
+        .iter()
+        .map(|entry| tokens(entry, "input_ids").len())
+        .sum::<usize>();
+    options.shared_prefix.len() + seq_lens + separator_len * bin.len().saturating_sub(1)
+}
+
+pub(super) fn composer_packing_strategy(
+    bins: &[Bin],
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    options: &PackOptions,
+) -> PyResult<ReturnFormat> {
+    // With pad_to_longest, a second pass over the bins finds the longest row and every row
+    // is padded to it. No row is longer, so nothing more is truncated
+    let pack_size = match options.pad_to_longest {
+        true => bins
+            .iter()
+            .map(|bin| filled_len(bin, options))
+            .max()
+            .map_or(0, |longest| longest.min(pack_size)),
+        false => pack_size,
+    };
+    // Rows are kept in the same order as the assignments
+    let (input_ids, positions_ids): (Vec<Sequence>, Vec<Sequence>) = bins
+        .iter()
+        .map(|bin| composer_row(bin, pack_size, pad_id, options))
+        .unzip();
     // Here handle the conversion to the desired format
     // for now is only composer format, which is a vec
-    let list_input_ids: Vec<Sequence> = input_ids.values().cloned().collect();
-    let list_positions_ids: Vec<Sequence> = positions_ids.values().cloned().collect();
-    let mut result = HashMap::new();
-    result.insert("tokens".to_string(), list_input_ids);
-    result.insert("positions_ids".to_string(), list_positions_ids);
-    ReturnFormat::Composer(result)
+    let mut result = Columns::new();
+    let row_lens = input_ids.iter().map(Vec::len).collect::<Vec<usize>>();
+    if let Some(loss_weight) = loss_weight_column(bins, &row_lens, options) {
+        result.insert("loss_weight".to_string(), loss_weight);
+    }
+    result.insert("tokens".to_string(), Column::Tokens(input_ids));
+    result.insert(
+        "positions_ids".to_string(),
+        position_column(positions_ids, options)?,
+    );
+    Ok(ReturnFormat::Composer(result))
+}
+
+// The composer rows with a `loss_mask` of 1 for the tokens of the sequences and 0 for the
+// shared prefix, separators and padding. Flattened with shared offsets when returned
+pub(super) fn csr_packing_strategy(
+    bins: &[Bin],
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    options: &PackOptions,
+) -> PyResult<ReturnFormat> {
+    let ReturnFormat::Composer(mut result) =
+        composer_packing_strategy(bins, pack_size, pad_id, options)?
+    else {
+        unreachable!("composer_packing_strategy returns the composer format")
+    };
+    let row_lens = result["tokens"].row_lens();
+    let loss_mask = lay_out(bins, &row_lens, options, |entry| {
+        vec![1 as TokenId; tokens(entry, "input_ids").len()]
+    });
+    result.insert("loss_mask".to_string(), Column::Tokens(loss_mask));
+    Ok(ReturnFormat::Csr(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::common::resolve_bins;
+    use super::*;
+    use crate::IFileHandles;
+    use std::collections::HashMap;
+
+    fn bin(lengths: &[usize]) -> Bin {
+        lengths
+            .iter()
+            .map(|&len| {
+                Example::from([
+                    ("input_ids".to_string(), Feature::Tokens(vec![1; len])),
+                    (
+                        "position_ids".to_string(),
+                        Feature::Tokens((0..len as TokenId).collect()),
+                    ),
+                ])
+            })
+            .collect()
+    }
+
+    // IFileHandles with a sequence of every (id, length), each token is the id of its
+    // sequence. The last sequence of a length is used first
+    fn ifile_handles(sequences: &[(TokenId, usize)]) -> IFileHandles {
+        let mut ifile_handles = IFileHandles::new();
+        for &(id, len) in sequences {
+            let entry = Example::from([
+                ("input_ids".to_string(), Feature::Tokens(vec![id; len])),
+                (
+                    "position_ids".to_string(),
+                    Feature::Tokens((0..len as TokenId).collect()),
+                ),
+            ]);
+            ifile_handles.entry(len).or_default().push(entry);
+        }
+        ifile_handles
+    }
+
+    #[test]
+    fn test_fill_rows() {
+        let options = PackOptions::builder().build().unwrap();
+        let sequences = [(1, 4), (2, 3), (3, 1), (4, 6), (5, 5), (6, 5)];
+        let assignments = vec![vec![4, 3, 1], vec![6]];
+        let mut handles = ifile_handles(&sequences);
+        let bins = resolve_bins(&mut handles, &mut HashMap::new(), &assignments).unwrap();
+        let result = composer_packing_strategy(&bins, 8, Some(9), &options).unwrap();
+        let tokens = result.sequences("tokens");
+        let positions_ids = result.sequences("positions_ids");
+        // exact fit, no padding
+        assert_eq!(tokens[0], vec![1, 1, 1, 1, 2, 2, 2, 3]);
+        assert_eq!(positions_ids[0], vec![0, 1, 2, 3, 0, 1, 2, 0]);
+        // underfilled, padded with pad_id at position 0
+        assert_eq!(tokens[1], vec![4, 4, 4, 4, 4, 4, 9, 9]);
+        assert_eq!(positions_ids[1], vec![0, 1, 2, 3, 4, 5, 0, 0]);
+
+        // without a pad_id, an underfilled row keeps its length
+        let assignments = [vec![6], vec![4, 1]];
+        let mut handles = ifile_handles(&sequences);
+        let bins = resolve_bins(&mut handles, &mut HashMap::new(), &assignments).unwrap();
+        let result = composer_packing_strategy(&bins, 8, None, &options).unwrap();
+        assert_eq!(
+            result.sequences("tokens"),
+            &vec![vec![4; 6], vec![1, 1, 1, 1, 3]]
+        );
+        assert_eq!(result.sequences("positions_ids")[1], vec![0, 1, 2, 3, 0]);
+        // an empty bin (e.g. from last_batch="pad") is only padding
+        let result = composer_packing_strategy(&[Vec::new()], 4, Some(9), &options).unwrap();
+        assert_eq!(result.sequences("tokens")[0], vec![9; 4]);
+    }
+
+    #[test]
+    fn test_pad_to_multiple_of() {
+        let options = PackOptions::builder()
+            .pad_to_multiple_of(Some(8))
+            .build()
+            .unwrap();
+        let result =
+            composer_packing_strategy(&[bin(&[6, 7]), bin(&[30])], 32, Some(0), &options).unwrap();
+        let tokens = result.sequences("tokens");
+        // 13 tokens are padded to 16, not to the pack size
+        assert_eq!(tokens[0].len(), 16);
+        assert_eq!(tokens[0][13..], [0, 0, 0]);
+        // the next multiple would be 40, but the pack size is the limit
+        assert_eq!(tokens[1].len(), 32);
+    }
+
+    #[test]
+    fn test_pad_to_longest() {
+        let options = PackOptions::builder()
+            .pad_to_longest(true)
+            .separator_id(Some(9))
+            .build()
+            .unwrap();
+        let bins = [bin(&[3, 2]), bin(&[4]), bin(&[1, 1, 1]), Vec::new()];
+        let result = composer_packing_strategy(&bins, 32, Some(0), &options).unwrap();
+        let tokens = result.sequences("tokens");
+        // the longest row is 3 + 1 + 2 tokens, far below the pack size
+        assert!(tokens.iter().all(|row| row.len() == 6));
+        assert_eq!(tokens[1], vec![1, 1, 1, 1, 0, 0]);
+        assert!(result
+            .sequences("positions_ids")
+            .iter()
+            .all(|row| row.len() == 6));
+        // a row over the pack size is still cut to the pack size
+        let result =
+            composer_packing_strategy(&[bin(&[40]), bin(&[2])], 32, Some(0), &options).unwrap();
+        assert!(result.sequences("tokens").iter().all(|row| row.len() == 32));
+    }
+
+    #[test]
+    fn test_separator_position_id() {
+        for (position, expected) in [
+            (SeparatorPosition::Continue, vec![0, 1, 2, 3, 0, 1, 0]),
+            (SeparatorPosition::Reset, vec![0, 1, 2, 0, 1, 2, 0]),
+        ] {
+            let options = PackOptions::builder()
+                .separator_id(Some(9))
+                .separator_position_id(position)
+                .build()
+                .unwrap();
+            let (tokens, positions_ids) = composer_row(&bin(&[3, 2]), 7, Some(0), &options);
+            assert_eq!(tokens, vec![1, 1, 1, 9, 1, 1, 0]);
+            assert_eq!(positions_ids, expected);
+        }
+    }
+
+    #[test]
+    fn test_continuous_positions() {
+        let positions = |mode, pad_position_id| {
+            let options = PackOptions::builder()
+                .shared_prefix(vec![5])
+                .separator_id(Some(9))
+                .position_mode(mode)
+                .pad_position_id(pad_position_id)
+                .build()
+                .unwrap();
+            composer_row(&bin(&[3, 2]), 10, Some(0), &options).1
+        };
+        // the prefix, separator and padding are numbered like any other token
+        let continuous = positions(PositionMode::Continuous, None);
+        assert_eq!(continuous, (0..10).collect::<Sequence>());
+        assert!(continuous.windows(2).all(|pair| pair[1] == pair[0] + 1));
+        let pad_zero = positions(PositionMode::Continuous, Some(0));
+        assert_eq!(pad_zero, vec![0, 1, 2, 3, 4, 5, 6, 0, 0, 0]);
+        assert_eq!(
+            positions(PositionMode::Reset, None),
+            vec![0, 0, 1, 2, 3, 0, 1, 0, 0, 0]
+        );
+        assert_eq!(
+            positions(PositionMode::Reset, Some(7)),
+            vec![0, 0, 1, 2, 3, 0, 1, 7, 7, 7]
+        );
+    }
+
+    #[test]
+    fn test_position_id_dtype() {
+        let positions = |dtype| {
+            let options = PackOptions::builder()
+                .position_id_dtype(dtype)
+                .build()
+                .unwrap();
+            let result = composer_packing_strategy(&[bin(&[2, 1])], 4, Some(0), &options).unwrap();
+            result.data()["positions_ids"].clone()
+        };
+        assert_eq!(positions(None), Column::Tokens(vec![vec![0, 1, 0, 0]]));
+        assert_eq!(
+            positions(Some(PositionIdDtype::Int32)),
+            Column::Int32(vec![vec![0, 1, 0, 0]])
+        );
+        assert_eq!(
+            positions(Some(PositionIdDtype::Int64)),
+            Column::Int64(vec![vec![0, 1, 0, 0]])
+        );
+    }
+
+    #[test]
+    fn test_loss_weight() {
+        let mut bin = bin(&[2, 3]);
+        bin[0].insert("loss_weight".to_string(), Feature::Floats(vec![0.5, 1.0]));
+        bin[1].insert(
+            "loss_weight".to_string(),
+            Feature::Floats(vec![2.0, 0.0, 0.25]),
+        );
+        let options = PackOptions::builder().build().unwrap();
+        let result = composer_packing_strategy(&[bin], 8, Some(0), &options).unwrap();
+        let Some(Column::Floats(loss_weight)) = result.data().get("loss_weight") else {
+            panic!("Expected a float loss_weight output");
+        };
+        // one weight per token, the 3 pad tokens have a weight of 0
+        assert_eq!(
+            loss_weight[0],
+            vec![0.5, 1.0, 2.0, 0.0, 0.25, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(loss_weight[0].len(), result.sequences("tokens")[0].len());
+    }
+
+    #[test]
+    fn test_truncation_side() {
+        let mut bin = bin(&[3, 4]);
+        bin[1].insert(
+            "loss_weight".to_string(),
+            Feature::Floats(vec![0.1, 0.2, 0.3, 0.4]),
+        );
+        bin[0].insert("loss_weight".to_string(), Feature::Floats(vec![1.0; 3]));
+        let options = PackOptions::builder()
+            .truncation_side(TruncationSide::Left)
+            .build()
+            .unwrap();
+        let result = composer_packing_strategy(&[bin], 5, Some(0), &options).unwrap();
+        // the first 2 of the 7 tokens are cut off, the rest of the row lines up
+        assert_eq!(result.sequences("positions_ids")[0], vec![2, 0, 1, 2, 3]);
+        let Some(Column::Floats(loss_weight)) = result.data().get("loss_weight") else {
+            panic!("Expected a float loss_weight output");
+        };
+        assert_eq!(loss_weight[0], vec![1.0, 0.1, 0.2, 0.3, 0.4]);
+        assert_eq!(result.sequences("tokens")[0].len(), 5);
+    }
 }
@@ -1 +1,369 @@
-//TODO: Implement an iterator pattern
\ No newline at end of file
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+
+use super::composer::{composer_row, lay_out, loss_weight_column, position_column};
+use crate::{Bin, Column, Columns, Example, Feature, PackOptions, ReturnFormat, Sequence, TokenId};
+
+// A single packed row, keyed by output name in order like the Columns
+pub type Row = BTreeMap<String, Feature>;
+
+// Same rows as composer, but returned to python as an iterator of dicts,
+// one per packed bin. The rows are converted to python objects one at a time
+pub(super) fn iterator_packing_strategy(
+    bins: &[Bin],
+    pack_size: usize,
+    pad_id: Option<TokenId>,
+    options: &PackOptions,
+) -> PyResult<ReturnFormat> {
+    let (input_ids, positions_ids): (Vec<Sequence>, Vec<Sequence>) = bins
+        .iter()
+        .map(|bin| composer_row(bin, pack_size, pad_id, options))
+        .unzip();
+    let row_lens = input_ids.iter().map(Vec::len).collect::<Vec<usize>>();
+    let mut result = preserved_columns(bins, &row_lens, options)?;
+    if let Some(loss_weight) = loss_weight_column(bins, &row_lens, options) {
+        result.insert("loss_weight".to_string(), loss_weight);
+    }
+    result.insert("tokens".to_string(), Column::Tokens(input_ids));
+    result.insert(
+        "positions_ids".to_string(),
+        position_column(positions_ids, options)?,
+    );
+    Ok(ReturnFormat::Iterator(result))
+}
+
+// The type of a preserved column
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Tokens,
+    Floats,
+    Int,
+    Float,
+}
+
+impl Kind {
+    fn of(value: &Feature) -> Option<Kind> {
+        match value {
+            Feature::Tokens(_) => Some(Kind::Tokens),
+            Feature::Floats(_) => Some(Kind::Floats),
+            Feature::Int(_) => Some(Kind::Int),
+            Feature::Float(_) => Some(Kind::Float),
+            Feature::Int32s(_)
+            | Feature::Int64s(_)
+            | Feature::Bytes(_)
+            | Feature::UInt8s(_)
+            | Feature::UInt16s(_) => None,
+        }
+    }
+
+    // The type both values fit, ints widen to floats. An empty list is extracted as tokens,
+    // so a list of ints widens to a list of floats
+    fn widen(self, other: Kind) -> Option<Kind> {
+        match (self, other) {
+            (a, b) if a == b => Some(a),
+            (Kind::Tokens, Kind::Floats) | (Kind::Floats, Kind::Tokens) => Some(Kind::Floats),
+            (Kind::Int, Kind::Float) | (Kind::Float, Kind::Int) => Some(Kind::Float),
+            _ => None,
+        }
+    }
+}
+
+// The type of a column over the values of every sequence, an error if they cannot share one
+fn column_kind(bins: &[Bin], key: &str) -> PyResult<Kind> {
+    let mismatch = |value: &Feature| {
+        PyValueError::new_err(format!(
+            "Expected every value of key '{key}' to be a number or every value to be a list, \
+             got {value:?}"
+        ))
+    };
+    let mut kind = None;
+    for entry in bins.iter().flatten() {
+        let value = entry.get(key).ok_or_else(|| {
+            PyValueError::new_err(format!("Expected key '{key}' in the dataset entry"))
+        })?;
+        let of = Kind::of(value).ok_or_else(|| mismatch(value))?;
+        kind = Some(match kind {
+            None => of,
+            Some(kind) => Kind::widen(kind, of).ok_or_else(|| mismatch(value))?,
+        });
+    }
+    Ok(kind.expect("Expected the key of a sequence"))
+}
+
+// Every other column of the dataset, so the rows can go straight to a dataloader.
+// List columns (e.g. labels, attention_mask) are laid out like the tokens, with 0 for the
+// shared prefix, separators and padding. Scalar columns become the list of the values of
+// the sequences in the row. The type of a column is taken over all of its values
+fn preserved_columns(bins: &[Bin], row_lens: &[usize], options: &PackOptions) -> PyResult<Columns> {
+    let keys = bins
+        .iter()
+        .flatten()
+        .flat_map(Example::keys)
+        // loss_weight is added by loss_weight_column
+        .filter(|key| !matches!(key.as_str(), "input_ids" | "position_ids" | "loss_weight"))
+        .collect::<HashSet<_>>();
+    keys.into_iter()
+        .map(|key| {
+            // column_kind checked every value
+            let value = |entry: &Example| entry[key].clone();
+            let column =
+                match column_kind(bins, key)? {
+                    Kind::Tokens => Column::Tokens(lay_out(bins, row_lens, options, |entry| {
+                        match value(entry) {
+                            Feature::Tokens(values) => values,
+                            other => unreachable!("Expected a list of ints, got {other:?}"),
+                        }
+                    })),
+                    Kind::Floats => Column::Floats(lay_out(bins, row_lens, options, |entry| {
+                        match value(entry) {
+                            Feature::Floats(values) => values,
+                            Feature::Tokens(values) => values.iter().map(|&v| v as f32).collect(),
+                            other => unreachable!("Expected a list of numbers, got {other:?}"),
+                        }
+                    })),
+                    Kind::Int => Column::Int64(per_sequence(bins, |entry| match value(entry) {
+                        Feature::Int(value) => value,
+                        other => unreachable!("Expected an int, got {other:?}"),
+                    })),
+                    Kind::Float => Column::Floats(per_sequence(bins, |entry| {
+                        value(entry).as_f64().expect("Expected a number") as f32
+                    })),
+                };
+            Ok((key.clone(), column))
+        })
+        .collect()
+}
+
+// The value of every sequence of each bin
+fn per_sequence<T>(bins: &[Bin], value: impl Fn(&Example) -> T) -> Vec<Vec<T>> {
+    bins.iter()
+        .map(|bin| bin.iter().map(&value).collect())
+        .collect()
+}
+
+#[pyclass(module = "binpack_rs")]
+#[derive(Debug, Default, PartialEq)]
+pub struct PyReturnIter {
+    rows: Vec<Row>,
+    // index of the next row to return
+    position: usize,
+}
+
+// Transpose the column outputs into one row per bin
+pub fn into_rows(columns: Columns) -> Vec<Row> {
+    let num_rows = columns.values().map(Column::len).max().unwrap_or(0);
+    let mut rows = vec![Row::new(); num_rows];
+    for (key, values) in columns {
+        for (row, value) in rows.iter_mut().zip(values.into_rows()) {
+            row.insert(key.clone(), value);
+        }
+    }
+    rows
+}
+
+impl PyReturnIter {
+    pub fn new(columns: Columns) -> Self {
+        PyReturnIter {
+            rows: into_rows(columns),
+            position: 0,
+        }
+    }
+}
+
+#[pymethods]
+impl PyReturnIter {
+    // Only used by pickle, the state is restored with __setstate__
+    #[new]
+    fn empty() -> Self {
+        PyReturnIter::default()
+    }
+
+    // The remaining rows and how many were already returned, so an unpickled
+    // iterator returns the same rows. Only the packed rows are saved, not the packing state
+    fn __getstate__(&self) -> (Vec<Row>, usize) {
+        (self.rows[self.position..].to_vec(), self.position)
+    }
+
+    fn __setstate__(&mut self, state: (Vec<Row>, usize)) {
+        let (remaining, position) = state;
+        // the returned rows are not kept, only their count for __repr__
+        self.rows = vec![Row::new(); position];
+        self.rows.extend(remaining);
+        self.position = position;
+    }
+
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self) -> Option<Row> {
+        let row = self.rows.get_mut(self.position).map(std::mem::take)?;
+        self.position += 1;
+        Some(row)
+    }
+
+    fn __len__(&self) -> usize {
+        self.rows.len() - self.position
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "PyReturnIter(remaining={}, total={})",
+            self.rows.len() - self.position,
+            self.rows.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::common::resolve_bins;
+    use super::*;
+    use crate::IFileHandles;
+    use pyo3::ffi::c_str;
+    use pyo3::types::PyDict;
+    use std::collections::HashMap;
+
+    fn example(id: TokenId, len: usize) -> Example {
+        Example::from([
+            ("input_ids".to_string(), Feature::Tokens(vec![id; len])),
+            (
+                "position_ids".to_string(),
+                Feature::Tokens((0..len as TokenId).collect()),
+            ),
+            ("labels".to_string(), Feature::Tokens(vec![id + 10; len])),
+            ("source".to_string(), Feature::Int(id as i64)),
+        ])
+    }
+
+    #[test]
+    fn test_iterator_rows() {
+        let mut ifile_handles = IFileHandles::new();
+        for (id, len) in [(1, 4), (2, 4), (3, 2), (4, 7), (5, 3)] {
+            ifile_handles.entry(len).or_default().push(example(id, len));
+        }
+        let assignments = vec![vec![4, 2], vec![3]];
+        let bins = resolve_bins(&mut ifile_handles, &mut HashMap::new(), &assignments).unwrap();
+        let options = PackOptions::builder().build().unwrap();
+        let rows = |pad_id| {
+            into_rows(
+                iterator_packing_strategy(&bins, 6, pad_id, &options)
+                    .unwrap()
+                    .into_data(),
+            )
+        };
+        let padded = rows(Some(0));
+        assert_eq!(padded.len(), 2);
+        // exact fit, the last sequence of length 4 is used first
+        assert_eq!(padded[0]["tokens"], Feature::Tokens(vec![2, 2, 2, 2, 3, 3]));
+        assert_eq!(
+            padded[0]["positions_ids"],
+            Feature::Tokens(vec![0, 1, 2, 3, 0, 1])
+        );
+        assert_eq!(
+            padded[0]["labels"],
+            Feature::Tokens(vec![12, 12, 12, 12, 13, 13])
+        );
+        assert_eq!(padded[0]["source"], Feature::Int64s(vec![2, 3]));
+        // underfilled, the padding has position 0 and a label of 0
+        assert_eq!(padded[1]["tokens"], Feature::Tokens(vec![5, 5, 5, 0, 0, 0]));
+        assert_eq!(
+            padded[1]["positions_ids"],
+            Feature::Tokens(vec![0, 1, 2, 0, 0, 0])
+        );
+        assert_eq!(
+            padded[1]["labels"],
+            Feature::Tokens(vec![15, 15, 15, 0, 0, 0])
+        );
+
+        // without a pad_id, the underfilled row keeps its length
+        let unpadded = rows(None);
+        assert_eq!(unpadded[1]["tokens"], Feature::Tokens(vec![5, 5, 5]));
+        assert_eq!(unpadded[1]["labels"], Feature::Tokens(vec![15, 15, 15]));
+        assert_eq!(unpadded[0], padded[0]);
+    }
+
+    #[test]
+    fn test_repr_reflects_consumption() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let columns = Columns::from([
+                (
+                    "tokens".to_string(),
+                    Column::Tokens(vec![vec![1, 2], vec![3, 0], vec![4, 5]]),
+                ),
+                (
+                    "positions_ids".to_string(),
+                    Column::Tokens(vec![vec![0, 1], vec![0, 0], vec![0, 1]]),
+                ),
+            ]);
+            let locals = PyDict::new(py);
+            locals
+                .set_item("it", Py::new(py, PyReturnIter::new(columns)).unwrap())
+                .unwrap();
+            let repr = |py: Python<'_>| -> String {
+                py.eval(c_str!("repr(it)"), None, Some(&locals))
+                    .unwrap()
+                    .extract()
+                    .unwrap()
+            };
+            assert_eq!(repr(py), "PyReturnIter(remaining=3, total=3)");
+            py.run(c_str!("first = next(it)"), None, Some(&locals))
+                .unwrap();
+            assert_eq!(repr(py), "PyReturnIter(remaining=2, total=3)");
+            let first: Row = locals
+                .get_item("first")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(first["tokens"], Feature::Tokens(vec![1, 2]));
+            py.run(c_str!("rest = list(it)"), None, Some(&locals))
+                .unwrap();
+            assert_eq!(repr(py), "PyReturnIter(remaining=0, total=3)");
+        });
+    }
+
+    #[test]
+    fn test_pickle_keeps_remaining_rows() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let columns = Columns::from([(
+                "tokens".to_string(),
+                Column::Tokens(vec![vec![1, 2], vec![3, 0], vec![4, 5]]),
+            )]);
+            let locals = PyDict::new(py);
+            locals
+                .set_item("it", Py::new(py, PyReturnIter::new(columns)).unwrap())
+                .unwrap();
+            locals
+                .set_item("PyReturnIter", py.get_type::<PyReturnIter>())
+                .unwrap();
+            py.run(
+                c_str!(
+                    r#"
+import pickle, sys, types
+# pickle looks the class up by its module
+sys.modules["binpack_rs"] = types.SimpleNamespace(PyReturnIter=PyReturnIter)
+next(it)
+resumed = pickle.loads(pickle.dumps(it))
+state = (repr(resumed), list(resumed), list(it))
+"#
+                ),
+                None,
+                Some(&locals),
+            )
+            .unwrap();
+            let (repr, resumed, rest): (String, Vec<Row>, Vec<Row>) = locals
+                .get_item("state")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(repr, "PyReturnIter(remaining=2, total=3)");
+            assert_eq!(resumed, rest);
+            assert_eq!(resumed[0]["tokens"], Feature::Tokens(vec![3, 0]));
+        });
+    }
+}
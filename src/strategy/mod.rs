@@ -1,3 +1,4 @@
 pub mod common;
 mod composer;
+pub mod iterator;
 pub mod nemo;
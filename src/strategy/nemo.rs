@@ -2,13 +2,25 @@ use pyo3::exceptions::PyValueError;
 use pyo3::prelude::*;
 use pyo3::types::PyDict;
 
-use crate::{IFileHandles, ReturnFormat, Sequence};
+use super::common::tokens;
+use crate::{Bin, Column, Columns, Example, Feature, PackOptions, ReturnFormat, Sequence, TokenId};
 use std::collections::HashMap;
 
 pub struct NemoOptions {
-    answer_start_id: Option<u32>,
-    answer_end_id: Option<u32>,
+    // (start, end) ids of the spans that are kept in the loss, answer_start_id and
+    // answer_end_id are the first pair
+    turn_markers: Vec<(TokenId, TokenId)>,
     answer_loss_only: bool,
+    // only used for validation, the pad id is passed to fast_pack directly
+    pad_id: Option<TokenId>,
+    // return the loss mask as a bitset, 8 tokens per byte, plus the bit length of each row
+    // in loss_mask_bits
+    compact_mask: bool,
+    // return the loss mask as (value, run_length) pairs in rle_mask instead
+    rle_mask: bool,
+    // extra masks, e.g. one per objective, each with its own span markers. Returned as
+    // loss_mask_<name>, sorted by name
+    loss_masks: Vec<(String, Vec<(TokenId, TokenId)>)>,
 }
 
 impl NemoOptions {
@@ -18,47 +30,123 @@ impl NemoOptions {
 
     // Validate the options
     fn validate(&self) -> Result<(), String> {
-        if self.answer_loss_only && (self.answer_start_id.is_none() || self.answer_end_id.is_none())
+        if self.answer_loss_only && self.turn_markers.is_empty() {
+            return Err(
+                "answer_loss_only is set to true, but answer_start_id or answer_end_id is None \
+                 and there are no turn_markers"
+                    .to_string(),
+            );
+        }
+        if self.compact_mask && self.rle_mask {
+            return Err("compact_mask cannot be combined with rle_mask".to_string());
+        }
+        if let Some((name, _)) = self
+            .loss_masks
+            .iter()
+            .find(|(_, markers)| markers.is_empty())
         {
+            return Err(format!(
+                "loss_masks['{name}'] needs at least one (start, end) pair"
+            ));
+        }
+        if self.compact_mask && self.loss_masks.iter().any(|(name, _)| name == "bits") {
             return Err(
-                "answer_loss_only is set to true, but answer_start_id or answer_end_id is None"
+                "loss_masks['bits'] clashes with the loss_mask_bits output of \
+                        compact_mask"
                     .to_string(),
             );
         }
+        // pad tokens are masked before the answer ids are checked, so a colliding id
+        // would never toggle the mask
+        if let Some(pad_id) = self.pad_id {
+            if self
+                .turn_markers
+                .iter()
+                .chain(self.loss_masks.iter().flat_map(|(_, markers)| markers))
+                .any(|&(start, end)| start == pad_id || end == pad_id)
+            {
+                return Err(format!(
+                    "pad_id {pad_id} must be different from answer_start_id, answer_end_id, \
+                     the turn_markers and the loss_masks markers"
+                ));
+            }
+        }
         Ok(())
     }
 }
 
 #[derive(Default)]
 pub struct NemoOptionsBuilder {
-    answer_start_id: Option<u32>,
-    answer_end_id: Option<u32>,
+    answer_start_id: Option<TokenId>,
+    answer_end_id: Option<TokenId>,
+    turn_markers: Vec<(TokenId, TokenId)>,
     answer_loss_only: bool,
+    pad_id: Option<TokenId>,
+    compact_mask: bool,
+    rle_mask: bool,
+    loss_masks: Vec<(String, Vec<(TokenId, TokenId)>)>,
 }
 
 impl NemoOptionsBuilder {
-    pub fn answer_start_id(mut self, id: Option<u32>) -> Self {
+    pub fn answer_start_id(mut self, id: Option<TokenId>) -> Self {
         self.answer_start_id = id;
         self
     }
 
-    pub fn answer_end_id(mut self, id: Option<u32>) -> Self {
+    pub fn answer_end_id(mut self, id: Option<TokenId>) -> Self {
         self.answer_end_id = id;
         self
     }
 
+    pub fn turn_markers(mut self, markers: Vec<(TokenId, TokenId)>) -> Self {
+        self.turn_markers = markers;
+        self
+    }
+
     pub fn answer_loss_only(mut self, loss_only: bool) -> Self {
         self.answer_loss_only = loss_only;
         self
     }
 
+    pub fn pad_id(mut self, pad_id: Option<TokenId>) -> Self {
+        self.pad_id = pad_id;
+        self
+    }
+
+    pub fn compact_mask(mut self, compact_mask: bool) -> Self {
+        self.compact_mask = compact_mask;
+        self
+    }
+
+    pub fn rle_mask(mut self, rle_mask: bool) -> Self {
+        self.rle_mask = rle_mask;
+        self
+    }
+
+    pub fn loss_masks(mut self, loss_masks: Vec<(String, Vec<(TokenId, TokenId)>)>) -> Self {
+        self.loss_masks = loss_masks;
+        self
+    }
+
     pub fn from_py_dict(mut self, kwargs: Option<&Bound<'_, PyDict>>) -> PyResult<Self> {
         if let Some(kwargs) = kwargs {
             for (key, value) in kwargs.iter() {
                 if let Ok(key_str) = key.extract::<&str>() {
                     match key_str {
-                        "answer_start_id" => self.answer_start_id = value.extract().unwrap_or(None),
-                        "answer_end_id" => self.answer_end_id = value.extract().unwrap_or(None),
+                        "answer_start_id" => {
+                            self.answer_start_id = optional_token_id(key_str, &value)?
+                        }
+                        "answer_end_id" => self.answer_end_id = optional_token_id(key_str, &value)?,
+                        "turn_markers" => self.turn_markers = markers(key_str, &value)?,
+                        "loss_masks" => {
+                            self.loss_masks = value
+                                .extract::<HashMap<String, Bound<'_, PyAny>>>()?
+                                .into_iter()
+                                .map(|(name, value)| Ok((name, markers(key_str, &value)?)))
+                                .collect::<PyResult<_>>()?
+                        }
+                        "compact_mask" => self.compact_mask = value.extract()?,
+                        "rle_mask" => self.rle_mask = value.extract()?,
                         "answer_loss_only" => {
                             self.answer_loss_only = value.extract().unwrap_or(false)
                         }
@@ -71,16 +159,24 @@ impl NemoOptionsBuilder {
     }
 
     pub fn build(self) -> PyResult<NemoOptions> {
+        let mut turn_markers = Vec::new();
+        if let (Some(start), Some(end)) = (self.answer_start_id, self.answer_end_id) {
+            turn_markers.push((start, end));
+        }
+        turn_markers.extend(self.turn_markers);
         let mut options = NemoOptions {
-            answer_start_id: self.answer_start_id,
-            answer_end_id: self.answer_end_id,
+            turn_markers,
             answer_loss_only: self.answer_loss_only,
+            pad_id: self.pad_id,
+            compact_mask: self.compact_mask,
+            rle_mask: self.rle_mask,
+            loss_masks: self.loss_masks,
         };
+        options.loss_masks.sort_by(|(a, _), (b, _)| a.cmp(b));
 
         // Apply business logic
         if !options.answer_loss_only {
-            options.answer_start_id = None;
-            options.answer_end_id = None;
+            options.turn_markers.clear();
         }
 
         // Validate the options
@@ -92,29 +188,62 @@ impl NemoOptionsBuilder {
     }
 }
 
+// A token id from python, an error naming the key unless it is an int that fits TokenId.
+// e.g. -1 or nan would otherwise be a confusing extraction error or silently ignored
+fn token_id(key: &str, value: &Bound<'_, PyAny>) -> PyResult<TokenId> {
+    value
+        .extract::<i128>()
+        .ok()
+        .and_then(|id| TokenId::try_from(id).ok())
+        .ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "{key} must be an integer between 0 and {}, got {value}",
+                TokenId::MAX
+            ))
+        })
+}
+
+// A list of (start, end) token id pairs from python
+fn markers(key: &str, value: &Bound<'_, PyAny>) -> PyResult<Vec<(TokenId, TokenId)>> {
+    value
+        .extract::<Vec<(Bound<'_, PyAny>, Bound<'_, PyAny>)>>()?
+        .iter()
+        .map(|(start, end)| Ok((token_id(key, start)?, token_id(key, end)?)))
+        .collect()
+}
+
+fn optional_token_id(key: &str, value: &Bound<'_, PyAny>) -> PyResult<Option<TokenId>> {
+    match value.is_none() {
+        true => Ok(None),
+        false => token_id(key, value).map(Some),
+    }
+}
+
 // Note that nemo has a different implementation, their answer_start_idx refers to the
 // start of the answer, while here, we use the idx that's before the answer, usually something like
 // the assistant message
+// The turn markers generalize this to several kinds of spans, e.g. chat turns with
+// different role markers. A span is closed by the end id of the marker that opened it
 fn create_loss_mask(
     input_ids: Sequence,
     answer_loss_only: bool,
-    answer_start_id: Option<u32>,
-    answer_end_id: Option<u32>,
-    pad_id: Option<u32>,
+    turn_markers: &[(TokenId, TokenId)],
+    pad_id: Option<TokenId>,
 ) -> Sequence {
     // If answer_loss_only is false, return a mask of ones
     if !answer_loss_only {
         let mut loss_mask = vec![1; input_ids.len()];
-        loss_mask[0] = 0; // The first token is always 0
+        // The first token is always 0, empty sequences have no first token
+        if let Some(first) = loss_mask.first_mut() {
+            *first = 0;
+        }
         return loss_mask;
     }
-    // Otherwise, create a mask based on the answer_start_id and answer_end_id
+    // Otherwise, create a mask based on the turn markers
     let mut loss_mask: Sequence = vec![0; input_ids.len()];
-    // unwrap idx here
-    let answer_start_id = answer_start_id.expect("answer_start_id is None");
-    let answer_end_id = answer_end_id.expect("answer_end_id is None");
     // logic here is the default is 0, when the answer starts, the flag is 1, until the answer ends
-    let mut is_answer = false;
+    // the end id of the open span, if any
+    let mut open_end: Option<TokenId> = None;
     for i in 0..input_ids.len() {
         if let Some(pad_id) = pad_id {
             if input_ids[i] == pad_id {
@@ -122,73 +251,148 @@ fn create_loss_mask(
                 continue;
             }
         } // The next few checks would not be possible if pad_id is set
-        if input_ids[i] == answer_start_id {
-            is_answer = true;
-        } else if input_ids[i] == answer_end_id {
-            is_answer = false;
+        if let Some(&(_, end)) = turn_markers
+            .iter()
+            .find(|(start, _)| input_ids[i] == *start)
+        {
+            open_end = Some(end);
+        } else if open_end == Some(input_ids[i]) {
+            open_end = None;
         }
         // regardless the answer. if the input is pad_id, set it to 0
 
-        loss_mask[i] = if is_answer { 1 } else { 0 };
+        loss_mask[i] = if open_end.is_some() { 1 } else { 0 };
     }
     loss_mask
 }
 
+// Weight of a sequence from the `weight` column, sequences without one have a weight of 1
+fn sequence_weight(entry: &Example) -> f32 {
+    entry
+        .get("weight")
+        .and_then(Feature::as_f64)
+        .map_or(1.0, |weight| weight as f32)
+}
+
+// Scale the binary loss mask by the weight of the sequence
+fn weighted_loss_mask(loss_mask: &[TokenId], weight: f32) -> Vec<f32> {
+    loss_mask.iter().map(|&m| m as f32 * weight).collect()
+}
+
+// Pack a 0/1 mask into a bitset, token `i` is bit `i % 8` of byte `i / 8`. The bits past
+// the end of the mask are 0, the number of bits is the length of the input_ids row
+fn pack_bits(mask: &[TokenId]) -> Vec<u8> {
+    mask.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (bit, &m)| byte | (((m != 0) as u8) << bit))
+        })
+        .collect()
+}
+
+// Run-length encode a mask as [value, run_length, value, run_length, ...], the run lengths
+// add up to the length of the input_ids row
+fn run_lengths(mask: &[TokenId]) -> Sequence {
+    mask.chunk_by(|a, b| a == b)
+        .flat_map(|run| [run[0], run.len() as TokenId])
+        .collect()
+}
+
 pub(super) fn nemo_packing_strategy(
-    ifile_handles: &mut IFileHandles,
-    assignments: Vec<Vec<usize>>,
-    options: NemoOptions,
-    pad_id: Option<u32>,
+    bins: &[Bin],
+    options: &NemoOptions,
+    pad_id: Option<TokenId>,
+    pack_options: &PackOptions,
 ) -> ReturnFormat {
     // Similar to fill_packing_strategy but for Nemo format
     // This is a placeholder for the actual implementation
-    let mut input_ids = HashMap::new();
-    let mut loss_mask = HashMap::new();
-    let mut seq_start_id = HashMap::new();
-
-    assignments
+    // Rows are kept in the same order as the assignments
+    let mut input_ids: Vec<Sequence> = Vec::with_capacity(bins.len());
+    let mut loss_mask: Vec<Sequence> = Vec::with_capacity(bins.len());
+    let mut seq_start_id: Vec<Sequence> = Vec::with_capacity(bins.len());
+    // Only returned when the dataset has a weight column
+    let has_weights = bins
         .iter()
-        .enumerate()
-        .for_each(|(oindex, assignment)| {
-            let mut _input_ids: Sequence = Vec::new();
-            // Loss mask only needs 0,1 but for easier conversion, use u32
-            let mut _loss_mask: Sequence = Vec::new();
-            let mut _seq_start_id: Sequence = vec![0];
-            for seq_len in assignment {
-                if let Some((input_ids_vec, positions_ids_vec)) = ifile_handles.get_mut(seq_len) {
-                    let _input_vec: Sequence = input_ids_vec
-                        .pop()
-                        .expect("Expected input_ids to be available");
-                    _input_ids.extend(_input_vec.clone());
-                    let loss_mask = create_loss_mask(
-                        _input_vec,
-                        options.answer_loss_only,
-                        options.answer_start_id,
-                        options.answer_end_id,
-                        pad_id,
-                    );
-                    _loss_mask.extend(loss_mask);
-                    _ = positions_ids_vec // positions_ids are not used in Nemo, but still need to be popped
-                        .pop()
-                        .expect("Expected positions_ids to be available")
-                }
-            } // Loop handling assignment ends here
-            input_ids.insert(oindex, _input_ids);
-            loss_mask.insert(oindex, _loss_mask);
-            // in the python implementation, a slice up to -1 is used
-            // but i didn't see a need that this variable is used
-            // so i just pop the last element
-            _seq_start_id.pop();
-            seq_start_id.insert(oindex, _seq_start_id);
-        }); // for each ends here
-            // for the return format
-    let list_input_ids: Vec<Sequence> = input_ids.values().cloned().collect();
-    let list_position_ids: Vec<Sequence> = loss_mask.values().cloned().collect();
-    let list_seq_start_id: Vec<Sequence> = seq_start_id.values().cloned().collect();
-    let mut result = HashMap::new();
-    result.insert("input_ids".to_string(), list_input_ids);
-    result.insert("loss_mask".to_string(), list_position_ids);
-    result.insert("seq_start_id".to_string(), list_seq_start_id);
+        .flatten()
+        .any(|entry| entry.contains_key("weight"));
+    let mut loss_weights: Vec<Vec<f32>> = Vec::new();
+    // the rows of every extra mask, in the order of options.loss_masks
+    let mut extra_masks: Vec<Vec<Sequence>> = vec![Vec::new(); options.loss_masks.len()];
+
+    bins.iter().for_each(|bin| {
+        // the shared prefix is never in the loss
+        let prefix = &pack_options.shared_prefix;
+        let mut _input_ids: Sequence = prefix.clone();
+        // Loss mask only needs 0,1 but for easier conversion, use the token id type
+        let mut _loss_mask: Sequence = vec![0; prefix.len()];
+        let mut _loss_weights: Vec<f32> = vec![0.0; prefix.len()];
+        let mut _seq_start_id: Sequence = vec![0];
+        for entry in bin {
+            let _input_vec: Sequence = tokens(entry, "input_ids").clone();
+            _input_ids.extend(_input_vec.clone());
+            let loss_mask = create_loss_mask(
+                _input_vec,
+                options.answer_loss_only,
+                &options.turn_markers,
+                pad_id,
+            );
+            if has_weights {
+                _loss_weights.extend(weighted_loss_mask(&loss_mask, sequence_weight(entry)));
+            }
+            _loss_mask.extend(loss_mask);
+        } // Loop handling assignment ends here
+        for ((_, markers), rows) in options.loss_masks.iter().zip(&mut extra_masks) {
+            let mut row: Sequence = vec![0; prefix.len()];
+            for entry in bin {
+                let input_ids = tokens(entry, "input_ids").clone();
+                row.extend(create_loss_mask(input_ids, true, markers, pad_id));
+            }
+            rows.push(row);
+        }
+        input_ids.push(_input_ids);
+        loss_mask.push(_loss_mask);
+        if has_weights {
+            loss_weights.push(_loss_weights);
+        }
+        // in the python implementation, a slice up to -1 is used
+        // but i didn't see a need that this variable is used
+        // so i just pop the last element
+        _seq_start_id.pop();
+        seq_start_id.push(_seq_start_id);
+    }); // for each ends here
+
+    // for the return format
+    let mut result = Columns::new();
+    result.insert("input_ids".to_string(), Column::Tokens(input_ids));
+    let mask_column = |rows: Vec<Sequence>| match (options.compact_mask, options.rle_mask) {
+        (true, _) => Column::Bytes(rows.iter().map(|mask| pack_bits(mask)).collect()),
+        (_, true) => Column::Tokens(rows.iter().map(|mask| run_lengths(mask)).collect()),
+        _ => Column::Tokens(rows),
+    };
+    // with rle_mask, the masks are named after it instead of loss_mask
+    let mask_name = if options.rle_mask {
+        "rle_mask"
+    } else {
+        "loss_mask"
+    };
+    if options.compact_mask {
+        // the bitset pads the last byte, the bit length says where the row ends
+        let bits = loss_mask
+            .iter()
+            .map(|mask| vec![mask.len() as TokenId])
+            .collect();
+        result.insert("loss_mask_bits".to_string(), Column::Tokens(bits));
+    }
+    result.insert(mask_name.to_string(), mask_column(loss_mask));
+    for ((name, _), rows) in options.loss_masks.iter().zip(extra_masks) {
+        result.insert(format!("{mask_name}_{name}"), mask_column(rows));
+    }
+    result.insert("seq_start_id".to_string(), Column::Tokens(seq_start_id));
+    if has_weights {
+        result.insert("loss_weights".to_string(), Column::Floats(loss_weights));
+    }
 
     ReturnFormat::Nemo(result)
 }
@@ -200,7 +404,7 @@ mod tests {
     fn test_loss_mask() {
         // No answer
         let input_ids = vec![1, 2, 3, 4, 5];
-        let loss_mask = create_loss_mask(input_ids, false, None, None, None);
+        let loss_mask = create_loss_mask(input_ids, false, &[], None);
         assert_eq!(loss_mask, vec![0, 1, 1, 1, 1]);
         let input_ids = vec![
             2, 105, 2364, 107, 3689, 563, 506, 5279, 529, 7001, 236881, 106, 107, 105, 4368, 107,
@@ -208,12 +412,11 @@ mod tests {
             5279, 529, 9405, 236881, 106, 107, 105, 4368, 107, 818, 5279, 529, 9405, 563, 15687,
             236761, 106, 107,
         ];
-        let answer_start_id = Some(4368);
-        let answer_end_id = Some(106);
+        let turn_markers = [(4368, 106)];
         let pad_id = None;
         // One way to think of loss mask is like setting -100 for labels
         // that are not in the answer
-        let loss_mask = create_loss_mask(input_ids, true, answer_start_id, answer_end_id, pad_id);
+        let loss_mask = create_loss_mask(input_ids, true, &turn_markers, pad_id);
         assert_eq!(
             loss_mask,
             vec![
@@ -229,7 +432,7 @@ mod tests {
             5279, 529, 9405, 236881, 106, 107, 105, 4368, 107, 818, 5279, 529, 9405, 563, 0,
             236761, 106, 107,
         ];
-        let loss_mask = create_loss_mask(input_ids, true, answer_start_id, answer_end_id, pad_id);
+        let loss_mask = create_loss_mask(input_ids, true, &turn_markers, pad_id);
         assert_eq!(
             loss_mask,
             vec![
@@ -238,4 +441,243 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_rle_mask() {
+        let example = |input_ids: Sequence| {
+            Example::from([("input_ids".to_string(), Feature::Tokens(input_ids))])
+        };
+        let bins = vec![
+            vec![example(vec![1, 7, 2, 3, 8, 4]), example(vec![7, 5, 8])],
+            vec![example(vec![2, 2])],
+        ];
+        let pack_options = PackOptions::builder().build().unwrap();
+        let options = |rle_mask: bool| {
+            NemoOptions::builder()
+                .answer_loss_only(true)
+                .answer_start_id(Some(7))
+                .answer_end_id(Some(8))
+                .rle_mask(rle_mask)
+                .build()
+                .unwrap()
+        };
+        let dense = nemo_packing_strategy(&bins, &options(false), None, &pack_options);
+        let rle = nemo_packing_strategy(&bins, &options(true), None, &pack_options);
+        assert!(!rle.data().contains_key("loss_mask"));
+        assert_eq!(
+            rle.sequences("rle_mask")[0],
+            vec![0, 1, 1, 3, 0, 2, 1, 2, 0, 1]
+        );
+        // expanding the runs gives the dense mask back
+        let decoded = rle
+            .sequences("rle_mask")
+            .iter()
+            .map(|pairs| {
+                pairs
+                    .chunks(2)
+                    .flat_map(|pair| vec![pair[0]; pair[1] as usize])
+                    .collect()
+            })
+            .collect::<Vec<Sequence>>();
+        assert_eq!(&decoded, dense.sequences("loss_mask"));
+
+        let both = NemoOptions::builder()
+            .compact_mask(true)
+            .rle_mask(true)
+            .build();
+        assert!(both.is_err());
+    }
+
+    #[test]
+    fn test_loss_weights() {
+        let example = |input_ids: Sequence, weight: f64| {
+            Example::from([
+                ("input_ids".to_string(), Feature::Tokens(input_ids)),
+                ("weight".to_string(), Feature::Float(weight)),
+            ])
+        };
+        let bins = vec![vec![
+            example(vec![1, 7, 2, 8], 0.5),
+            example(vec![1, 7, 3], 2.0),
+        ]];
+        let options = NemoOptions::builder()
+            .answer_loss_only(true)
+            .answer_start_id(Some(7))
+            .answer_end_id(Some(8))
+            .build()
+            .unwrap();
+        let result = nemo_packing_strategy(
+            &bins,
+            &options,
+            None,
+            &PackOptions::builder().build().unwrap(),
+        );
+        let Some(Column::Floats(loss_weights)) = result.data().get("loss_weights") else {
+            panic!("Expected float loss_weights");
+        };
+        // masked positions are 0, the others are the weight of their sequence
+        assert_eq!(loss_weights[0], vec![0.0, 0.5, 0.5, 0.0, 0.0, 2.0, 2.0]);
+        assert_eq!(result.sequences("loss_mask")[0], vec![0, 1, 1, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn test_loss_masks() {
+        // 7 ... 8 is an answer, 5 ... 6 a rationale
+        let bins = vec![vec![Example::from([(
+            "input_ids".to_string(),
+            Feature::Tokens(vec![1, 5, 2, 6, 7, 3, 4, 8, 9]),
+        )])]];
+        let options = NemoOptions::builder()
+            .answer_loss_only(true)
+            .answer_start_id(Some(7))
+            .answer_end_id(Some(8))
+            .loss_masks(vec![
+                ("rationale".to_string(), vec![(5, 6)]),
+                ("answer_and_rationale".to_string(), vec![(5, 6), (7, 8)]),
+            ])
+            .build()
+            .unwrap();
+        let pack_options = PackOptions::builder()
+            .shared_prefix(vec![0])
+            .build()
+            .unwrap();
+        let result = nemo_packing_strategy(&bins, &options, None, &pack_options);
+        // the shared prefix is in none of the masks
+        assert_eq!(
+            result.sequences("loss_mask")[0],
+            vec![0, 0, 0, 0, 0, 1, 1, 1, 0, 0]
+        );
+        assert_eq!(
+            result.sequences("loss_mask_rationale")[0],
+            vec![0, 0, 1, 1, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            result.sequences("loss_mask_answer_and_rationale")[0],
+            vec![0, 0, 1, 1, 0, 1, 1, 1, 0, 0]
+        );
+
+        let empty = NemoOptions::builder().loss_masks(vec![("none".to_string(), vec![])]);
+        assert!(empty.build().is_err());
+        let collision = NemoOptions::builder()
+            .loss_masks(vec![("rationale".to_string(), vec![(5, 6)])])
+            .pad_id(Some(6));
+        assert!(collision.build().is_err());
+    }
+
+    #[test]
+    fn test_pad_id_collision() {
+        let options = |pad_id| {
+            NemoOptions::builder()
+                .answer_loss_only(true)
+                .answer_start_id(Some(7))
+                .answer_end_id(Some(8))
+                .pad_id(pad_id)
+                .build()
+        };
+        assert!(options(Some(7)).is_err());
+        assert!(options(Some(8)).is_err());
+        assert!(options(Some(0)).is_ok());
+        assert!(options(None).is_ok());
+        // the answer ids are not used without answer_loss_only
+        let options = NemoOptions::builder()
+            .answer_start_id(Some(0))
+            .pad_id(Some(0))
+            .build();
+        assert!(options.is_ok());
+    }
+
+    #[test]
+    fn test_invalid_ids() {
+        use pyo3::ffi::c_str;
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let options = |kwargs: &std::ffi::CStr| {
+                let kwargs = py.eval(kwargs, None, None).unwrap();
+                NemoOptions::builder()
+                    .from_py_dict(Some(kwargs.downcast::<PyDict>().unwrap()))
+                    .map(|_| ())
+                    .map_err(|err| err.to_string())
+            };
+            assert_eq!(
+                options(c_str!("{'answer_start_id': -1}")),
+                Err(format!(
+                    "ValueError: answer_start_id must be an integer between 0 and {}, got -1",
+                    TokenId::MAX
+                ))
+            );
+            let out_of_range = options(c_str!("{'answer_end_id': 2**70}")).unwrap_err();
+            assert!(out_of_range.contains("answer_end_id"), "{out_of_range}");
+            assert!(
+                out_of_range.ends_with("got 1180591620717411303424"),
+                "{out_of_range}"
+            );
+            let nan = options(c_str!("{'turn_markers': [(7, float('nan'))]}")).unwrap_err();
+            assert!(nan.starts_with("ValueError: turn_markers must be"), "{nan}");
+            assert!(nan.ends_with("got nan"), "{nan}");
+            assert!(options(c_str!(
+                "{'answer_start_id': None, 'turn_markers': [(7, 8)]}"
+            ))
+            .is_ok());
+        });
+    }
+
+    #[test]
+    fn test_turn_markers() {
+        // 7 ... 8 is an assistant turn, 5 ... 6 is a user turn
+        let input_ids = vec![5, 1, 6, 7, 2, 3, 8, 5, 1, 6, 7, 4, 8];
+        let options = NemoOptions::builder()
+            .answer_loss_only(true)
+            .turn_markers(vec![(7, 8)])
+            .build()
+            .unwrap();
+        let loss_mask = create_loss_mask(input_ids, true, &options.turn_markers, None);
+        assert_eq!(loss_mask, vec![0, 0, 0, 1, 1, 1, 0, 0, 0, 0, 1, 1, 0]);
+
+        // the end id only closes the span opened by its own start id
+        let input_ids = vec![7, 2, 6, 3, 8, 9, 4, 8, 6];
+        let loss_mask = create_loss_mask(input_ids, true, &[(7, 8), (9, 6)], None);
+        assert_eq!(loss_mask, vec![1, 1, 1, 1, 0, 1, 1, 1, 0]);
+    }
+
+    #[test]
+    fn test_compact_mask() {
+        let unpack = |bits: &[u8], len: usize| -> Vec<bool> {
+            (0..len).map(|i| bits[i / 8] >> (i % 8) & 1 == 1).collect()
+        };
+        let bins = vec![vec![Example::from([(
+            "input_ids".to_string(),
+            Feature::Tokens(vec![3, 7, 1, 2, 8, 4, 7, 5, 6, 8, 9]),
+        )])]];
+        let options = |compact_mask| {
+            NemoOptions::builder()
+                .answer_loss_only(true)
+                .answer_start_id(Some(7))
+                .answer_end_id(Some(8))
+                .compact_mask(compact_mask)
+                .build()
+                .unwrap()
+        };
+        let pack_options = PackOptions::builder().build().unwrap();
+        let full = nemo_packing_strategy(&bins, &options(false), None, &pack_options);
+        let compact = nemo_packing_strategy(&bins, &options(true), None, &pack_options);
+        let Some(Column::Bytes(bits)) = compact.data().get("loss_mask") else {
+            panic!("Expected a bitset loss_mask");
+        };
+        let expected = &full.sequences("loss_mask")[0];
+        // 11 tokens take 2 bytes
+        assert_eq!(bits[0].len(), 2);
+        assert_eq!(compact.sequences("loss_mask_bits"), &[vec![11]]);
+        assert!(!full.data().contains_key("loss_mask_bits"));
+        let len = compact.sequences("loss_mask_bits")[0][0] as usize;
+        let mask = unpack(&bits[0], len);
+        assert_eq!(
+            mask,
+            expected.iter().map(|&m| m == 1).collect::<Vec<bool>>()
+        );
+
+        let clash = NemoOptions::builder()
+            .compact_mask(true)
+            .loss_masks(vec![("bits".to_string(), vec![(5, 6)])]);
+        assert!(clash.build().is_err());
+    }
 }